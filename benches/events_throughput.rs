@@ -1,4 +1,5 @@
 use criterion::{BenchmarkId, Criterion, Throughput, criterion_group, criterion_main};
+use metrics::{Key, Label, Level, Metadata, Recorder};
 use metrics_procession::recorder::ProcessionRecorder;
 use std::hint::black_box;
 
@@ -118,5 +119,55 @@ pub fn histograms(c: &mut Criterion) {
     });
 }
 
-criterion_group!(benches, counters, gauges, histograms);
+static THREADS: &[u64] = &[1, 2, 4, 8];
+
+macro_rules! across_threads {
+    ($c:ident, $grp:literal, $ctor:expr) => {{
+        let mut group = $c.benchmark_group($grp);
+        for &threads in THREADS.iter() {
+            group.throughput(Throughput::Elements(threads * K));
+            group.bench_with_input(
+                BenchmarkId::from_parameter(threads),
+                &threads,
+                |b, &threads| {
+                    b.iter_custom(|ct| {
+                        let recorder: ProcessionRecorder = $ctor;
+                        let metadata = Metadata::new(module_path!(), Level::INFO, None);
+                        let start = std::time::Instant::now();
+                        std::thread::scope(|s| {
+                            for t in 0..threads {
+                                let recorder = &recorder;
+                                let metadata = &metadata;
+                                s.spawn(move || {
+                                    let key = Key::from_parts(
+                                        "threaded",
+                                        vec![Label::new("thread", t.to_string())],
+                                    );
+                                    let met = recorder.register_counter(&key, metadata);
+                                    for _ in 0..ct * K {
+                                        met.increment(1);
+                                    }
+                                });
+                            }
+                        });
+                        let dur = start.elapsed();
+                        black_box(recorder.memory_size());
+                        dur
+                    });
+                },
+            );
+        }
+    }};
+}
+
+pub fn threads(c: &mut Criterion) {
+    across_threads!(c, "locked-counter-threads", ProcessionRecorder::default());
+    across_threads!(
+        c,
+        "sharded-counter-threads",
+        ProcessionRecorder::sharded(THREADS[THREADS.len() - 1] as usize)
+    );
+}
+
+criterion_group!(benches, counters, gauges, histograms, threads);
 criterion_main!(benches);
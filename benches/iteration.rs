@@ -0,0 +1,77 @@
+use criterion::{BenchmarkId, Criterion, Throughput, criterion_group, criterion_main};
+use metrics::Key;
+use metrics_procession::{
+    event::{Entry, Op},
+    procession::Procession,
+};
+use std::hint::black_box;
+
+static EVENTS: u64 = 16 * 1024;
+static KEYS: &[u64] = &[16, 256, 1024, 4096];
+
+/// Build a series of `EVENTS` counter increments spread evenly across `keys` keys
+fn procession(keys: u64) -> Procession {
+    let mut procession = Procession::default();
+    let labels: Vec<_> = (0..keys)
+        .map(|i| {
+            procession
+                .ensure_label(&Key::from_parts("counter", &[("i", i.to_string())]))
+                .unwrap()
+        })
+        .collect();
+    for i in 0..EVENTS {
+        procession.insert_entry(
+            Entry::Counter {
+                value: 1,
+                op: Op::Add,
+            },
+            labels[(i % keys) as usize],
+        );
+    }
+    procession
+}
+
+pub fn iteration(c: &mut Criterion) {
+    let mut group = c.benchmark_group("iterate");
+    for &keys in KEYS {
+        let procession = procession(keys);
+        group.throughput(Throughput::Elements(EVENTS));
+        group.bench_with_input(
+            BenchmarkId::new("reverse-index", keys),
+            &procession,
+            |b, procession| {
+                b.iter(|| {
+                    for metric in procession.iter() {
+                        black_box(metric);
+                    }
+                })
+            },
+        );
+        // the lookup `MetricsRefIterator` performed before the reverse index
+        group.bench_with_input(
+            BenchmarkId::new("linear-search", keys),
+            &procession,
+            |b, procession| {
+                b.iter(|| {
+                    for chunk in procession.chunks.iter() {
+                        let events = chunk.events();
+                        for event in events.iter() {
+                            let when = chunk.reference_time
+                                + time::Duration::milliseconds(i64::from(event.ms));
+                            let key = procession
+                                .labels
+                                .iter()
+                                .find_map(|(k, v)| (v == event.label).then_some(k));
+                            let description =
+                                key.and_then(|k| procession.descriptions.get(k.name()));
+                            black_box((when, events.entry(event), key, description));
+                        }
+                    }
+                })
+            },
+        );
+    }
+}
+
+criterion_group!(benches, iteration);
+criterion_main!(benches);
@@ -1,17 +1,24 @@
 use std::{
     collections::HashMap,
     fs::File,
-    io::{self, BufRead, BufReader, Write, stdout},
+    io::{self, BufReader, Read, Write, stdout},
+    ops::{Bound, Deref, RangeBounds},
     path::{Path, PathBuf},
     str::FromStr,
 };
 
 use clap::Parser;
-use metrics::Key;
+use metrics::{Key, Label};
+#[cfg(feature = "mmap")]
+use metrics_procession::view::MappedCapture;
 use metrics_procession::{
-    event::Op,
-    iter::{Metric, MetricRef},
+    capture::{self, Format},
+    description::Description,
+    event::{Float, Op},
+    iter::{Filter, Metric, MetricRef},
     procession::Procession,
+    stream::{JsonArray, JsonLines, Postcard, StreamError},
+    view::ProcessionView,
 };
 use metrics_util::storage::Summary;
 use regex::Regex;
@@ -81,31 +88,102 @@ fn main() {
         start,
         end,
     } = Args::parse();
-    let metrics = deser_metrics(&source);
+    let range = (
+        start.map_or(Bound::Unbounded, |s| Bound::Included(s.assume_utc())),
+        end.map_or(Bound::Unbounded, |e| Bound::Included(e.assume_utc())),
+    );
+    let matches_key = |key: &Key| {
+        keys.iter().all(|re| re.is_match(key.name())) && labels.iter().all(|kv| kv.matches(key))
+    };
     let mut collector = QueryCollector::default();
-    for metric in metrics.iter() {
-        if !keys.iter().all(|re| re.is_match(metric.key.name())) {
-            continue;
+    // the first few bytes are enough to tell the formats apart
+    let mut prefix = Vec::new();
+    File::open(&source)
+        .unwrap()
+        .take(64)
+        .read_to_end(&mut prefix)
+        .unwrap();
+    let format = capture::detect(&prefix);
+    if let Format::Capture(_) = format {
+        // captures are read in place, only decoding one chunk at a time
+        let bytes = read_source(&source);
+        let view = ProcessionView::new(&bytes).unwrap();
+        // resolving the matching keys up front lets the chunk indexes skip anything that can't match
+        let filter = Filter::new().keys(view.labels(), matches_key).range(range);
+        for metric in view.iter_filtered(filter) {
+            collector.track_metric(metric.unwrap().as_ref());
         }
-        if !labels.iter().all(|kv| kv.matches(metric.key)) {
-            continue;
+        collector.report_into(&mut stdout().lock()).unwrap();
+        return;
+    }
+    let file = File::open(&source).unwrap();
+    let metrics: Box<dyn Iterator<Item = Result<Metric, StreamError>>> =
+        match (format, prefix.iter().find(|b| !b.is_ascii_whitespace())) {
+            (Format::Json, Some(b'[')) => Box::new(JsonArray::new(file)),
+            (Format::Json, _) => Box::new(JsonLines::new(file)),
+            _ => Box::new(Postcard::new(file)),
+        };
+    let mut metrics = metrics.peekable();
+    if let (Format::Json, Some(Err(e))) = (format, metrics.peek()) {
+        // a json object that isn't a single metric on its first line is a full Procession
+        eprintln!("failed to deser as json-lines: {e}");
+        let file = BufReader::new(File::open(&source).unwrap());
+        let metrics: Procession = serde_json::from_reader(file).unwrap();
+        if let Err(e) = metrics.validate() {
+            eprintln!("warning: {} is inconsistent: {e}", source.display());
         }
-        if let Some(start) = start {
-            if start.assume_offset(metric.when.offset()) > metric.when {
-                continue;
-            }
+        let filter = Filter::new()
+            .keys(&metrics.labels, matches_key)
+            .range(range);
+        for metric in metrics.iter_filtered(filter) {
+            collector.track_metric(metric);
         }
-        if let Some(end) = end {
-            if end.assume_offset(metric.when.offset()) >= metric.when {
+        collector.report_into(&mut stdout().lock()).unwrap();
+        return;
+    }
+    // everything else is streamed, only holding a single metric at a time
+    for metric in metrics {
+        let metric = match metric {
+            Ok(metric) => metric,
+            Err(e) => {
+                eprintln!("skipping invalid metric: {e}");
                 continue;
             }
+        };
+        let key = Key::from_parts(
+            metric.key,
+            metric
+                .labels
+                .into_iter()
+                .map(|(k, v)| Label::new(k, v))
+                .collect::<Vec<_>>(),
+        );
+        if !matches_key(&key) || !range.contains(&metric.when) {
+            continue;
         }
-
-        collector.track_metric(metric);
+        collector.track_metric(MetricRef {
+            when: metric.when,
+            event: metric.event,
+            key: &key,
+            description: metric.description.as_ref(),
+            rollup: metric.rollup.as_ref(),
+        });
     }
     collector.report_into(&mut stdout().lock()).unwrap();
 }
 
+/// Map the provided file into memory
+#[cfg(feature = "mmap")]
+fn read_source(path: &Path) -> impl Deref<Target = [u8]> {
+    MappedCapture::open(path).unwrap()
+}
+
+/// Read the provided file into memory, enable the `mmap` feature to map it instead
+#[cfg(not(feature = "mmap"))]
+fn read_source(path: &Path) -> impl Deref<Target = [u8]> {
+    std::fs::read(path).unwrap()
+}
+
 fn parse_date_time(s: &str) -> Result<PrimitiveDateTime, String> {
     let res = PrimitiveDateTime::parse(s, &Rfc3339)
         .map_err(|e| format!("expected RFC3339 formatted date or date-time found `{s}`: {e}"));
@@ -117,57 +195,12 @@ fn parse_date_time(s: &str) -> Result<PrimitiveDateTime, String> {
     res
 }
 
-/// Attempt to deserialize the provided file path as a Procession
-fn deser_metrics(path: &Path) -> Procession {
-    if path
-        .extension()
-        .map(|e| e == "postcard")
-        .unwrap_or_default()
-    {
-        let bytes = std::fs::read(path).unwrap();
-        let events: Vec<Metric> = postcard::from_bytes(&bytes).unwrap();
-        return events.into_iter().collect();
-    }
-    // If the line was a jsonl file, we can assume each line will be a Metric
-    if path.extension().map(|e| e == "jsonl").unwrap_or_default() {
-        let buf = BufReader::new(
-            File::options()
-                .read(true)
-                .create(false)
-                .write(false)
-                .open(path)
-                .unwrap(),
-        );
-        return buf
-            .lines()
-            .filter_map(|r| {
-                let line = r.ok()?;
-                if line.trim().is_empty() {
-                    return None;
-                }
-                serde_json::from_str::<Metric>(&line).ok()
-            })
-            .collect();
-    }
-    let s = std::fs::read_to_string(path).unwrap();
-    // If we have any other file extension, first try and deserialize the full Procession type
-    if let Ok(proc) = serde_json::from_str::<Procession>(&s)
-        .inspect_err(|e| eprintln!("failed to deser as Procession: {e}"))
-    {
-        return proc;
-    }
-    // If the above failed, let finally try and use the array of metrics method for deserializing
-    serde_json::from_str::<Vec<Metric>>(&s)
-        .unwrap()
-        .into_iter()
-        .collect()
-}
-
 #[derive(Default)]
 struct QueryCollector {
-    counters: HashMap<Key, usize>,
+    counters: HashMap<Key, u64>,
     gauges: HashMap<Key, GaugeResult>,
     histograms: HashMap<Key, Summary>,
+    descriptions: HashMap<String, Description>,
 }
 
 impl QueryCollector {
@@ -179,7 +212,9 @@ impl QueryCollector {
                 for label in k.labels() {
                     dest.write_fmt(format_args!("\n  {} => {}", label.key(), label.value()))?;
                 }
-                dest.write_fmt(format_args!("}}\n{v}\n-"))?;
+                dest.write_fmt(format_args!("}}\n"))?;
+                self.write_description(dest, k)?;
+                dest.write_fmt(format_args!("{v}\n-"))?;
             }
             dest.write_all(b"\n")?;
         }
@@ -191,6 +226,7 @@ impl QueryCollector {
                     dest.write_fmt(format_args!("\n  {} => {}", label.key(), label.value()))?;
                 }
                 dest.write_fmt(format_args!("}}\n"))?;
+                self.write_description(dest, k)?;
                 dest.write_fmt(format_args!("   min: {:.02},\n", v.min))?;
                 dest.write_fmt(format_args!("   max: {:.02},\n", v.max))?;
                 dest.write_fmt(format_args!("   avg: {:.02},\n", v.avg))?;
@@ -206,6 +242,7 @@ impl QueryCollector {
                     dest.write_fmt(format_args!("\n  {} => {}", label.key(), label.value()))?;
                 }
                 dest.write_fmt(format_args!("}}\n"))?;
+                self.write_description(dest, k)?;
                 for q in [0.5, 0.75, 0.9, 0.99] {
                     let value = v.quantile(q).unwrap();
                     dest.write_fmt(format_args!("p{q:.02}: {value:>.02}\n"))?;
@@ -215,8 +252,30 @@ impl QueryCollector {
         Ok(())
     }
 
+    fn write_description(&self, dest: &mut dyn Write, key: &Key) -> Result<(), io::Error> {
+        let Some(description) = self.descriptions.get(key.name()) else {
+            return Ok(());
+        };
+        dest.write_fmt(format_args!("# {}", description.text))?;
+        if let Some(unit) = description.unit {
+            dest.write_fmt(format_args!(" ({})", unit.as_str()))?;
+        }
+        dest.write_all(b"\n")
+    }
+
     fn track_metric(&mut self, metric: MetricRef) {
-        let MetricRef { event, key, .. } = metric;
+        let MetricRef {
+            event,
+            key,
+            description,
+            ..
+        } = metric;
+        if let Some(description) = description
+            && !self.descriptions.contains_key(key.name())
+        {
+            self.descriptions
+                .insert(key.name().to_string(), description.clone());
+        }
         match event {
             metrics_procession::event::Entry::Gauge { value, op } => {
                 self.track_gauge(key.clone(), op, value)
@@ -229,16 +288,19 @@ impl QueryCollector {
             }
         }
     }
-    fn track_counter(&mut self, key: Key, op: Op, value: u32) {
+    fn track_counter(&mut self, key: Key, op: Op, value: u64) {
         if matches!(op, Op::Set) {
-            self.counters.insert(key, value as _);
+            self.counters.insert(key, value);
             return;
         }
         let def = self.counters.entry(key).or_default();
-        *def += value as usize;
+        *def += value;
     }
 
-    fn track_gauge(&mut self, key: Key, op: Op, value: f32) {
+    // `Float` is already an `f64` with the `f64-values` feature
+    #[allow(clippy::unnecessary_cast)]
+    fn track_gauge(&mut self, key: Key, op: Op, value: Float) {
+        let value = value as f64;
         let v = self.gauges.entry(key).or_default();
         let value = match op {
             Op::Add => v.latest + value,
@@ -248,11 +310,13 @@ impl QueryCollector {
         v.latest = value;
         v.max = v.max.max(value);
         v.min = v.min.min(value);
-        v.avg = v.avg + ((value - v.avg) / (v.count as f32 + 1.0));
+        v.avg = v.avg + ((value - v.avg) / (v.count as f64 + 1.0));
         v.count += 1;
     }
 
-    fn track_histo(&mut self, key: Key, value: f32) {
+    // `Float` is already an `f64` with the `f64-values` feature
+    #[allow(clippy::unnecessary_cast)]
+    fn track_histo(&mut self, key: Key, value: Float) {
         let v = self
             .histograms
             .entry(key)
@@ -263,9 +327,9 @@ impl QueryCollector {
 
 #[derive(Default)]
 struct GaugeResult {
-    min: f32,
-    max: f32,
-    avg: f32,
-    latest: f32,
+    min: f64,
+    max: f64,
+    avg: f64,
+    latest: f64,
     count: usize,
 }
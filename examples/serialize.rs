@@ -23,7 +23,8 @@ struct Args {
     #[clap(default_value_t = 4096)]
     count: u64,
     /// What format to output, the original json blob, an array of metrics
-    /// events with timestamps and labels, or a json-lines entry of metrics events
+    /// events with timestamps and labels, a json-lines entry of metrics events, postcard,
+    /// a versioned binary capture or a comparison of the size of each format
     #[clap(long, short, default_value = "original")]
     format: OutputFormat,
     #[clap(long, short)]
@@ -39,6 +40,9 @@ fn main() {
     } = Args::parse();
     let recorder = ProcessionRecorder::default();
     metrics::set_global_recorder(recorder.clone()).unwrap();
+    metrics::describe_counter!("counter1", metrics::Unit::Count, "The first counter");
+    metrics::describe_gauge!("gauge1", metrics::Unit::Bytes, "The first gauge");
+    metrics::describe_histogram!("histo1", metrics::Unit::Seconds, "The first histogram");
     let counters = [
         metrics::counter!("counter1"),
         metrics::counter!("counter2", "label1" => "value1"),
@@ -92,6 +96,53 @@ fn main() {
             let bytes = postcard::to_stdvec(&v).unwrap();
             out.write_all(&bytes).unwrap();
         }
+        OutputFormat::Capture => out.write_all(&metrics.to_capture()).unwrap(),
+        OutputFormat::Sizes => {
+            let mut jsonl = 0;
+            for metric in metrics.iter() {
+                jsonl += serde_json::to_vec(&metric).unwrap().len() + 1;
+            }
+            let metadata = postcard::to_stdvec(&(&metrics.labels, &metrics.descriptions))
+                .unwrap()
+                .len();
+            let columnar = metrics
+                .chunks
+                .iter()
+                .map(|c| c.to_bytes().len())
+                .sum::<usize>();
+            let sizes = [
+                ("original", serde_json::to_vec(&*metrics).unwrap().len()),
+                (
+                    "array",
+                    serde_json::to_vec(&metrics.iter().collect::<Vec<_>>())
+                        .unwrap()
+                        .len(),
+                ),
+                ("json-lines", jsonl),
+                (
+                    "postcard",
+                    postcard::to_stdvec(&metrics.iter_owned().collect::<Vec<_>>())
+                        .unwrap()
+                        .len(),
+                ),
+                (
+                    "postcard procession",
+                    postcard::to_stdvec(&*metrics).unwrap().len(),
+                ),
+                ("columnar chunks + postcard labels", columnar + metadata),
+                ("capture", metrics.to_capture().len()),
+            ];
+            let events = metrics.chunks.iter().map(|c| c.len()).sum::<usize>();
+            writeln!(out, "{events} events").unwrap();
+            for (name, size) in sizes {
+                writeln!(
+                    out,
+                    "{name:>34}: {size:>10} bytes ({:.2}/event)",
+                    size as f64 / events.max(1) as f64
+                )
+                .unwrap();
+            }
+        }
     }
 }
 
@@ -120,6 +171,8 @@ enum OutputFormat {
     Array,
     JsonLines,
     Postcard,
+    Capture,
+    Sizes,
 }
 
 impl FromStr for OutputFormat {
@@ -131,9 +184,11 @@ impl FromStr for OutputFormat {
             "array" | "a" => Self::Array,
             "json-lines" | "j" => Self::JsonLines,
             "postcard" | "p" => Self::Postcard,
+            "capture" | "c" => Self::Capture,
+            "sizes" | "s" => Self::Sizes,
             _ => {
                 return Err(format!(
-                    "expected `original`, `o`, `array`, `a`, `json-lines`, or `j` found `{s}`"
+                    "expected `original`, `o`, `array`, `a`, `json-lines`, `j`, `postcard`, `p`, `capture`, `c`, `sizes`, or `s` found `{s}`"
                 ));
             }
         })
@@ -147,6 +202,8 @@ impl Display for OutputFormat {
             OutputFormat::Array => "Array",
             OutputFormat::JsonLines => "JsonLines",
             OutputFormat::Postcard => "Postcard",
+            OutputFormat::Capture => "Capture",
+            OutputFormat::Sizes => "Sizes",
         })
     }
 }
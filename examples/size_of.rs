@@ -13,6 +13,13 @@
 //! The metrics generated are incrementing counters with 1 label, the label has 4096 variations
 //! on the value
 //!
+//! Before starting, the size of a single `Event` is printed, this is affected by the
+//! `wide-labels` and `f64-values` features, for example
+//!
+//! ```shell
+//! $ cargo run --example size_of --features f64-values
+//! ```
+//!
 
 use std::time::Duration;
 
@@ -20,7 +27,11 @@ use clap::Parser;
 use indicatif::{
     HumanBytes, HumanCount, MultiProgress, ParallelProgressIterator, ProgressBar, ProgressStyle,
 };
-use metrics_procession::recorder::ProcessionRecorder;
+use metrics_procession::{
+    event::{Event, Float},
+    label_set::LabelId,
+    recorder::ProcessionRecorder,
+};
 use rayon::iter::{IntoParallelIterator, ParallelIterator};
 
 /// Calculate the memory size of a given number of metrics
@@ -33,6 +44,12 @@ struct Args {
 
 fn main() {
     let Args { count } = Args::parse();
+    println!(
+        "each event takes {} bytes ({} bit label ids, {} bit values)",
+        size_of::<Event>(),
+        LabelId::BITS,
+        size_of::<Float>() * 8,
+    );
     let recorder = ProcessionRecorder::default();
     metrics::set_global_recorder(recorder.clone()).unwrap();
     let mpg = MultiProgress::new();
@@ -0,0 +1,603 @@
+//! A versioned, self-describing file format for a [`Procession`]
+//!
+//! Every capture starts with a header that can be read without knowing anything else about
+//! the capture
+//!
+//! - [`MAGIC`]
+//! - the format version as a little endian `u16`
+//! - the width of the label identifiers and of the gauge and histogram values in bits
+//! - the [`SealOptions`] the chunks were encoded with
+//! - flags for the capture itself, currently only whether each chunk is followed by a checksum
+//! - when the capture was written and the name and version of the crate that wrote it
+//!
+//! followed by the body
+//!
+//! - the labels: a table of every distinct string followed by each key as positions in that
+//!   table along with its identifier
+//! - the descriptions: the name, unit and text of each
+//! - the chunks: a tag for each chunk, its `reference_time`, its [`ChunkIndex`] and the length
+//!   of the rest of the chunk, followed by either its events encoded with the [`crate::codec`]
+//!   or its rolled up buckets, and when enabled the CRC-32 of everything from the tag as a
+//!   little endian `u32`. A reader can skip a chunk using only its index and length
+//!
+//! Version 1 of the format had no capture flags and so no checksums and version 2 had no index
+//! or length for each chunk, both are still read by this build. A capture written with narrower label identifiers or values than the current build is
+//! widened as it is read. Wider label identifiers can be read as long as every identifier fits
+//! while wider values need the `f64-values` feature
+use metrics::{Key, KeyName, Label, Unit};
+use time::OffsetDateTime;
+
+use crate::{
+    chunk::{Chunk, ChunkIndex},
+    codec::{self, DecodeError, FLOAT_BYTES, Reader, SealOptions},
+    description::{Description, Descriptions},
+    label_set::{LabelId, LabelSet},
+    procession::Procession,
+    rollup::Rollup,
+};
+
+/// The bytes every capture starts with, the line endings and `^Z` catch a capture that was
+/// mangled by a text mode transfer
+pub const MAGIC: [u8; 8] = *b"MPROC\r\n\x1a";
+
+/// The version of the format written by this build
+pub const VERSION: u16 = 3;
+
+/// The name and version of this crate, recorded as the writer of each capture
+const WRITER: &str = concat!(env!("CARGO_PKG_NAME"), " ", env!("CARGO_PKG_VERSION"));
+
+/// The capture flag set when every chunk is followed by its checksum
+const CHECKSUMS: u8 = 0x01;
+
+/// The tag of a chunk holding events
+const EVENTS: u8 = 0;
+/// The tag of a chunk holding a [`Rollup`]
+const ROLLUP: u8 = 1;
+
+/// The metadata at the start of every capture
+#[derive(Debug, Clone, PartialEq)]
+pub struct Header {
+    /// The version of the format the capture was written with
+    pub version: u16,
+    /// The width of the label identifiers in the build that wrote the capture
+    pub label_id_bits: u8,
+    /// The width of the gauge and histogram values in the build that wrote the capture
+    pub value_bits: u8,
+    /// How the events of each chunk were encoded
+    pub options: SealOptions,
+    /// If every chunk is followed by its checksum
+    pub checksums: bool,
+    /// When the capture was written
+    pub created: OffsetDateTime,
+    /// The name and version of the crate that wrote the capture
+    pub writer: String,
+}
+
+/// How a capture is written
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CaptureOptions {
+    /// How the events of each chunk are encoded
+    pub seal: SealOptions,
+    /// Follow every chunk with its CRC-32 so any corruption is caught while decoding
+    pub checksums: bool,
+}
+
+impl Default for CaptureOptions {
+    fn default() -> Self {
+        Self {
+            seal: SealOptions::default(),
+            checksums: true,
+        }
+    }
+}
+
+/// The format of some serialized metrics, as detected from their first few bytes
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    /// A capture written with [`encode`] in the provided version of the format
+    Capture(u16),
+    /// Text that starts like a JSON object or array, this is either a serialized
+    /// [`Procession`], an array of [`crate::iter::Metric`]s or one per line
+    Json,
+    /// Anything else, like a binary serde format
+    Unknown,
+}
+
+/// Detect the format of the provided bytes
+pub fn detect(bytes: &[u8]) -> Format {
+    if let Some(rest) = bytes.strip_prefix(&MAGIC) {
+        return match rest {
+            [low, high, ..] => Format::Capture(u16::from_le_bytes([*low, *high])),
+            _ => Format::Unknown,
+        };
+    }
+    match bytes.iter().find(|b| !b.is_ascii_whitespace()) {
+        Some(b'{' | b'[') => Format::Json,
+        _ => Format::Unknown,
+    }
+}
+
+/// Encode the provided series as a capture, appending the bytes to `out`. Chunks are encoded
+/// with the provided [`SealOptions`](CaptureOptions::seal), any chunk already sealed with the
+/// same options is copied as-is
+pub fn encode(procession: &Procession, options: CaptureOptions, out: &mut Vec<u8>) {
+    encode_version(procession, options, VERSION, out);
+}
+
+/// Encode the provided series in an earlier version of the format, only the migrations are
+/// tested with anything but [`VERSION`]
+fn encode_version(
+    procession: &Procession,
+    options: CaptureOptions,
+    version: u16,
+    out: &mut Vec<u8>,
+) {
+    out.extend_from_slice(&MAGIC);
+    out.extend_from_slice(&version.to_le_bytes());
+    out.push(LabelId::BITS as u8);
+    out.push(FLOAT_BYTES * 8);
+    out.push(codec::flags(options.seal));
+    if version >= 2 {
+        out.push(if options.checksums { CHECKSUMS } else { 0 });
+    }
+    codec::write_time(out, procession.clock.now());
+    codec::write_str(out, WRITER);
+    encode_labels(&procession.labels, out);
+    encode_descriptions(&procession.descriptions, out);
+    codec::write_varint(out, procession.chunks.len() as u128);
+    let mut body = Vec::new();
+    for chunk in &procession.chunks {
+        let start = out.len();
+        body.clear();
+        let tag = match chunk.rollup() {
+            Some(rollup) => {
+                if version < 3 {
+                    codec::write_time(&mut body, chunk.reference_time);
+                }
+                rollup.encode(&mut body);
+                ROLLUP
+            }
+            None => {
+                match chunk.sealed_bytes(options.seal) {
+                    Some(bytes) => body.extend_from_slice(bytes),
+                    None => codec::encode_events(
+                        chunk.reference_time,
+                        &chunk.events(),
+                        options.seal,
+                        &mut body,
+                    ),
+                }
+                EVENTS
+            }
+        };
+        out.push(tag);
+        if version >= 3 {
+            codec::write_time(out, chunk.reference_time);
+            chunk.index().encode(out);
+            codec::write_varint(out, body.len() as u128);
+        }
+        out.extend_from_slice(&body);
+        if options.checksums {
+            let crc = codec::crc32(&out[start..]);
+            out.extend_from_slice(&crc.to_le_bytes());
+        }
+    }
+}
+
+/// Read the [`Header`] from the front of a capture
+pub fn header(bytes: &[u8]) -> Result<Header, DecodeError> {
+    read_header(&mut Reader::new(bytes))
+}
+
+/// Decode a capture written with [`encode`], `bytes` must contain exactly one capture. Every
+/// chunk is decoded into an open chunk, see [`Procession::seal`]
+pub fn decode(bytes: &[u8]) -> Result<Procession, DecodeError> {
+    let mut reader = Reader::new(bytes);
+    let header = read_header(&mut reader)?;
+    // each version is migrated here as the format changes
+    let procession = match header.version {
+        // version 1 is version 2 without any capture flags, version 2 is version 3 without an
+        // index or length for each chunk
+        1..=3 => decode_v3(&header, &mut reader)?,
+        version => return Err(DecodeError::UnsupportedVersion(version)),
+    };
+    match reader.remaining() {
+        0 => Ok(procession),
+        count => Err(DecodeError::TrailingBytes(count)),
+    }
+}
+
+pub(crate) fn read_header(reader: &mut Reader<'_>) -> Result<Header, DecodeError> {
+    if reader
+        .bytes(MAGIC.len())
+        .map_err(|_| DecodeError::NotACapture)?
+        != MAGIC
+    {
+        return Err(DecodeError::NotACapture);
+    }
+    let version = u16::from_le_bytes(
+        reader
+            .bytes(2)?
+            .try_into()
+            .expect("exactly 2 bytes were read"),
+    );
+    if version == 0 || version > VERSION {
+        return Err(DecodeError::UnsupportedVersion(version));
+    }
+    let label_id_bits = reader.byte()?;
+    if !matches!(label_id_bits, 16 | 32) {
+        return Err(DecodeError::LabelWidth(label_id_bits));
+    }
+    let value_bits = reader.byte()?;
+    if value_bits != 32 && value_bits != FLOAT_BYTES * 8 {
+        return Err(DecodeError::ValueWidth(value_bits / 8));
+    }
+    let options = codec::options(reader.byte()?)?;
+    let flags = if version >= 2 { reader.byte()? } else { 0 };
+    if flags & !CHECKSUMS != 0 {
+        return Err(DecodeError::UnknownFlags(flags));
+    }
+    Ok(Header {
+        version,
+        label_id_bits,
+        value_bits,
+        options,
+        checksums: flags & CHECKSUMS != 0,
+        created: reader.time()?,
+        writer: reader.str()?.to_string(),
+    })
+}
+
+fn decode_v3(header: &Header, reader: &mut Reader<'_>) -> Result<Procession, DecodeError> {
+    let labels = decode_labels(reader)?;
+    let descriptions = decode_descriptions(reader)?;
+    let len = reader.length()?;
+    let mut chunks = Vec::with_capacity(len);
+    for index in 0..len {
+        let chunk = match header.version {
+            1 | 2 => decode_chunk(header, index, reader)?,
+            _ => read_frame(header, index, reader)?.decode(header)?,
+        };
+        chunks.push(chunk);
+    }
+    Ok(Procession {
+        chunks,
+        labels,
+        descriptions,
+        ..Default::default()
+    })
+}
+
+/// A chunk of a version 3 capture that hasn't been decoded yet
+#[derive(Debug, Clone)]
+pub(crate) struct Frame<'a> {
+    tag: u8,
+    pub(crate) reference_time: OffsetDateTime,
+    pub(crate) index: ChunkIndex,
+    /// The encoded events or buckets of the chunk
+    pub(crate) body: &'a [u8],
+}
+
+impl Frame<'_> {
+    /// If this chunk holds a [`Rollup`] instead of events
+    pub(crate) fn is_rolled_up(&self) -> bool {
+        self.tag == ROLLUP
+    }
+
+    /// Decode the events or buckets of this chunk
+    pub(crate) fn decode(&self, header: &Header) -> Result<Chunk, DecodeError> {
+        if !self.is_rolled_up() {
+            let (reference_time, events) = codec::decode_events(self.body)?;
+            return Ok(Chunk::from_events(reference_time, events));
+        }
+        let mut reader = Reader::new(self.body);
+        let rollup = Rollup::decode(&mut reader, header.value_bits / 8)?;
+        match reader.remaining() {
+            0 => Ok(Chunk::from_rollup(self.reference_time, rollup)),
+            count => Err(DecodeError::TrailingBytes(count)),
+        }
+    }
+}
+
+/// Read the chunk at position `index` of a version 3 capture from the front of the provided
+/// reader without decoding its events, checking its checksum if the capture has them
+pub(crate) fn read_frame<'a>(
+    header: &Header,
+    index: usize,
+    reader: &mut Reader<'a>,
+) -> Result<Frame<'a>, DecodeError> {
+    let start = reader.remaining();
+    let frame_bytes = reader.rest();
+    let tag = reader.byte()?;
+    if tag != EVENTS && tag != ROLLUP {
+        return Err(DecodeError::UnknownKind(tag));
+    }
+    let reference_time = reader.time()?;
+    let chunk_index = ChunkIndex::decode(reader)?;
+    let len = reader.length()?;
+    let body = reader.bytes(len)?;
+    if header.checksums {
+        check_crc(&frame_bytes[..start - reader.remaining()], index, reader)?;
+    }
+    Ok(Frame {
+        tag,
+        reference_time,
+        index: chunk_index,
+        body,
+    })
+}
+
+/// Read the CRC-32 following the chunk at position `index` and compare it to its bytes
+fn check_crc(encoded: &[u8], index: usize, reader: &mut Reader<'_>) -> Result<(), DecodeError> {
+    let crc = u32::from_le_bytes(
+        reader
+            .bytes(4)?
+            .try_into()
+            .expect("exactly 4 bytes were read"),
+    );
+    if codec::crc32(encoded) != crc {
+        return Err(DecodeError::ChunkChecksum(index));
+    }
+    Ok(())
+}
+
+/// Decode the chunk at position `index` of a version 1 or 2 capture from the front of the
+/// provided reader, checking its checksum if the capture has them
+pub(crate) fn decode_chunk(
+    header: &Header,
+    index: usize,
+    reader: &mut Reader<'_>,
+) -> Result<Chunk, DecodeError> {
+    let start = reader.remaining();
+    let chunk_bytes = reader.rest();
+    let chunk = match reader.byte()? {
+        EVENTS => {
+            let (reference_time, events) = codec::decode_from(reader)?;
+            Chunk::from_events(reference_time, events)
+        }
+        ROLLUP => {
+            let reference_time = reader.time()?;
+            Chunk::from_rollup(
+                reference_time,
+                Rollup::decode(reader, header.value_bits / 8)?,
+            )
+        }
+        tag => return Err(DecodeError::UnknownKind(tag)),
+    };
+    if header.checksums {
+        check_crc(&chunk_bytes[..start - reader.remaining()], index, reader)?;
+    }
+    Ok(chunk)
+}
+
+/// Append every distinct string once followed by each key as positions of its strings
+fn encode_labels(labels: &LabelSet, out: &mut Vec<u8>) {
+    let strings: Vec<&str> = labels.strings().collect();
+    let position = |s: &str| {
+        strings
+            .binary_search(&s)
+            .expect("every string of a key is interned") as u128
+    };
+    codec::write_varint(out, strings.len() as u128);
+    for s in &strings {
+        codec::write_str(out, s);
+    }
+    codec::write_varint(out, labels.len() as u128);
+    for (key, id) in labels.iter() {
+        codec::write_varint(out, id as u128);
+        codec::write_varint(out, position(key.name()));
+        codec::write_varint(out, key.labels().len() as u128);
+        for label in key.labels() {
+            codec::write_varint(out, position(label.key()));
+            codec::write_varint(out, position(label.value()));
+        }
+    }
+}
+
+pub(crate) fn decode_labels(reader: &mut Reader<'_>) -> Result<LabelSet, DecodeError> {
+    let len = reader.length()?;
+    let mut strings = Vec::with_capacity(len);
+    for _ in 0..len {
+        strings.push(reader.str()?);
+    }
+    let string = |reader: &mut Reader<'_>| {
+        let index = reader.varint_u64()?;
+        strings
+            .get(index as usize)
+            .map(|s| s.to_string())
+            .ok_or(DecodeError::StringOutOfRange(index))
+    };
+    let len = reader.length()?;
+    let mut ids = Vec::with_capacity(len);
+    for _ in 0..len {
+        let id = reader.varint_u64()?;
+        let id = LabelId::try_from(id).map_err(|_| DecodeError::LabelOutOfRange(id))?;
+        let name = string(reader)?;
+        let label_count = reader.length()?;
+        let mut labels = Vec::with_capacity(label_count);
+        for _ in 0..label_count {
+            labels.push(Label::new(string(reader)?, string(reader)?));
+        }
+        ids.push((Key::from_parts(name, labels), id));
+    }
+    Ok(ids.into_iter().collect())
+}
+
+fn encode_descriptions(descriptions: &Descriptions, out: &mut Vec<u8>) {
+    codec::write_varint(out, descriptions.0.len() as u128);
+    for (name, description) in &descriptions.0 {
+        codec::write_str(out, name.as_str());
+        codec::write_str(
+            out,
+            description.unit.map(|u| u.as_str()).unwrap_or_default(),
+        );
+        codec::write_str(out, &description.text);
+    }
+}
+
+pub(crate) fn decode_descriptions(reader: &mut Reader<'_>) -> Result<Descriptions, DecodeError> {
+    let len = reader.length()?;
+    let mut ret = Descriptions::default();
+    for _ in 0..len {
+        let name = KeyName::from(reader.str()?.to_string());
+        let unit = match reader.str()? {
+            "" => None,
+            unit => Some(
+                Unit::from_string(unit)
+                    .ok_or_else(|| DecodeError::UnknownUnit(unit.to_string()))?,
+            ),
+        };
+        let text = reader.str()?.to_string();
+        ret.insert(name, Description { unit, text });
+    }
+    Ok(ret)
+}
+
+impl Procession {
+    /// Encode this series as a [`crate::capture`] with a checksum for each chunk, chunks are
+    /// encoded with the [`SealOptions`] this series seals chunks with or the defaults if it
+    /// doesn't
+    pub fn to_capture(&self) -> Vec<u8> {
+        let mut out = Vec::new();
+        let options = CaptureOptions {
+            seal: self.sealing.unwrap_or_default(),
+            ..Default::default()
+        };
+        encode(self, options, &mut out);
+        out
+    }
+
+    /// Decode a series from a [`crate::capture`]
+    pub fn from_capture(bytes: &[u8]) -> Result<Self, DecodeError> {
+        decode(bytes)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use time::Duration;
+
+    use crate::test_util::{procession, start};
+
+    use super::*;
+
+    #[test]
+    fn round_trip() {
+        let procession = procession();
+        assert!(procession.chunks.iter().any(|c| c.is_rolled_up()));
+        assert!(procession.chunks.iter().any(|c| c.is_sealed()));
+        let bytes = procession.to_capture();
+        assert_eq!(detect(&bytes), Format::Capture(VERSION));
+        assert_eq!(Procession::from_capture(&bytes).unwrap(), procession);
+        let header = header(&bytes).unwrap();
+        assert_eq!(header.version, VERSION);
+        assert_eq!(header.label_id_bits, LabelId::BITS as u8);
+        assert_eq!(header.value_bits, FLOAT_BYTES * 8);
+        assert_eq!(header.options, SealOptions::default());
+        assert!(header.checksums);
+        assert_eq!(header.created, start() + Duration::milliseconds(500_000));
+        assert_eq!(header.writer, WRITER);
+        for options in [
+            CaptureOptions {
+                seal: SealOptions {
+                    xor_values: false,
+                    ..Default::default()
+                },
+                checksums: false,
+            },
+            CaptureOptions::default(),
+        ] {
+            let mut bytes = Vec::new();
+            encode(&procession, options, &mut bytes);
+            assert_eq!(decode(&bytes).unwrap(), procession);
+        }
+        let empty = Procession::default();
+        assert_eq!(decode(&empty.to_capture()).unwrap(), empty);
+    }
+
+    #[test]
+    fn migrates_version_1() {
+        let procession = procession();
+        let mut bytes = Vec::new();
+        let options = CaptureOptions {
+            checksums: false,
+            ..Default::default()
+        };
+        encode_version(&procession, options, 1, &mut bytes);
+        // version 1 had no capture flags
+        let mut version_2 = Vec::new();
+        encode_version(&procession, options, 2, &mut version_2);
+        assert_eq!(bytes.len() + 1, version_2.len());
+        assert_eq!(detect(&bytes), Format::Capture(1));
+        assert!(!header(&bytes).unwrap().checksums);
+        assert_eq!(decode(&bytes).unwrap(), procession);
+    }
+
+    #[test]
+    fn migrates_version_2() {
+        let procession = procession();
+        for options in [
+            CaptureOptions::default(),
+            CaptureOptions {
+                checksums: false,
+                ..Default::default()
+            },
+        ] {
+            let mut bytes = Vec::new();
+            encode_version(&procession, options, 2, &mut bytes);
+            assert_eq!(detect(&bytes), Format::Capture(2));
+            assert_eq!(decode(&bytes).unwrap(), procession);
+        }
+    }
+
+    #[test]
+    fn checksums() {
+        let procession = procession();
+        let bytes = procession.to_capture();
+        let last = procession.chunks.len() - 1;
+        // the checksum of the last chunk is the last 4 bytes, corrupt it and the byte before it
+        for offset in [bytes.len() - 1, bytes.len() - 5] {
+            let mut corrupt = bytes.clone();
+            corrupt[offset] ^= 0x01;
+            assert_eq!(decode(&corrupt), Err(DecodeError::ChunkChecksum(last)));
+        }
+    }
+
+    #[test]
+    fn detects_formats() {
+        let procession = procession();
+        let json = serde_json::to_vec(&procession).unwrap();
+        assert_eq!(detect(&json), Format::Json);
+        assert_eq!(detect(b"  \n[]"), Format::Json);
+        assert_eq!(detect(&MAGIC), Format::Unknown);
+        assert_eq!(detect(b""), Format::Unknown);
+        assert_eq!(decode(&json), Err(DecodeError::NotACapture));
+    }
+
+    #[test]
+    fn errors() {
+        let bytes = procession().to_capture();
+        // every field of the header and the start of the body, then a sample of the chunks
+        for len in (0..128).chain((128..bytes.len()).step_by(97)) {
+            assert!(decode(&bytes[..len]).is_err(), "{len}");
+        }
+        let mut trailing = bytes.clone();
+        trailing.push(0);
+        assert_eq!(decode(&trailing), Err(DecodeError::TrailingBytes(1)));
+        let mut version = bytes.clone();
+        version[MAGIC.len()..][..2].copy_from_slice(&(VERSION + 1).to_le_bytes());
+        assert_eq!(
+            decode(&version),
+            Err(DecodeError::UnsupportedVersion(VERSION + 1))
+        );
+        let mut labels = bytes.clone();
+        labels[MAGIC.len() + 2] = 8;
+        assert_eq!(decode(&labels), Err(DecodeError::LabelWidth(8)));
+        let mut flags = bytes.clone();
+        flags[MAGIC.len() + 5] |= 0x80;
+        assert_eq!(
+            decode(&flags),
+            Err(DecodeError::UnknownFlags(CHECKSUMS | 0x80))
+        );
+    }
+}
@@ -1,36 +1,463 @@
+use std::{borrow::Cow, ops::Deref};
+
 use serde::{Deserialize, Serialize};
-use time::OffsetDateTime;
+use time::{Duration, OffsetDateTime};
 
-use crate::event::Event;
+use crate::{
+    codec::{self, DecodeError, Reader, SealOptions},
+    event::{Entry, Event, RawEntry},
+    label_set::LabelId,
+    memory::MemoryUsage,
+    rollup::{Aggregate, Rollup},
+};
 
 /// A chunk of metrics that represents all events emitted from the `reference_time`
 /// through 65 seconds after that reference time.
-#[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
+///
+/// A chunk is either open, holding its [`Events`] as-is, or sealed, holding them encoded
+/// with the [`crate::codec`] until they are needed. Sealed chunks are decoded on demand by
+/// [`Chunk::events`] and any method that adds events will decode them and leave the chunk open,
+/// so a run of late events only decodes it once, until it is sealed again.
+/// Old chunks can also be rolled up, replacing their events with a [`Rollup`] of each key
+#[derive(Debug, Clone)]
 pub struct Chunk {
     /// The start time of this chunk
     pub reference_time: OffsetDateTime,
-    /// The events that have happened within 65 seconds of the reference time
+    storage: Storage,
+}
+
+#[derive(Debug, Clone)]
+enum Storage {
+    Open(Events),
+    Sealed(Sealed),
+    Rollup(Rollup),
+}
+
+/// The encoded form of a sealed chunk
+#[derive(Debug, Clone)]
+struct Sealed {
+    bytes: Box<[u8]>,
+    options: SealOptions,
+    index: ChunkIndex,
+}
+
+/// A summary of the events in a [`Chunk`], this is kept for every sealed chunk so they
+/// can be filtered without being decoded
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ChunkIndex {
+    /// The label identifiers with at least one event in the chunk, in ascending order
+    pub labels: Vec<LabelId>,
+    /// The smallest `ms` of any event in the chunk
+    pub first_ms: u16,
+    /// The largest `ms` of any event in the chunk
+    pub last_ms: u16,
+    /// The number of counter events
+    pub counters: usize,
+    /// The number of gauge events
+    pub gauges: usize,
+    /// The number of histogram events
+    pub histograms: usize,
+}
+
+impl ChunkIndex {
+    /// Summarize the buckets of the provided rollup
+    pub fn from_rollup(rollup: &Rollup) -> Self {
+        let mut ret = Self {
+            first_ms: rollup.buckets.first().map(|b| b.ms).unwrap_or_default(),
+            last_ms: rollup.buckets.last().map(|b| b.ms).unwrap_or_default(),
+            ..Default::default()
+        };
+        for bucket in &rollup.buckets {
+            match bucket.aggregate {
+                Aggregate::Counter { .. } => ret.counters += 1,
+                Aggregate::Gauge { .. } => ret.gauges += 1,
+                Aggregate::Histogram(_) => ret.histograms += 1,
+            }
+        }
+        ret.labels = rollup.buckets.iter().map(|b| b.label).collect();
+        ret.labels.sort_unstable();
+        ret.labels.dedup();
+        ret
+    }
+
+    /// Summarize the provided events
+    pub fn new(events: &Events) -> Self {
+        let mut ret = Self {
+            first_ms: events.iter().map(|e| e.ms).min().unwrap_or_default(),
+            last_ms: events.iter().map(|e| e.ms).max().unwrap_or_default(),
+            ..Default::default()
+        };
+        for event in events.iter() {
+            match event.entry {
+                RawEntry::Counter { .. } | RawEntry::WideCounter { .. } => ret.counters += 1,
+                RawEntry::Gauge { .. } => ret.gauges += 1,
+                RawEntry::Histogram { .. } => ret.histograms += 1,
+            }
+        }
+        ret.labels = events.iter().map(|e| e.label).collect();
+        ret.labels.sort_unstable();
+        ret.labels.dedup();
+        // the index is kept for as long as the chunk is sealed
+        ret.labels.shrink_to_fit();
+        ret
+    }
+
+    /// The total number of events
+    pub fn len(&self) -> usize {
+        self.counters + self.gauges + self.histograms
+    }
+
+    /// If there are no events
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// If there is at least one event for the provided label
+    pub fn contains(&self, label: LabelId) -> bool {
+        self.labels.binary_search(&label).is_ok()
+    }
+
+    /// Append this index to `out`, see [`crate::capture`]. The labels are written as the
+    /// difference from the label before them
+    pub(crate) fn encode(&self, out: &mut Vec<u8>) {
+        codec::write_varint(out, self.labels.len() as u128);
+        let mut previous = 0;
+        for label in &self.labels {
+            codec::write_varint(out, (label - previous) as u128);
+            previous = *label;
+        }
+        for value in [self.first_ms, self.last_ms] {
+            codec::write_varint(out, u128::from(value));
+        }
+        for count in [self.counters, self.gauges, self.histograms] {
+            codec::write_varint(out, count as u128);
+        }
+    }
+
+    /// Decode an index written by [`ChunkIndex::encode`]
+    pub(crate) fn decode(reader: &mut Reader<'_>) -> Result<Self, DecodeError> {
+        let len = reader.length()?;
+        let mut labels = Vec::with_capacity(len);
+        let mut previous: u64 = 0;
+        for _ in 0..len {
+            let label = previous
+                .checked_add(reader.varint_u64()?)
+                .ok_or(DecodeError::InvalidVarint)?;
+            labels.push(LabelId::try_from(label).map_err(|_| DecodeError::LabelOutOfRange(label))?);
+            previous = label;
+        }
+        let mut ms = [0; 2];
+        for ms in &mut ms {
+            let value = reader.varint_u64()?;
+            *ms = u16::try_from(value).map_err(|_| DecodeError::MsOutOfRange(value as i64))?;
+        }
+        let mut counts = [0; 3];
+        for count in &mut counts {
+            *count =
+                usize::try_from(reader.varint_u64()?).map_err(|_| DecodeError::InvalidVarint)?;
+        }
+        let [first_ms, last_ms] = ms;
+        let [counters, gauges, histograms] = counts;
+        Ok(Self {
+            labels,
+            first_ms,
+            last_ms,
+            counters,
+            gauges,
+            histograms,
+        })
+    }
+}
+
+/// The events that have happened within 65 seconds of a [`Chunk`]'s reference time
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct Events {
+    /// The events in the order they occurred
     pub events: Vec<Event>,
+    /// Counter values that were too large to fit in a [`RawEntry::Counter`]
+    #[serde(default)]
+    pub wide_values: Vec<u64>,
+}
+
+impl Deref for Events {
+    type Target = [Event];
+
+    fn deref(&self) -> &Self::Target {
+        &self.events
+    }
+}
+
+impl Events {
+    /// Add a new event
+    pub fn push(&mut self, event: Event) {
+        self.events.push(event);
+    }
+
+    /// Convert the provided [`Entry`] into its compact form, storing any values that
+    /// do not fit in `wide_values`
+    pub fn raw_entry(&mut self, entry: Entry) -> RawEntry {
+        match entry {
+            Entry::Gauge { value, op } => RawEntry::Gauge { value, op },
+            Entry::Histogram { value } => RawEntry::Histogram { value },
+            Entry::Counter { value, op } => match u32::try_from(value) {
+                Ok(value) => RawEntry::Counter { value, op },
+                Err(_) => {
+                    let index = self.wide_values.len() as u32;
+                    self.wide_values.push(value);
+                    RawEntry::WideCounter { index, op }
+                }
+            },
+        }
+    }
+
+    /// Resolve the full [`Entry`] for one of these events, `None` if it is a
+    /// [`RawEntry::WideCounter`] whose index is past the end of `wide_values`
+    pub fn entry(&self, event: &Event) -> Option<Entry> {
+        Some(match event.entry {
+            RawEntry::Gauge { value, op } => Entry::Gauge { value, op },
+            RawEntry::Histogram { value } => Entry::Histogram { value },
+            RawEntry::Counter { value, op } => Entry::Counter {
+                value: u64::from(value),
+                op,
+            },
+            RawEntry::WideCounter { index, op } => Entry::Counter {
+                value: *self.wide_values.get(index as usize)?,
+                op,
+            },
+        })
+    }
 }
 
 impl Chunk {
     /// Create a new chunk from the provided time
     pub fn new(reference_time: OffsetDateTime) -> Self {
+        Self::from_events(reference_time, Events::default())
+    }
+
+    /// Create a new open chunk holding the provided events
+    pub fn from_events(reference_time: OffsetDateTime, events: Events) -> Self {
+        Self {
+            reference_time,
+            storage: Storage::Open(events),
+        }
+    }
+
+    /// Create a new chunk holding the provided rollup in place of its events
+    pub fn from_rollup(reference_time: OffsetDateTime, rollup: Rollup) -> Self {
         Self {
             reference_time,
-            events: Default::default(),
+            storage: Storage::Rollup(rollup),
+        }
+    }
+
+    /// The events in this chunk, a sealed chunk will be decoded into a new [`Events`] while a
+    /// rolled up chunk has no events, see [`Chunk::rollup`]
+    pub fn events(&self) -> Cow<'_, Events> {
+        match &self.storage {
+            Storage::Open(events) => Cow::Borrowed(events),
+            Storage::Rollup(_) => Cow::Owned(Events::default()),
+            Storage::Sealed(sealed) => Cow::Owned(
+                codec::decode_events(&sealed.bytes)
+                    .expect("sealed chunks are always valid")
+                    .1,
+            ),
+        }
+    }
+
+    /// The rolled up buckets replacing the events of this chunk, `None` unless the chunk was
+    /// rolled up
+    pub fn rollup(&self) -> Option<&Rollup> {
+        match &self.storage {
+            Storage::Rollup(rollup) => Some(rollup),
+            _ => None,
+        }
+    }
+
+    /// If this chunk has been rolled up
+    pub fn is_rolled_up(&self) -> bool {
+        matches!(self.storage, Storage::Rollup(_))
+    }
+
+    /// The number of events in this chunk, or buckets if it was rolled up
+    pub fn len(&self) -> usize {
+        match &self.storage {
+            Storage::Open(events) => events.len(),
+            Storage::Sealed(sealed) => sealed.index.len(),
+            Storage::Rollup(rollup) => rollup.buckets.len(),
+        }
+    }
+
+    /// If this chunk has no events
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// The largest `ms` value of any event in this chunk
+    pub fn last_ms(&self) -> u16 {
+        match &self.storage {
+            Storage::Open(events) => events.iter().map(|e| e.ms).max().unwrap_or_default(),
+            Storage::Sealed(sealed) => sealed.index.last_ms,
+            Storage::Rollup(rollup) => rollup.buckets.last().map(|b| b.ms).unwrap_or_default(),
+        }
+    }
+
+    /// The summary of the events in this chunk, this is kept for sealed chunks and
+    /// calculated for open chunks
+    pub fn index(&self) -> Cow<'_, ChunkIndex> {
+        match &self.storage {
+            Storage::Open(events) => Cow::Owned(ChunkIndex::new(events)),
+            Storage::Sealed(sealed) => Cow::Borrowed(&sealed.index),
+            Storage::Rollup(rollup) => Cow::Owned(ChunkIndex::from_rollup(rollup)),
+        }
+    }
+
+    /// If this chunk has at least one event for the provided label, this will not decode a
+    /// sealed chunk
+    pub fn contains_label(&self, label: LabelId) -> bool {
+        match &self.storage {
+            Storage::Open(events) => events.iter().any(|e| e.label == label),
+            Storage::Sealed(sealed) => sealed.index.contains(label),
+            Storage::Rollup(rollup) => rollup.buckets.iter().any(|b| b.label == label),
+        }
+    }
+
+    /// The times of the first and last events in this chunk, `None` if there are no events.
+    /// This will not decode a sealed chunk
+    pub fn time_range(&self) -> Option<(OffsetDateTime, OffsetDateTime)> {
+        let (first_ms, last_ms) = match &self.storage {
+            Storage::Open(events) => (
+                events.iter().map(|e| e.ms).min()?,
+                events.iter().map(|e| e.ms).max()?,
+            ),
+            Storage::Sealed(sealed) if sealed.index.is_empty() => return None,
+            Storage::Sealed(sealed) => (sealed.index.first_ms, sealed.index.last_ms),
+            Storage::Rollup(rollup) => (rollup.buckets.first()?.ms, rollup.buckets.last()?.ms),
+        };
+        let at = |ms: u16| self.reference_time + Duration::milliseconds(i64::from(ms));
+        Some((at(first_ms), at(last_ms)))
+    }
+
+    /// If this chunk is currently sealed
+    pub fn is_sealed(&self) -> bool {
+        matches!(self.storage, Storage::Sealed(_))
+    }
+
+    /// Encode the events of this chunk with the provided [`SealOptions`], re-encoding the
+    /// chunk if it was already sealed with different options. A rolled up chunk is left as is
+    pub fn seal(&mut self, options: SealOptions) {
+        match &self.storage {
+            Storage::Sealed(sealed) if sealed.options == options => return,
+            Storage::Rollup(_) => return,
+            _ => {}
+        }
+        let events = self.events();
+        let mut bytes = Vec::new();
+        codec::encode_events(self.reference_time, &events, options, &mut bytes);
+        let sealed = Sealed {
+            bytes: bytes.into_boxed_slice(),
+            options,
+            index: ChunkIndex::new(&events),
+        };
+        self.storage = Storage::Sealed(sealed);
+    }
+
+    /// The encoded events of this chunk if it is sealed with the provided [`SealOptions`]
+    pub(crate) fn sealed_bytes(&self, options: SealOptions) -> Option<&[u8]> {
+        match &self.storage {
+            Storage::Sealed(sealed) if sealed.options == options => Some(&sealed.bytes),
+            _ => None,
+        }
+    }
+
+    /// Decode the events of this chunk if it was sealed
+    pub fn unseal(&mut self) {
+        if self.is_sealed() {
+            let events = self.events().into_owned();
+            self.storage = Storage::Open(events);
+        }
+    }
+
+    /// Apply a change to the events of this chunk, a sealed chunk is decoded first and left
+    /// open. A rolled up chunk has no events to change so `rolled_up` is called with its rollup
+    /// instead
+    fn modify<T>(
+        &mut self,
+        f: impl FnOnce(&mut Events) -> T,
+        rolled_up: impl FnOnce(&mut Rollup) -> T,
+    ) -> T {
+        if let Storage::Rollup(rollup) = &mut self.storage {
+            return rolled_up(rollup);
         }
+        self.unseal();
+        let Storage::Open(events) = &mut self.storage else {
+            unreachable!("chunk was just unsealed")
+        };
+        f(events)
     }
 
-    /// Add a new event into this chunk
+    /// Add a new event into this chunk, a rolled up chunk will fold it into its buckets
+    ///
+    /// # Panics
+    ///
+    /// If this chunk is rolled up and the event is a [`RawEntry::WideCounter`], a rollup has no
+    /// `wide_values` for it to refer to. Use [`Chunk::push_entry`] to add a counter of any size
     pub fn push(&mut self, event: Event) {
-        self.events.push(event);
+        self.modify(
+            |events| events.push(event),
+            |rollup| {
+                let entry = Events::default()
+                    .entry(&event)
+                    .expect("a wide counter can't be pushed into a rolled up chunk");
+                rollup.insert(event.ms, event.label, entry)
+            },
+        );
+    }
+
+    /// Add a new event into this chunk from an [`Entry`], storing any values that
+    /// do not fit into a [`RawEntry`] in `wide_values`
+    pub fn push_entry(&mut self, ms: u16, label: LabelId, entry: Entry) {
+        self.modify(
+            |events| {
+                let entry = events.raw_entry(entry);
+                events.push(Event { entry, ms, label });
+            },
+            |rollup| rollup.insert(ms, label, entry),
+        );
     }
 
-    /// A naive method for trying to determine the total memory size used by this chunk
+    /// Insert a new event into this chunk from an [`Entry`], keeping the events ordered by
+    /// their `ms` value. An event is placed after any existing events with the same `ms`
+    pub fn insert_entry(&mut self, ms: u16, label: LabelId, entry: Entry) {
+        self.modify(
+            |events| {
+                let index = events.partition_point(|e| e.ms <= ms);
+                let entry = events.raw_entry(entry);
+                events.events.insert(index, Event { entry, ms, label });
+            },
+            |rollup| rollup.insert(ms, label, entry),
+        );
+    }
+
+    /// The total memory used by this chunk, including any spare capacity
     pub fn memory_size(&self) -> usize {
-        use std::mem::size_of;
-        size_of::<Self>() + (self.events.len() * (size_of::<Event>()))
+        size_of::<Self>() + self.memory_usage().total()
+    }
+
+    /// A breakdown of the heap memory held by this chunk
+    pub fn memory_usage(&self) -> MemoryUsage {
+        match &self.storage {
+            Storage::Open(events) => {
+                MemoryUsage::events(&events.events) + MemoryUsage::events(&events.wide_values)
+            }
+            Storage::Sealed(sealed) => {
+                MemoryUsage {
+                    events_used: sealed.bytes.len(),
+                    events_reserved: sealed.bytes.len(),
+                    ..Default::default()
+                } + MemoryUsage::events(&sealed.index.labels)
+            }
+            Storage::Rollup(rollup) => rollup.memory_usage(),
+        }
     }
 }
 
@@ -40,3 +467,77 @@ impl Default for Chunk {
         Self::new(OffsetDateTime::now_utc())
     }
 }
+
+/// Two chunks are equal when they have the same reference time and events (or rollup),
+/// regardless of whether either is sealed
+impl PartialEq for Chunk {
+    fn eq(&self, other: &Self) -> bool {
+        self.reference_time == other.reference_time
+            && self.events() == other.events()
+            && self.rollup() == other.rollup()
+    }
+}
+
+/// Helper for serializing a [`Chunk`] with its decoded events
+#[derive(Serialize)]
+#[serde(rename = "Chunk")]
+struct SerChunk<'a> {
+    reference_time: OffsetDateTime,
+    events: &'a [Event],
+    wide_values: &'a [u64],
+    rollup: Option<&'a Rollup>,
+}
+
+/// Helper for deserializing a [`Chunk`], this is always deserialized as an open chunk
+/// unless it was rolled up
+#[derive(Deserialize)]
+#[serde(rename = "Chunk")]
+struct DeChunk {
+    reference_time: OffsetDateTime,
+    events: Vec<Event>,
+    #[serde(default)]
+    wide_values: Vec<u64>,
+    #[serde(default)]
+    rollup: Option<Rollup>,
+}
+
+/// Serialized with the decoded events, a sealed chunk serializes the same as an open one
+impl Serialize for Chunk {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: serde::Serializer,
+    {
+        let events = self.events();
+        SerChunk {
+            reference_time: self.reference_time,
+            events: &events.events,
+            wide_values: &events.wide_values,
+            rollup: self.rollup(),
+        }
+        .serialize(serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for Chunk {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        let DeChunk {
+            reference_time,
+            events,
+            wide_values,
+            rollup,
+        } = DeChunk::deserialize(deserializer)?;
+        if let Some(rollup) = rollup {
+            return Ok(Self::from_rollup(reference_time, rollup));
+        }
+        Ok(Self::from_events(
+            reference_time,
+            Events {
+                events,
+                wide_values,
+            },
+        ))
+    }
+}
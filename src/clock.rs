@@ -0,0 +1,96 @@
+//! The source of timestamps used when recording events into a [`crate::procession::Procession`]
+use std::{
+    fmt::Debug,
+    sync::{Arc, Mutex},
+};
+
+use time::{Duration, OffsetDateTime};
+
+/// A source for the current time
+pub trait Clock: Debug + Send + Sync {
+    /// The current time
+    fn now(&self) -> OffsetDateTime;
+}
+
+/// The default [`Clock`] which reports the system's time via [`time::OffsetDateTime::now_utc`]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> OffsetDateTime {
+        OffsetDateTime::now_utc()
+    }
+}
+
+/// A [`Clock`] that only moves when told to, useful for producing deterministic timestamps
+/// in tests
+#[derive(Debug)]
+pub struct ManualClock(Mutex<OffsetDateTime>);
+
+impl ManualClock {
+    /// Create a new clock stopped at the provided time
+    pub fn new(start: OffsetDateTime) -> Self {
+        Self(Mutex::new(start))
+    }
+
+    /// Move the clock forward by the provided duration
+    pub fn advance(&self, duration: Duration) {
+        *self.0.lock().unwrap_or_else(|e| e.into_inner()) += duration;
+    }
+
+    /// Move the clock to the provided time
+    pub fn set(&self, now: OffsetDateTime) {
+        *self.0.lock().unwrap_or_else(|e| e.into_inner()) = now;
+    }
+}
+
+impl Clock for ManualClock {
+    fn now(&self) -> OffsetDateTime {
+        *self.0.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+/// The clock used when none has been provided
+pub(crate) fn system() -> Arc<dyn Clock> {
+    Arc::new(SystemClock)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        event::{Entry, Op},
+        procession::Procession,
+        test_util::start,
+    };
+
+    use super::*;
+
+    #[test]
+    fn chunk_rollover() {
+        let start = start();
+        let clock = Arc::new(ManualClock::new(start));
+        let mut procession = Procession::default().with_clock(clock.clone());
+        let entry = Entry::Counter {
+            value: 1,
+            op: Op::Add,
+        };
+        procession.insert_entry(entry, 0);
+        clock.advance(Duration::milliseconds(i64::from(u16::MAX)));
+        procession.insert_entry(entry, 0);
+        assert_eq!(procession.chunks.len(), 1);
+        assert_eq!(procession.chunks[0].reference_time, start);
+        assert_eq!(
+            procession.chunks[0]
+                .events()
+                .iter()
+                .map(|e| e.ms)
+                .collect::<Vec<_>>(),
+            vec![0, u16::MAX]
+        );
+        clock.advance(Duration::milliseconds(1));
+        procession.insert_entry(entry, 0);
+        assert_eq!(procession.chunks.len(), 2);
+        assert_eq!(procession.chunks[1].reference_time, clock.now());
+        assert_eq!(procession.chunks[1].events()[0].ms, 0);
+    }
+}
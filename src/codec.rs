@@ -0,0 +1,1086 @@
+//! A compact columnar binary encoding for a [`Chunk`]
+//!
+//! Instead of writing each [`Event`] as a padded struct, the events of a chunk are split into
+//! columns that each compress well on their own
+//!
+//! - the header: the width of the values and any [`SealOptions`], the `reference_time`, the
+//!   number of events and the number of `wide_values`
+//! - `ms`: the difference from the previous event as a zigzag varint, events are almost always
+//!   in order so this is typically 1 byte per event
+//! - `label`: a varint per event
+//! - kind: the type and [`Op`] of each event packed into 4 bits, 2 events per byte
+//! - counters: a varint per counter event, either the value or its index in `wide_values`
+//! - floats: the little endian bytes of each gauge and histogram value, or when
+//!   [`SealOptions::xor_values`] is set the values of each label compressed with Gorilla style
+//!   XOR encoding
+//! - `wide_values`: a varint per value
+//!
+//! A rolled up chunk sets the rollup flag in the header, which is followed by the
+//! `reference_time` and the buckets of its [`Rollup`] uncompressed
+use std::{collections::HashMap, fmt::Display};
+
+use time::{OffsetDateTime, UtcOffset};
+
+use crate::{
+    chunk::{Chunk, Events},
+    event::{Event, Float, Op, RawEntry},
+    label_set::LabelId,
+    rollup::Rollup,
+};
+
+/// The encoded kind of a gauge, the kinds of gauges and counters are followed by one kind per
+/// [`Op`] so the op bits are added to them. These are shared with [`crate::rollup::Rollup`]
+pub(crate) const GAUGE: u8 = 0;
+/// The encoded kind of a [`RawEntry::Counter`]
+pub(crate) const COUNTER: u8 = 3;
+/// The encoded kind of a histogram
+pub(crate) const HISTOGRAM: u8 = 6;
+/// The encoded kind of a [`RawEntry::WideCounter`]
+const WIDE_COUNTER: u8 = 7;
+/// One past the last encoded kind
+const KINDS: u8 = 10;
+
+/// The number of bytes in a [`Float`]
+pub(crate) const FLOAT_BYTES: u8 = std::mem::size_of::<Float>() as u8;
+/// The bits of the header byte holding the number of bytes in a [`Float`]
+const FLOAT_BYTES_MASK: u8 = 0x0F;
+/// The header flag set when the floats column is XOR encoded
+const XOR_VALUES: u8 = 0x10;
+/// The bits of the header byte holding the [`Compression`] of everything after the header
+const COMPRESSION_MASK: u8 = 0x60;
+const LZ4: u8 = 0x20;
+const ZSTD: u8 = 0x40;
+/// The header flag set when the chunk holds a [`Rollup`] in place of its events
+const ROLLUP: u8 = 0x80;
+
+/// How the events of a [`Chunk`] are encoded when it is sealed
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SealOptions {
+    /// Compress the gauge and histogram values of each label with Gorilla style XOR encoding,
+    /// values that repeat or change slowly will take a few bits instead of a full [`Float`]
+    pub xor_values: bool,
+    /// Compress the encoded chunk with a general purpose codec
+    pub compression: Compression,
+}
+
+impl Default for SealOptions {
+    fn default() -> Self {
+        Self {
+            xor_values: true,
+            compression: Compression::default(),
+        }
+    }
+}
+
+/// A general purpose compression codec applied to an encoded chunk, each codec is
+/// available behind a cargo feature of the same name
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Compression {
+    /// Only use the columnar encoding
+    #[default]
+    None,
+    /// Compress with lz4, this is very fast with a moderate compression ratio
+    #[cfg(feature = "lz4")]
+    Lz4,
+    /// Compress with zstd at the provided level, `0` uses zstd's default level. This is slower
+    /// than [`Compression::Lz4`] with a better compression ratio
+    #[cfg(feature = "zstd")]
+    Zstd(i32),
+}
+
+/// An error encountered while decoding a [`Chunk`] or a [`crate::capture`]
+#[derive(Debug, Clone, PartialEq)]
+pub enum DecodeError {
+    /// The input ended before the chunk was complete
+    UnexpectedEnd,
+    /// A varint was longer than the value it was decoded into
+    InvalidVarint,
+    /// The type and op of an event was not recognized
+    UnknownKind(u8),
+    /// A label id was larger than [`LabelId`] can hold, this happens when reading a capture
+    /// from a build with the `wide-labels` feature without it enabled
+    LabelOutOfRange(u64),
+    /// A `ms` value was outside of the range of a `u16`
+    MsOutOfRange(i64),
+    /// The `reference_time` was not a valid time
+    InvalidTime,
+    /// The gauge and histogram values were encoded with an unsupported width, this happens when
+    /// reading a capture from a build with the `f64-values` feature without it enabled
+    ValueWidth(u8),
+    /// There were bytes remaining after the chunk was decoded
+    TrailingBytes(usize),
+    /// The header contained flags that were not recognized
+    UnknownFlags(u8),
+    /// The chunk was compressed with a codec that isn't enabled in this build, the value is the
+    /// name of the cargo feature that would enable it
+    UnsupportedCompression(&'static str),
+    /// The compressed bytes could not be decompressed
+    Decompress(String),
+    /// The input did not start with [`crate::capture::MAGIC`]
+    NotACapture,
+    /// The capture was written with a newer version of the format than this build can read
+    UnsupportedVersion(u16),
+    /// The label identifiers were recorded with an unsupported width
+    LabelWidth(u8),
+    /// A string was not valid UTF-8
+    InvalidUtf8,
+    /// A key referred to a string that is not in the string table
+    StringOutOfRange(u64),
+    /// A description had a unit that was not recognized
+    UnknownUnit(String),
+    /// The input did not start with [`crate::wal::MAGIC`]
+    NotALog,
+    /// A record of a [`crate::wal::WriteAheadLog`] that was followed by more records failed its
+    /// checksum, the value is the offset of the record
+    CorruptRecord(usize),
+    /// A chunk of a capture failed its checksum, the value is the position of the chunk
+    ChunkChecksum(usize),
+    /// A rolled up chunk had buckets 0 milliseconds wide
+    ZeroStep,
+}
+
+impl Display for DecodeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::UnexpectedEnd => f.write_str("unexpected end of input"),
+            Self::InvalidVarint => f.write_str("invalid varint"),
+            Self::UnknownKind(kind) => write!(f, "unknown event kind: {kind}"),
+            Self::LabelOutOfRange(label) => write!(
+                f,
+                "label id {label} is out of range, the `wide-labels` feature may be required"
+            ),
+            Self::MsOutOfRange(ms) => write!(f, "ms value {ms} is out of range"),
+            Self::InvalidTime => f.write_str("invalid reference time"),
+            Self::ValueWidth(bytes) => write!(
+                f,
+                "unsupported value width of {bytes} bytes, the `f64-values` feature may be required"
+            ),
+            Self::TrailingBytes(count) => write!(f, "{count} unexpected trailing bytes"),
+            Self::UnknownFlags(flags) => write!(f, "unknown header flags: {flags:#04x}"),
+            Self::UnsupportedCompression(feature) => write!(
+                f,
+                "chunk is compressed, enable the `{feature}` feature to decompress it"
+            ),
+            Self::Decompress(e) => write!(f, "failed to decompress chunk: {e}"),
+            Self::NotACapture => f.write_str("input is not a capture"),
+            Self::UnsupportedVersion(version) => {
+                write!(f, "unsupported capture version: {version}")
+            }
+            Self::LabelWidth(bits) => write!(f, "unsupported label id width: {bits}"),
+            Self::InvalidUtf8 => f.write_str("invalid UTF-8 string"),
+            Self::StringOutOfRange(index) => write!(f, "string index {index} is out of range"),
+            Self::UnknownUnit(unit) => write!(f, "unknown unit `{unit}`"),
+            Self::NotALog => f.write_str("input is not a write-ahead log"),
+            Self::CorruptRecord(offset) => {
+                write!(f, "record at byte {offset} failed its checksum")
+            }
+            Self::ChunkChecksum(index) => write!(f, "chunk {index} failed its checksum"),
+            Self::ZeroStep => f.write_str("rolled up chunk has a step of 0 milliseconds"),
+        }
+    }
+}
+
+impl std::error::Error for DecodeError {}
+
+/// Encode the provided chunk with the default [`SealOptions`], appending the bytes to `out`
+pub fn encode(chunk: &Chunk, out: &mut Vec<u8>) {
+    encode_with(chunk, SealOptions::default(), out);
+}
+
+/// Encode the provided chunk with the provided [`SealOptions`], appending the bytes to `out`.
+/// The buckets of a rolled up chunk are encoded without any [`SealOptions`]
+pub fn encode_with(chunk: &Chunk, options: SealOptions, out: &mut Vec<u8>) {
+    if let Some(rollup) = chunk.rollup() {
+        out.push(FLOAT_BYTES | ROLLUP);
+        write_time(out, chunk.reference_time);
+        rollup.encode(out);
+        return;
+    }
+    encode_events(chunk.reference_time, &chunk.events(), options, out);
+}
+
+/// Encode a chunk's reference time and events, appending the bytes to `out`
+pub(crate) fn encode_events(
+    reference_time: OffsetDateTime,
+    events: &Events,
+    options: SealOptions,
+    out: &mut Vec<u8>,
+) {
+    out.push(FLOAT_BYTES | flags(options));
+    if options.compression == Compression::None {
+        encode_body(reference_time, events, options, out);
+        return;
+    }
+    let mut body = Vec::new();
+    encode_body(reference_time, events, options, &mut body);
+    let compressed = compress(options.compression, &body);
+    write_compressed(out, body.len(), &compressed);
+}
+
+/// The header bits recording the provided [`SealOptions`]
+pub(crate) fn flags(options: SealOptions) -> u8 {
+    let xor = if options.xor_values { XOR_VALUES } else { 0 };
+    xor | match options.compression {
+        Compression::None => 0,
+        #[cfg(feature = "lz4")]
+        Compression::Lz4 => LZ4,
+        #[cfg(feature = "zstd")]
+        Compression::Zstd(_) => ZSTD,
+    }
+}
+
+/// The [`SealOptions`] recorded by the provided header bits, a zstd level isn't recorded so
+/// it will be the default level
+pub(crate) fn options(flags: u8) -> Result<SealOptions, DecodeError> {
+    if flags & !(XOR_VALUES | COMPRESSION_MASK) != 0 {
+        return Err(DecodeError::UnknownFlags(flags));
+    }
+    let compression = match flags & COMPRESSION_MASK {
+        0 => Compression::None,
+        #[cfg(feature = "lz4")]
+        LZ4 => Compression::Lz4,
+        #[cfg(not(feature = "lz4"))]
+        LZ4 => return Err(DecodeError::UnsupportedCompression("lz4")),
+        #[cfg(feature = "zstd")]
+        ZSTD => Compression::Zstd(0),
+        #[cfg(not(feature = "zstd"))]
+        ZSTD => return Err(DecodeError::UnsupportedCompression("zstd")),
+        _ => return Err(DecodeError::UnknownFlags(flags)),
+    };
+    Ok(SealOptions {
+        xor_values: flags & XOR_VALUES != 0,
+        compression,
+    })
+}
+
+/// Compress the encoded bytes of a chunk with the provided codec
+fn compress(compression: Compression, body: &[u8]) -> Vec<u8> {
+    match compression {
+        Compression::None => body.to_vec(),
+        #[cfg(feature = "lz4")]
+        Compression::Lz4 => lz4_flex::block::compress(body),
+        #[cfg(feature = "zstd")]
+        Compression::Zstd(level) => zstd::bulk::compress(body, level)
+            .expect("compressing into a growable buffer does not fail"),
+    }
+}
+
+/// Append the length of the uncompressed bytes, the length of the compressed bytes and then
+/// the compressed bytes
+fn write_compressed(out: &mut Vec<u8>, len: usize, compressed: &[u8]) {
+    write_varint(out, len as u128);
+    write_varint(out, compressed.len() as u128);
+    out.extend_from_slice(compressed);
+}
+
+/// Encode everything after the header byte
+fn encode_body(
+    reference_time: OffsetDateTime,
+    events: &Events,
+    options: SealOptions,
+    out: &mut Vec<u8>,
+) {
+    write_time(out, reference_time);
+    write_varint(out, events.len() as u128);
+    write_varint(out, events.wide_values.len() as u128);
+    let mut last_ms = 0i64;
+    for event in events.iter() {
+        let ms = i64::from(event.ms);
+        write_varint(out, zigzag(i128::from(ms - last_ms)));
+        last_ms = ms;
+    }
+    for event in events.iter() {
+        write_varint(out, event.label as u128);
+    }
+    for pair in events.chunks(2) {
+        let low = kind(&pair[0].entry);
+        let high = pair.get(1).map(|e| kind(&e.entry)).unwrap_or_default();
+        out.push(low | (high << 4));
+    }
+    for event in events.iter() {
+        match event.entry {
+            RawEntry::Counter { value, .. } => write_varint(out, u128::from(value)),
+            RawEntry::WideCounter { index, .. } => write_varint(out, u128::from(index)),
+            _ => {}
+        }
+    }
+    let floats = events.iter().filter_map(|event| match event.entry {
+        RawEntry::Gauge { value, .. } | RawEntry::Histogram { value } => Some((event.label, value)),
+        _ => None,
+    });
+    if options.xor_values {
+        // each label's values are compressed on their own, in order of first appearance
+        let mut streams: Vec<Vec<Float>> = Vec::new();
+        let mut stream_index = HashMap::new();
+        for (label, value) in floats {
+            let index = *stream_index.entry(label).or_insert_with(|| {
+                streams.push(Vec::new());
+                streams.len() - 1
+            });
+            streams[index].push(value);
+        }
+        let mut bits = xor::BitWriter::default();
+        for stream in &streams {
+            xor::encode(stream, u32::from(FLOAT_BYTES) * 8, &mut bits);
+        }
+        let bytes = bits.finish();
+        write_varint(out, bytes.len() as u128);
+        out.extend_from_slice(&bytes);
+    } else {
+        for (_, value) in floats {
+            out.extend_from_slice(&value.to_le_bytes());
+        }
+    }
+    for value in &events.wide_values {
+        write_varint(out, u128::from(*value));
+    }
+}
+
+/// Decode a chunk that was encoded with [`encode`], `bytes` must contain exactly one chunk
+pub fn decode(bytes: &[u8]) -> Result<Chunk, DecodeError> {
+    if bytes.first().is_some_and(|header| header & ROLLUP != 0) {
+        return decode_rollup(bytes);
+    }
+    let (reference_time, events) = decode_events(bytes)?;
+    Ok(Chunk::from_events(reference_time, events))
+}
+
+/// Decode a rolled up chunk written by [`encode_with`]
+fn decode_rollup(bytes: &[u8]) -> Result<Chunk, DecodeError> {
+    let mut reader = Reader::new(bytes);
+    let header = reader.byte()?;
+    let float_bytes = header & FLOAT_BYTES_MASK;
+    if float_bytes != 4 && float_bytes != FLOAT_BYTES {
+        return Err(DecodeError::ValueWidth(float_bytes));
+    }
+    if header & !(FLOAT_BYTES_MASK | ROLLUP) != 0 {
+        return Err(DecodeError::UnknownFlags(header));
+    }
+    let reference_time = reader.time()?;
+    let rollup = Rollup::decode(&mut reader, float_bytes)?;
+    match reader.remaining() {
+        0 => Ok(Chunk::from_rollup(reference_time, rollup)),
+        count => Err(DecodeError::TrailingBytes(count)),
+    }
+}
+
+/// Decode a chunk's reference time and events, `bytes` must contain exactly one chunk
+pub(crate) fn decode_events(bytes: &[u8]) -> Result<(OffsetDateTime, Events), DecodeError> {
+    let mut reader = Reader::new(bytes);
+    let ret = decode_from(&mut reader)?;
+    match reader.remaining() {
+        0 => Ok(ret),
+        count => Err(DecodeError::TrailingBytes(count)),
+    }
+}
+
+/// Decode a single chunk's reference time and events from the front of the provided reader
+pub(crate) fn decode_from(
+    reader: &mut Reader<'_>,
+) -> Result<(OffsetDateTime, Events), DecodeError> {
+    let header = reader.byte()?;
+    let float_bytes = header & FLOAT_BYTES_MASK;
+    if float_bytes != 4 && float_bytes != FLOAT_BYTES {
+        return Err(DecodeError::ValueWidth(float_bytes));
+    }
+    if header & !(FLOAT_BYTES_MASK | XOR_VALUES | COMPRESSION_MASK) != 0 {
+        return Err(DecodeError::UnknownFlags(header));
+    }
+    let compression = header & COMPRESSION_MASK;
+    if compression == 0 {
+        return decode_body(header, reader);
+    }
+    let len = usize::try_from(reader.varint()?).map_err(|_| DecodeError::InvalidVarint)?;
+    let compressed_len = reader.length()?;
+    let compressed = reader.bytes(compressed_len)?;
+    // guard against allocating for a corrupted length
+    if len > compressed_len.saturating_mul(4096).max(4096) {
+        return Err(DecodeError::Decompress(format!(
+            "{len} bytes is too large for {compressed_len} compressed bytes"
+        )));
+    }
+    let body = decompress(compression, compressed, len)?;
+    let mut body_reader = Reader::new(&body);
+    let ret = decode_body(header, &mut body_reader)?;
+    match body_reader.remaining() {
+        0 => Ok(ret),
+        count => Err(DecodeError::TrailingBytes(count)),
+    }
+}
+
+/// Decompress the bytes of a chunk that were compressed into `compressed`, `len` is the
+/// expected length of the decompressed bytes
+#[allow(unused_variables)]
+fn decompress(compression: u8, compressed: &[u8], len: usize) -> Result<Vec<u8>, DecodeError> {
+    match compression {
+        #[cfg(feature = "lz4")]
+        LZ4 => lz4_flex::block::decompress(compressed, len)
+            .map_err(|e| DecodeError::Decompress(e.to_string())),
+        #[cfg(not(feature = "lz4"))]
+        LZ4 => Err(DecodeError::UnsupportedCompression("lz4")),
+        #[cfg(feature = "zstd")]
+        ZSTD => zstd::bulk::decompress(compressed, len)
+            .map_err(|e| DecodeError::Decompress(e.to_string())),
+        #[cfg(not(feature = "zstd"))]
+        ZSTD => Err(DecodeError::UnsupportedCompression("zstd")),
+        _ => Err(DecodeError::UnknownFlags(compression)),
+    }
+}
+
+/// Decode everything after the header byte
+fn decode_body(
+    header: u8,
+    reader: &mut Reader<'_>,
+) -> Result<(OffsetDateTime, Events), DecodeError> {
+    let float_bytes = header & FLOAT_BYTES_MASK;
+    let reference_time = reader.time()?;
+    let len = reader.length()?;
+    let wide_len = reader.length()?;
+    let mut ms = Vec::with_capacity(len);
+    let mut last_ms = 0i64;
+    for _ in 0..len {
+        let delta =
+            i64::try_from(unzigzag(reader.varint()?)).map_err(|_| DecodeError::InvalidVarint)?;
+        last_ms = last_ms.saturating_add(delta);
+        ms.push(u16::try_from(last_ms).map_err(|_| DecodeError::MsOutOfRange(last_ms))?);
+    }
+    let mut labels = Vec::with_capacity(len);
+    for _ in 0..len {
+        let label = reader.varint_u64()?;
+        labels.push(LabelId::try_from(label).map_err(|_| DecodeError::LabelOutOfRange(label))?);
+    }
+    let kinds = reader.bytes(len.div_ceil(2))?;
+    let kinds = (0..len).map(|i| (kinds[i / 2] >> ((i % 2) * 4)) & 0xF);
+    let mut entries = Vec::with_capacity(len);
+    for kind in kinds {
+        let entry = match kind {
+            GAUGE..COUNTER => RawEntry::Gauge {
+                value: 0.0,
+                op: op(kind - GAUGE),
+            },
+            COUNTER..HISTOGRAM => RawEntry::Counter {
+                value: 0,
+                op: op(kind - COUNTER),
+            },
+            HISTOGRAM => RawEntry::Histogram { value: 0.0 },
+            WIDE_COUNTER..KINDS => RawEntry::WideCounter {
+                index: 0,
+                op: op(kind - WIDE_COUNTER),
+            },
+            _ => return Err(DecodeError::UnknownKind(kind)),
+        };
+        entries.push(entry);
+    }
+    for entry in entries.iter_mut() {
+        match entry {
+            RawEntry::Counter { value, .. } | RawEntry::WideCounter { index: value, .. } => {
+                let v = reader.varint_u64()?;
+                *value = u32::try_from(v).map_err(|_| DecodeError::InvalidVarint)?;
+            }
+            _ => {}
+        }
+    }
+    let float_values =
+        entries
+            .iter_mut()
+            .zip(labels.iter())
+            .filter_map(|(entry, label)| match entry {
+                RawEntry::Gauge { value, .. } | RawEntry::Histogram { value } => {
+                    Some((label, value))
+                }
+                _ => None,
+            });
+    if header & XOR_VALUES != 0 {
+        // count the values of each label to know the length of each stream
+        let mut streams: Vec<(usize, Vec<Float>)> = Vec::new();
+        let mut stream_index = HashMap::new();
+        let float_values: Vec<_> = float_values
+            .map(|(label, value)| {
+                let index = *stream_index.entry(*label).or_insert_with(|| {
+                    streams.push((0, Vec::new()));
+                    streams.len() - 1
+                });
+                streams[index].0 += 1;
+                (index, value)
+            })
+            .collect();
+        let bits_len = reader.length()?;
+        let mut bits = xor::BitReader::new(reader.bytes(bits_len)?);
+        for (count, values) in streams.iter_mut() {
+            *values = xor::decode(*count, u32::from(float_bytes) * 8, &mut bits)?;
+            values.reverse();
+        }
+        for (index, value) in float_values {
+            *value = streams[index].1.pop().ok_or(DecodeError::UnexpectedEnd)?;
+        }
+    } else {
+        for (_, value) in float_values {
+            *value = reader.float(float_bytes)?;
+        }
+    }
+    let mut wide_values = Vec::with_capacity(wide_len);
+    for _ in 0..wide_len {
+        wide_values.push(reader.varint_u64()?);
+    }
+    let events = entries
+        .into_iter()
+        .zip(ms)
+        .zip(labels)
+        .map(|((entry, ms), label)| Event { entry, ms, label })
+        .collect();
+    Ok((
+        reference_time,
+        Events {
+            events,
+            wide_values,
+        },
+    ))
+}
+
+/// The 4 bit type and op for an entry
+fn kind(entry: &RawEntry) -> u8 {
+    match entry {
+        RawEntry::Gauge { op, .. } => GAUGE + op_bits(*op),
+        RawEntry::Counter { op, .. } => COUNTER + op_bits(*op),
+        RawEntry::Histogram { .. } => HISTOGRAM,
+        RawEntry::WideCounter { op, .. } => WIDE_COUNTER + op_bits(*op),
+    }
+}
+
+pub(crate) fn op_bits(op: Op) -> u8 {
+    match op {
+        Op::Add => 0,
+        Op::Sub => 1,
+        Op::Set => 2,
+    }
+}
+
+pub(crate) fn op(bits: u8) -> Op {
+    match bits {
+        0 => Op::Add,
+        1 => Op::Sub,
+        _ => Op::Set,
+    }
+}
+
+pub(crate) fn zigzag(value: i128) -> u128 {
+    ((value << 1) ^ (value >> 127)) as u128
+}
+
+pub(crate) fn unzigzag(value: u128) -> i128 {
+    ((value >> 1) as i128) ^ -((value & 1) as i128)
+}
+
+/// Append the LEB128 encoding of `value` to `out`
+pub(crate) fn write_varint(out: &mut Vec<u8>, mut value: u128) {
+    while value >= 0x80 {
+        out.push((value as u8) | 0x80);
+        value >>= 7;
+    }
+    out.push(value as u8);
+}
+
+/// The CRC-32 (IEEE) checksum of the provided bytes
+pub(crate) fn crc32(bytes: &[u8]) -> u32 {
+    const TABLE: [u32; 256] = {
+        let mut table = [0u32; 256];
+        let mut i = 0;
+        while i < 256 {
+            let mut crc = i as u32;
+            let mut bit = 0;
+            while bit < 8 {
+                crc = if crc & 1 == 1 {
+                    (crc >> 1) ^ 0xEDB8_8320
+                } else {
+                    crc >> 1
+                };
+                bit += 1;
+            }
+            table[i] = crc;
+            i += 1;
+        }
+        table
+    };
+    !bytes.iter().fold(!0u32, |crc, byte| {
+        TABLE[usize::from((crc as u8) ^ byte)] ^ (crc >> 8)
+    })
+}
+
+/// Append a time as the zigzag varint of its unix timestamp in nanoseconds followed by the
+/// zigzag varint of its offset in seconds
+pub(crate) fn write_time(out: &mut Vec<u8>, time: OffsetDateTime) {
+    write_varint(out, zigzag(time.unix_timestamp_nanos()));
+    write_varint(out, zigzag(i128::from(time.offset().whole_seconds())));
+}
+
+/// Append a string as its length in bytes followed by its UTF-8 bytes
+pub(crate) fn write_str(out: &mut Vec<u8>, s: &str) {
+    write_varint(out, s.len() as u128);
+    out.extend_from_slice(s.as_bytes());
+}
+
+/// A cursor over encoded bytes
+pub(crate) struct Reader<'a> {
+    bytes: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    pub(crate) fn new(bytes: &'a [u8]) -> Self {
+        Self { bytes }
+    }
+
+    /// The number of bytes that have not been read
+    pub(crate) fn remaining(&self) -> usize {
+        self.bytes.len()
+    }
+
+    /// The bytes that have not been read, without consuming them
+    pub(crate) fn rest(&self) -> &'a [u8] {
+        self.bytes
+    }
+
+    pub(crate) fn byte(&mut self) -> Result<u8, DecodeError> {
+        let (first, rest) = self.bytes.split_first().ok_or(DecodeError::UnexpectedEnd)?;
+        self.bytes = rest;
+        Ok(*first)
+    }
+
+    pub(crate) fn bytes(&mut self, len: usize) -> Result<&'a [u8], DecodeError> {
+        if self.bytes.len() < len {
+            return Err(DecodeError::UnexpectedEnd);
+        }
+        let (ret, rest) = self.bytes.split_at(len);
+        self.bytes = rest;
+        Ok(ret)
+    }
+
+    pub(crate) fn varint(&mut self) -> Result<u128, DecodeError> {
+        let mut value = 0u128;
+        for shift in (0..128).step_by(7) {
+            let byte = self.byte()?;
+            value |= u128::from(byte & 0x7F) << shift;
+            if byte & 0x80 == 0 {
+                return Ok(value);
+            }
+        }
+        Err(DecodeError::InvalidVarint)
+    }
+
+    pub(crate) fn varint_u64(&mut self) -> Result<u64, DecodeError> {
+        u64::try_from(self.varint()?).map_err(|_| DecodeError::InvalidVarint)
+    }
+
+    /// A varint used as the length of a collection, this is checked against the number of bytes
+    /// remaining to avoid allocating for a length that can't be correct
+    pub(crate) fn length(&mut self) -> Result<usize, DecodeError> {
+        let len = usize::try_from(self.varint()?).map_err(|_| DecodeError::InvalidVarint)?;
+        if len > self.bytes.len() {
+            return Err(DecodeError::UnexpectedEnd);
+        }
+        Ok(len)
+    }
+
+    /// A time written with [`write_time`]
+    pub(crate) fn time(&mut self) -> Result<OffsetDateTime, DecodeError> {
+        let nanos = unzigzag(self.varint()?);
+        let offset =
+            i32::try_from(unzigzag(self.varint()?)).map_err(|_| DecodeError::InvalidTime)?;
+        let offset = UtcOffset::from_whole_seconds(offset).map_err(|_| DecodeError::InvalidTime)?;
+        Ok(OffsetDateTime::from_unix_timestamp_nanos(nanos)
+            .map_err(|_| DecodeError::InvalidTime)?
+            .to_offset(offset))
+    }
+
+    /// A string written with [`write_str`]
+    pub(crate) fn str(&mut self) -> Result<&'a str, DecodeError> {
+        let len = self.length()?;
+        std::str::from_utf8(self.bytes(len)?).map_err(|_| DecodeError::InvalidUtf8)
+    }
+
+    /// A little endian `f64`
+    pub(crate) fn f64(&mut self) -> Result<f64, DecodeError> {
+        let bytes = self.bytes(8)?;
+        Ok(f64::from_le_bytes(
+            bytes.try_into().expect("exactly 8 bytes were read"),
+        ))
+    }
+
+    /// A little endian float that was encoded with `width` bytes
+    pub(crate) fn float(&mut self, width: u8) -> Result<Float, DecodeError> {
+        let bytes = self.bytes(usize::from(width))?;
+        // a wider value can only be read by a build with the same width, checked in the header
+        let value = match bytes.try_into() {
+            Ok(bytes) => Float::from_le_bytes(bytes),
+            Err(_) => Float::from(f32::from_le_bytes(
+                bytes
+                    .try_into()
+                    .map_err(|_| DecodeError::ValueWidth(width))?,
+            )),
+        };
+        Ok(value)
+    }
+}
+
+impl Chunk {
+    /// Encode this chunk with the columnar [`crate::codec`]
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut out = Vec::new();
+        encode(self, &mut out);
+        out
+    }
+
+    /// Decode a chunk encoded with the columnar [`crate::codec`]
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, DecodeError> {
+        decode(bytes)
+    }
+}
+
+/// Gorilla style XOR compression for a stream of [`Float`]s, as described in
+/// "Gorilla: A Fast, Scalable, In-Memory Time Series Database".
+///
+/// The first value is written as-is, each value after that is XORed with the one before it.
+/// A repeated value is a single `0` bit, otherwise a `1` bit is followed by either a `0` bit
+/// and the meaningful bits of the XOR if they fit in the previous value's window of leading
+/// and trailing zeros, or a `1` bit, 6 bits for the number of leading zeros, 6 bits for the
+/// number of meaningful bits and then the meaningful bits themselves
+pub(crate) mod xor {
+    use super::DecodeError;
+    use crate::event::Float;
+
+    /// Compress `values` that are `width` bits wide into `out`
+    pub(crate) fn encode(values: &[Float], width: u32, out: &mut BitWriter) {
+        let Some((first, rest)) = values.split_first() else {
+            return;
+        };
+        let mut prev = to_bits(*first);
+        out.write(prev, width);
+        // the leading and trailing zeros of the previous meaningful bits
+        let mut window: Option<(u32, u32)> = None;
+        for value in rest {
+            let bits = to_bits(*value);
+            let xor = bits ^ prev;
+            prev = bits;
+            if xor == 0 {
+                out.write(0, 1);
+                continue;
+            }
+            out.write(1, 1);
+            let leading = (xor.leading_zeros() - (64 - width)).min(63);
+            let trailing = xor.trailing_zeros();
+            match window {
+                Some((prev_leading, prev_trailing))
+                    if leading >= prev_leading && trailing >= prev_trailing =>
+                {
+                    out.write(0, 1);
+                    out.write(xor >> prev_trailing, width - prev_leading - prev_trailing);
+                }
+                _ => {
+                    let meaningful = width - leading - trailing;
+                    out.write(1, 1);
+                    out.write(u64::from(leading), 6);
+                    out.write(u64::from(meaningful - 1), 6);
+                    out.write(xor >> trailing, meaningful);
+                    window = Some((leading, trailing));
+                }
+            }
+        }
+    }
+
+    /// Decompress `count` values that are `width` bits wide from `bits`
+    pub(crate) fn decode(
+        count: usize,
+        width: u32,
+        bits: &mut BitReader<'_>,
+    ) -> Result<Vec<Float>, DecodeError> {
+        let mut values = Vec::with_capacity(count);
+        if count == 0 {
+            return Ok(values);
+        }
+        let mut prev = bits.read(width)?;
+        values.push(from_bits(prev, width));
+        let mut window = (0, 0);
+        for _ in 1..count {
+            if bits.read(1)? == 1 {
+                if bits.read(1)? == 1 {
+                    let leading = bits.read(6)? as u32;
+                    let meaningful = bits.read(6)? as u32 + 1;
+                    let trailing = width
+                        .checked_sub(leading + meaningful)
+                        .ok_or(DecodeError::InvalidVarint)?;
+                    window = (leading, trailing);
+                }
+                let (leading, trailing) = window;
+                prev ^= bits.read(width - leading - trailing)? << trailing;
+            }
+            values.push(from_bits(prev, width));
+        }
+        Ok(values)
+    }
+
+    #[cfg(not(feature = "f64-values"))]
+    fn to_bits(value: Float) -> u64 {
+        u64::from(value.to_bits())
+    }
+
+    #[cfg(feature = "f64-values")]
+    fn to_bits(value: Float) -> u64 {
+        value.to_bits()
+    }
+
+    /// Convert bits from a value that was `width` bits wide, only a build with `f64-values` will
+    /// decode a 64 bit value which is checked when reading the header
+    #[cfg(not(feature = "f64-values"))]
+    fn from_bits(bits: u64, _width: u32) -> Float {
+        Float::from_bits(bits as u32)
+    }
+
+    /// Convert bits from a value that was `width` bits wide, this may be a 32 bit value from a
+    /// build without `f64-values`
+    #[cfg(feature = "f64-values")]
+    fn from_bits(bits: u64, width: u32) -> Float {
+        if width == 32 {
+            Float::from(f32::from_bits(bits as u32))
+        } else {
+            Float::from_bits(bits)
+        }
+    }
+
+    /// Writes values into a stream of bits, most significant bit first
+    #[derive(Debug, Default)]
+    pub(crate) struct BitWriter {
+        bytes: Vec<u8>,
+        /// The number of bits used in the last byte, 0 when it is full
+        used: u32,
+    }
+
+    impl BitWriter {
+        /// Write the lowest `count` bits of `value`
+        pub(crate) fn write(&mut self, value: u64, count: u32) {
+            for i in (0..count).rev() {
+                if self.used == 0 {
+                    self.bytes.push(0);
+                }
+                let bit = ((value >> i) & 1) as u8;
+                *self.bytes.last_mut().unwrap() |= bit << (7 - self.used);
+                self.used = (self.used + 1) % 8;
+            }
+        }
+
+        /// The written bits, padded with zeros to a whole byte
+        pub(crate) fn finish(self) -> Vec<u8> {
+            self.bytes
+        }
+    }
+
+    /// Reads values from a stream of bits written by a [`BitWriter`]
+    #[derive(Debug)]
+    pub(crate) struct BitReader<'a> {
+        bytes: &'a [u8],
+        /// The index of the next bit to read
+        position: usize,
+    }
+
+    impl<'a> BitReader<'a> {
+        pub(crate) fn new(bytes: &'a [u8]) -> Self {
+            Self { bytes, position: 0 }
+        }
+
+        /// Read `count` bits into the lowest bits of the returned value
+        pub(crate) fn read(&mut self, count: u32) -> Result<u64, DecodeError> {
+            let mut value = 0;
+            for _ in 0..count {
+                let byte = self
+                    .bytes
+                    .get(self.position / 8)
+                    .ok_or(DecodeError::UnexpectedEnd)?;
+                let bit = (byte >> (7 - (self.position % 8))) & 1;
+                value = (value << 1) | u64::from(bit);
+                self.position += 1;
+            }
+            Ok(value)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{event::Entry, test_util::start};
+
+    use super::*;
+
+    fn chunk() -> Chunk {
+        let mut chunk = Chunk::new(start());
+        for i in 0..100u16 {
+            let entry = match i % 5 {
+                0 => Entry::Gauge {
+                    value: Float::from(i) * 1.5,
+                    op: op((i % 3) as u8),
+                },
+                1 => Entry::Counter {
+                    value: u64::from(i),
+                    op: op((i % 3) as u8),
+                },
+                2 => Entry::Counter {
+                    value: u64::MAX - u64::from(i),
+                    op: Op::Set,
+                },
+                3 => Entry::Histogram {
+                    value: -Float::from(i) / 7.0,
+                },
+                _ => Entry::Counter {
+                    value: 1,
+                    op: Op::Add,
+                },
+            };
+            chunk.push_entry(i * 600, LabelId::from(i % 7), entry);
+        }
+        // an out of order event
+        chunk.push_entry(12, 3, Entry::Histogram { value: 1.0 });
+        chunk
+    }
+
+    #[test]
+    fn round_trip() {
+        let chunk = chunk();
+        let bytes = chunk.to_bytes();
+        assert_eq!(Chunk::from_bytes(&bytes).unwrap(), chunk);
+        let empty = Chunk::new(
+            OffsetDateTime::UNIX_EPOCH.to_offset(UtcOffset::from_hms(-5, 0, 0).unwrap()),
+        );
+        let decoded = Chunk::from_bytes(&empty.to_bytes()).unwrap();
+        assert_eq!(decoded, empty);
+        assert_eq!(
+            decoded.reference_time.offset(),
+            empty.reference_time.offset()
+        );
+    }
+
+    #[test]
+    fn rolled_up_chunks() {
+        let chunk = chunk();
+        let rollup = Rollup::from_events(&chunk.events(), 1_000, &mut Default::default());
+        let rolled_up = Chunk::from_rollup(chunk.reference_time, rollup);
+        let bytes = rolled_up.to_bytes();
+        assert_eq!(bytes[0] & ROLLUP, ROLLUP);
+        assert_eq!(Chunk::from_bytes(&bytes).unwrap(), rolled_up);
+        // a rollup is only read as a whole chunk
+        assert_eq!(
+            decode_events(&bytes),
+            Err(DecodeError::UnknownFlags(bytes[0]))
+        );
+        let mut trailing = bytes.clone();
+        trailing.push(0);
+        assert_eq!(
+            Chunk::from_bytes(&trailing),
+            Err(DecodeError::TrailingBytes(1))
+        );
+    }
+
+    #[test]
+    fn smaller_than_postcard() {
+        let chunk = chunk();
+        let postcard = postcard::to_stdvec(&chunk).unwrap();
+        assert!(chunk.to_bytes().len() < postcard.len());
+    }
+
+    #[test]
+    fn xor_values() {
+        let mut values: Vec<Float> = vec![0.0, 0.0, 1.0, -1.0, Float::MAX, Float::MIN_POSITIVE];
+        values.extend((0..100).map(|i| 1000.0 + Float::from(i as u8) * 0.25));
+        values.extend([Float::INFINITY, Float::NEG_INFINITY, 1e-30, 12345.678]);
+        let width = u32::from(FLOAT_BYTES) * 8;
+        let mut bits = xor::BitWriter::default();
+        xor::encode(&values, width, &mut bits);
+        xor::encode(&values[..1], width, &mut bits);
+        let bytes = bits.finish();
+        let mut reader = xor::BitReader::new(&bytes);
+        assert_eq!(
+            xor::decode(values.len(), width, &mut reader).unwrap(),
+            values
+        );
+        assert_eq!(xor::decode(1, width, &mut reader).unwrap(), values[..1]);
+        let mut bits = xor::BitWriter::default();
+        xor::encode(&[Float::NAN, Float::NAN], width, &mut bits);
+        let bytes = bits.finish();
+        let decoded = xor::decode(2, width, &mut xor::BitReader::new(&bytes)).unwrap();
+        assert!(decoded.iter().all(|v| v.is_nan()));
+    }
+
+    #[test]
+    fn sealed_chunks() {
+        let mut chunk = chunk();
+        let open = chunk.clone();
+        for xor_values in [false, true] {
+            chunk.seal(SealOptions {
+                xor_values,
+                ..Default::default()
+            });
+            assert!(chunk.is_sealed());
+            assert_eq!(chunk, open);
+            assert_eq!(chunk.len(), open.len());
+            assert_eq!(chunk.last_ms(), open.last_ms());
+        }
+        // adding to a sealed chunk decodes it once and leaves it open
+        chunk.insert_entry(1, 1, Entry::Histogram { value: 2.0 });
+        assert!(!chunk.is_sealed());
+        assert_eq!(chunk.events()[1].entry, RawEntry::Histogram { value: 2.0 });
+        assert_eq!(chunk.len(), open.len() + 1);
+        chunk.seal(SealOptions::default());
+        assert!(chunk.is_sealed());
+        assert_eq!(chunk.len(), open.len() + 1);
+    }
+
+    #[test]
+    fn xor_shrinks_slow_gauges() {
+        let mut chunk = Chunk::new(OffsetDateTime::UNIX_EPOCH);
+        for i in 0..1000u16 {
+            let entry = Entry::Gauge {
+                value: Float::from(100 + i / 100),
+                op: Op::Set,
+            };
+            chunk.push_entry(i, LabelId::from(i % 4), entry);
+        }
+        let raw = chunk.memory_size();
+        chunk.seal(SealOptions {
+            xor_values: false,
+            ..Default::default()
+        });
+        let columnar = chunk.memory_size();
+        chunk.seal(SealOptions::default());
+        let xor = chunk.memory_size();
+        assert!(columnar < raw);
+        assert!(xor * 2 < columnar, "{xor} {columnar}");
+    }
+
+    #[test]
+    fn errors() {
+        let bytes = chunk().to_bytes();
+        for len in 0..bytes.len() {
+            assert_eq!(
+                Chunk::from_bytes(&bytes[..len]),
+                Err(DecodeError::UnexpectedEnd),
+                "{len}"
+            );
+        }
+        let mut trailing = bytes.clone();
+        trailing.push(0);
+        assert_eq!(
+            Chunk::from_bytes(&trailing),
+            Err(DecodeError::TrailingBytes(1))
+        );
+        let mut width = bytes.clone();
+        width[0] = 3;
+        assert_eq!(Chunk::from_bytes(&width), Err(DecodeError::ValueWidth(3)));
+        let mut flags = bytes.clone();
+        flags[0] |= 0x80;
+        assert_eq!(
+            Chunk::from_bytes(&flags),
+            Err(DecodeError::UnknownFlags(flags[0]))
+        );
+        #[cfg(not(feature = "lz4"))]
+        assert_eq!(
+            Chunk::from_bytes(&[FLOAT_BYTES | LZ4, 0, 0]),
+            Err(DecodeError::UnsupportedCompression("lz4"))
+        );
+    }
+}
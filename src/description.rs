@@ -0,0 +1,106 @@
+use std::collections::BTreeMap;
+
+use metrics::{KeyName, Unit};
+use serde::{Deserialize, Serialize, ser::SerializeMap};
+
+use crate::memory::{self, MemoryUsage};
+
+/// The unit and help text provided for a metric name through the `describe_*` family
+/// of macros in the metrics crate
+#[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
+pub struct Description {
+    /// The unit this metric is measured in, if one was provided
+    #[serde(with = "unit")]
+    pub unit: Option<Unit>,
+    /// The help text describing this metric
+    pub text: String,
+}
+
+/// A set of [`Description`]s keyed by the [`metrics::KeyName`] they were provided for, these
+/// apply to every [`metrics::Key`] with a matching name regardless of its labels
+#[derive(Debug, PartialEq, Clone, Default)]
+pub struct Descriptions(pub BTreeMap<KeyName, Description>);
+
+impl Descriptions {
+    /// A breakdown of the heap memory held by these descriptions, every name is counted as
+    /// if it were allocated although names provided by the metrics macros are usually static
+    pub fn memory_usage(&self) -> MemoryUsage {
+        let descriptions = memory::btree_size::<KeyName, Description>(self.0.len())
+            + self
+                .0
+                .iter()
+                .map(|(name, d)| name.as_str().len() + d.text.capacity())
+                .sum::<usize>();
+        MemoryUsage {
+            descriptions,
+            ..Default::default()
+        }
+    }
+
+    /// Get the description for the provided metric name
+    pub fn get(&self, name: &str) -> Option<&Description> {
+        self.0.get(name)
+    }
+
+    /// Insert or replace the description for the provided metric name
+    pub fn insert(&mut self, name: KeyName, description: Description) {
+        self.0.insert(name, description);
+    }
+}
+
+/// Serialized as a map of metric names to their [`Description`]
+impl Serialize for Descriptions {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: serde::Serializer,
+    {
+        let mut m = serializer.serialize_map(Some(self.0.len()))?;
+        for (name, description) in self.0.iter() {
+            m.serialize_entry(name.as_str(), description)?;
+        }
+        m.end()
+    }
+}
+
+impl<'de> Deserialize<'de> for Descriptions {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        let map = BTreeMap::<String, Description>::deserialize(deserializer)?;
+        Ok(Self(
+            map.into_iter()
+                .map(|(name, description)| (KeyName::from(name), description))
+                .collect(),
+        ))
+    }
+}
+
+/// Helper for (de)serializing a [`metrics::Unit`] using the value returned by
+/// [`metrics::Unit::as_str`]
+mod unit {
+    use metrics::Unit;
+    use serde::{Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S>(unit: &Option<Unit>, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        match unit {
+            Some(unit) => serializer.serialize_some(unit.as_str()),
+            None => serializer.serialize_none(),
+        }
+    }
+
+    pub fn deserialize<'de, D>(deserializer: D) -> Result<Option<Unit>, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        let Some(s) = Option::<String>::deserialize(deserializer)? else {
+            return Ok(None);
+        };
+        Unit::from_string(&s)
+            .map(Some)
+            .ok_or_else(|| serde::de::Error::custom(format!("unknown unit `{s}`")))
+    }
+}
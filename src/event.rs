@@ -1,25 +1,51 @@
 use serde::{Deserialize, Serialize};
 
+use crate::label_set::LabelId;
+
+/// The type used to store gauge and histogram values, this is an `f32` unless the
+/// `f64-values` feature is enabled which makes it an `f64`
+#[cfg(not(feature = "f64-values"))]
+pub type Float = f32;
+/// The type used to store gauge and histogram values, this is an `f32` unless the
+/// `f64-values` feature is enabled which makes it an `f64`
+#[cfg(feature = "f64-values")]
+pub type Float = f64;
+
 /// A single metrics event represented in the most compact form
 #[derive(Debug, PartialEq, Clone, Copy, Serialize, Deserialize)]
 pub struct Event {
     /// The type and value of this event
-    pub entry: Entry,
+    pub entry: RawEntry,
     /// The number of milliseconds since the owning [`crate::chunk::Chunk`]'s `reference_time`
     pub ms: u16,
     /// The label identifier from the owning [`crate::Procession`]'s [`crate::label_set::LabelSet`]
-    pub label: u16,
+    pub label: LabelId,
 }
 
-/// A raw metrics event representing the type and value of what was emitted by the instrumentation
+/// A metrics event representing the type and value of what was emitted by the instrumentation
 #[derive(Debug, PartialEq, Clone, Copy, Serialize, Deserialize)]
 pub enum Entry {
     /// A gauge event
-    Gauge { value: f32, op: Op },
+    Gauge { value: Float, op: Op },
+    /// A counter event
+    Counter { value: u64, op: Op },
+    /// A histogram event
+    Histogram { value: Float },
+}
+
+/// A raw metrics event, the compact form of an [`Entry`] stored in an [`Event`]. Any value that
+/// doesn't fit in this form is stored next to the events in the owning [`crate::chunk::Chunk`]
+#[derive(Debug, PartialEq, Clone, Copy, Serialize, Deserialize)]
+pub enum RawEntry {
+    /// A gauge event
+    Gauge { value: Float, op: Op },
     /// A counter event
     Counter { value: u32, op: Op },
     /// A histogram event
-    Histogram { value: f32 },
+    Histogram { value: Float },
+    /// A counter event with a value too large for a `u32`, `index` is the position of the
+    /// value in the owning [`crate::chunk::Chunk`]'s `wide_values`
+    WideCounter { index: u32, op: Op },
 }
 
 /// An [`Entry`]'s operation, used for handling the `increment` and `set` methods
@@ -30,3 +56,87 @@ pub enum Op {
     Sub,
     Set,
 }
+
+/// Records the width of [`Float`] in a serialized [`crate::procession::Procession`], captures
+/// without this value were recorded with 32 bit values.
+///
+/// A 64 bit build can read a 32 bit capture from a self-describing format like JSON while a
+/// 32 bit build will refuse to read a 64 bit capture with a message pointing to the
+/// `f64-values` feature
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub(crate) struct ValueBits;
+
+/// The number of bits in a [`Float`]
+const FLOAT_BITS: u32 = (std::mem::size_of::<Float>() * 8) as u32;
+
+impl Serialize for ValueBits {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: serde::Serializer,
+    {
+        serializer.serialize_u32(FLOAT_BITS)
+    }
+}
+
+impl<'de> Deserialize<'de> for ValueBits {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        // only a self-describing format can read floats of a different width
+        let human_readable = deserializer.is_human_readable();
+        let bits = u32::deserialize(deserializer)?;
+        match bits {
+            32 | 64 if bits == FLOAT_BITS => Ok(Self),
+            32 if human_readable => Ok(Self),
+            32 => Err(serde::de::Error::custom(
+                "capture was recorded with 32 bit values, disable the `f64-values` feature to read it",
+            )),
+            64 => Err(serde::de::Error::custom(
+                "capture was recorded with 64 bit values, enable the `f64-values` feature to read it",
+            )),
+            _ => Err(serde::de::Error::custom(format!(
+                "unsupported value width: {bits}"
+            ))),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::procession::Procession;
+
+    use super::*;
+
+    #[test]
+    fn value_width_is_recorded() {
+        let json = serde_json::to_value(Procession::default()).unwrap();
+        assert_eq!(json["value_bits"], FLOAT_BITS);
+        let narrow = r#"{"value_bits":32,"chunks":[],"labels":[]}"#;
+        assert!(serde_json::from_str::<Procession>(narrow).is_ok());
+        let wide = r#"{"value_bits":64,"chunks":[],"labels":[]}"#;
+        let wide = serde_json::from_str::<Procession>(wide);
+        #[cfg(feature = "f64-values")]
+        assert!(wide.is_ok());
+        #[cfg(not(feature = "f64-values"))]
+        assert!(wide.unwrap_err().to_string().contains("f64-values"));
+    }
+
+    #[test]
+    fn values_keep_their_precision() {
+        let mut procession = Procession::default();
+        // the first integer an f32 can't represent
+        let value = 16_777_217.0;
+        procession.insert_entry(Entry::Gauge { value, op: Op::Set }, 0);
+        let bytes = postcard::to_stdvec(&procession).unwrap();
+        let procession: Procession = postcard::from_bytes(&bytes).unwrap();
+        let Some(Entry::Gauge { value: stored, .. }) = procession.iter().next().map(|m| m.event)
+        else {
+            panic!("expected a gauge");
+        };
+        #[cfg(feature = "f64-values")]
+        assert_eq!(stored, 16_777_217.0);
+        #[cfg(not(feature = "f64-values"))]
+        assert_eq!(stored, 16_777_216.0);
+    }
+}
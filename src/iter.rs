@@ -1,33 +1,171 @@
 //! This module is responsible for creating iterators from a [`crate::Procession`]
-use std::sync::OnceLock;
+use std::{
+    borrow::Cow,
+    collections::BTreeSet,
+    ops::{Bound, RangeBounds},
+    sync::OnceLock,
+};
 
 use metrics::Key;
 use serde::{
     Deserialize, Serialize,
-    ser::{SerializeMap, SerializeSeq},
+    ser::{SerializeMap, SerializeSeq, SerializeStruct},
 };
 use time::{Duration, OffsetDateTime};
 
 /// Only used in cases of an emergency, when a [`metrics::Key`] can somehow be lost when
 /// attempting to create a [`Metric`]
-static EMPTY_KEY: OnceLock<Key> = OnceLock::new();
+pub(crate) static EMPTY_KEY: OnceLock<Key> = OnceLock::new();
 
 use crate::{
-    chunk::Chunk,
-    event::{Entry, Event},
+    chunk::{Chunk, ChunkIndex, Events},
+    description::Description,
+    event::Entry,
+    label_set::{LabelId, LabelSet},
     procession::Procession,
+    rollup::Aggregate,
 };
 
+/// A set of conditions used to restrict the events produced by a [`MetricsRefIterator`],
+/// sealed chunks that cannot match are skipped using their [`crate::chunk::ChunkIndex`]
+/// without being decoded
+#[derive(Debug, Clone)]
+pub struct Filter {
+    labels: Option<BTreeSet<LabelId>>,
+    start: Bound<OffsetDateTime>,
+    end: Bound<OffsetDateTime>,
+}
+
+impl Default for Filter {
+    fn default() -> Self {
+        Self {
+            labels: None,
+            start: Bound::Unbounded,
+            end: Bound::Unbounded,
+        }
+    }
+}
+
+impl Filter {
+    /// A filter that matches every event
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Only match events for the provided label identifiers
+    pub fn labels(mut self, labels: impl IntoIterator<Item = LabelId>) -> Self {
+        self.labels = Some(labels.into_iter().collect());
+        self
+    }
+
+    /// Only match events for the keys in `labels` that satisfy the `predicate`
+    pub fn keys(self, labels: &LabelSet, mut predicate: impl FnMut(&Key) -> bool) -> Self {
+        self.labels(
+            labels
+                .iter()
+                .filter_map(|(key, id)| predicate(key).then_some(id)),
+        )
+    }
+
+    /// Only match events that occurred within the provided range
+    pub fn range(mut self, range: impl RangeBounds<OffsetDateTime>) -> Self {
+        self.start = range.start_bound().cloned();
+        self.end = range.end_bound().cloned();
+        self
+    }
+
+    /// If any event in the provided chunk could match this filter
+    pub fn may_match(&self, chunk: &Chunk) -> bool {
+        let Some((first, last)) = chunk.time_range() else {
+            return false;
+        };
+        self.overlaps(first, last)
+            && self
+                .labels
+                .as_ref()
+                .is_none_or(|labels| labels.iter().any(|label| chunk.contains_label(*label)))
+    }
+
+    /// If any event summarized by the provided [`ChunkIndex`] of a chunk starting at
+    /// `reference_time` could match this filter, the chunk itself doesn't need to be decoded
+    pub fn may_match_index(&self, reference_time: OffsetDateTime, index: &ChunkIndex) -> bool {
+        let at = |ms: u16| reference_time + Duration::milliseconds(i64::from(ms));
+        !index.is_empty()
+            && self.overlaps(at(index.first_ms), at(index.last_ms))
+            && self
+                .labels
+                .as_ref()
+                .is_none_or(|labels| labels.iter().any(|label| index.contains(*label)))
+    }
+
+    /// If any time from `first` through `last` is within the range of this filter
+    fn overlaps(&self, first: OffsetDateTime, last: OffsetDateTime) -> bool {
+        let after_start = match self.start {
+            Bound::Included(start) => last >= start,
+            Bound::Excluded(start) => last > start,
+            Bound::Unbounded => true,
+        };
+        let before_end = match self.end {
+            Bound::Included(end) => first <= end,
+            Bound::Excluded(end) => first < end,
+            Bound::Unbounded => true,
+        };
+        after_start && before_end
+    }
+
+    /// If an event for `label` that occurred at `when` matches this filter
+    pub fn matches(&self, label: LabelId, when: OffsetDateTime) -> bool {
+        (self.start, self.end).contains(&when)
+            && self
+                .labels
+                .as_ref()
+                .is_none_or(|labels| labels.contains(&label))
+    }
+}
+
 /// A single event cloned out of the [Procession], this representation will
 /// allocation the strings needed to represent the value w/o holding a reference
 /// the time [Procession] itself. This type can be serialized and deserialized
 /// and represents and "owned" version of the [MetricRef] type.
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, Deserialize)]
 pub struct Metric {
     pub when: OffsetDateTime,
     pub event: Entry,
     pub key: String,
     pub labels: Vec<(String, String)>,
+    /// The unit and help text provided for this metric's name
+    #[serde(default)]
+    pub description: Option<Description>,
+    /// The combined events this metric stands in for when it came from a rolled up chunk
+    #[serde(default)]
+    pub rollup: Option<Aggregate>,
+}
+
+impl Serialize for Metric {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: serde::Serializer,
+    {
+        // self-describing formats leave out the missing fields the same as a `MetricRef`, the
+        // others (like postcard) need every field in place to read the metric back
+        let skip_none = serializer.is_human_readable();
+        let mut s = serializer.serialize_struct("Metric", 6)?;
+        s.serialize_field("when", &self.when)?;
+        s.serialize_field("event", &self.event)?;
+        s.serialize_field("key", &self.key)?;
+        s.serialize_field("labels", &self.labels)?;
+        if skip_none && self.description.is_none() {
+            s.skip_field("description")?;
+        } else {
+            s.serialize_field("description", &self.description)?;
+        }
+        if skip_none && self.rollup.is_none() {
+            s.skip_field("rollup")?;
+        } else {
+            s.serialize_field("rollup", &self.rollup)?;
+        }
+        s.end()
+    }
 }
 
 /// A single event borrowed from the [Procession], this representation
@@ -42,6 +180,11 @@ pub struct MetricRef<'a> {
     pub event: Entry,
     /// The key and labels provided by the metrics crate
     pub key: &'a Key,
+    /// The unit and help text provided for this metric's name
+    pub description: Option<&'a Description>,
+    /// The combined events this metric stands in for when it came from a rolled up
+    /// [`Chunk`], `event` is then the [`Aggregate::entry`] of this value
+    pub rollup: Option<&'a Aggregate>,
 }
 
 impl Serialize for MetricRef<'_> {
@@ -49,11 +192,18 @@ impl Serialize for MetricRef<'_> {
     where
         S: serde::Serializer,
     {
-        let mut m = serializer.serialize_map(Some(4))?;
+        let len = 4 + usize::from(self.description.is_some()) + usize::from(self.rollup.is_some());
+        let mut m = serializer.serialize_map(Some(len))?;
         m.serialize_entry("when", &self.when)?;
         m.serialize_entry("event", &self.event)?;
         m.serialize_entry("key", &self.key.name())?;
         m.serialize_entry("labels", &LabelsSet(self.key))?;
+        if let Some(description) = self.description {
+            m.serialize_entry("description", description)?;
+        }
+        if let Some(rollup) = self.rollup {
+            m.serialize_entry("rollup", rollup)?;
+        }
         m.end()
     }
 }
@@ -93,6 +243,8 @@ impl<'a> From<&'a Procession> for MetricsRefIterator<'a> {
             stream: value,
             chunk_index: 0,
             event_index: 0,
+            events: None,
+            filter: None,
         }
     }
 }
@@ -105,7 +257,13 @@ impl<'a> From<&'a Procession> for MetricsIterator<'a> {
 impl Iterator for MetricsIterator<'_> {
     type Item = Metric;
     fn next(&mut self) -> Option<Self::Item> {
-        let MetricRef { when, event, key } = self.0.next()?;
+        let MetricRef {
+            when,
+            event,
+            key,
+            description,
+            rollup,
+        } = self.0.next()?;
         Some(Metric {
             when,
             event,
@@ -114,6 +272,8 @@ impl Iterator for MetricsIterator<'_> {
                 .labels()
                 .map(|l| (l.key().to_string(), l.value().to_string()))
                 .collect(),
+            description: description.cloned(),
+            rollup: rollup.cloned(),
         })
     }
 }
@@ -125,58 +285,102 @@ pub struct MetricsRefIterator<'a> {
     stream: &'a Procession,
     chunk_index: usize,
     event_index: usize,
+    /// The events of the current chunk, sealed chunks are decoded once when they are reached
+    events: Option<Cow<'a, Events>>,
+    /// Only events matching this filter will be produced
+    filter: Option<Filter>,
 }
 
 impl<'a> Iterator for MetricsRefIterator<'a> {
     type Item = MetricRef<'a>;
 
     fn next(&mut self) -> Option<Self::Item> {
-        let (event, chunk) = self.get_next_event()?;
-        let when = chunk.reference_time + Duration::milliseconds(event.ms as i64);
-        let Some(key) = self.stream.labels.0.iter().find_map(|(k, v)| {
-            if *v == event.label {
-                return Some(k);
-            }
-            None
-        }) else {
+        let (label, entry, when, rollup) = self.get_next_event()?;
+        let Some(key) = self.stream.labels.key(label) else {
             return Some(MetricRef {
                 when,
-                event: event.entry,
+                event: entry,
                 key: EMPTY_KEY.get_or_init(|| Key::from_name("")),
+                description: None,
+                rollup,
             });
         };
         Some(MetricRef {
             when,
-            event: event.entry,
+            event: entry,
             key,
+            description: self.stream.descriptions.get(key.name()),
+            rollup,
         })
     }
 }
 
 impl<'a> MetricsRefIterator<'a> {
+    /// Only produce the events that match the provided [`Filter`]
+    pub fn with_filter(mut self, filter: Filter) -> Self {
+        self.filter = Some(filter);
+        self
+    }
+
     /// This method will do the majority of the work needed by the `next` implementation
-    /// above. The returned [`Event`] represents the correct value that should come
-    /// next in the series but since it only contains the millisecond count since its owning
-    /// [`Chunk`]'s `reference_time` we will also return the correct [`Chunk`]
+    /// above. The returned label and resolved [`Entry`] represent the correct value that
+    /// should come next in the series along with the time it occurred, calculated from the
+    /// owning [`Chunk`]'s `reference_time`. A rolled up chunk produces one value for each of
+    /// its buckets along with the bucket's [`Aggregate`]
     ///
     /// This method will also handle the index management for the calculation of the next event
     /// we should emit. If the current chunk is exhausted, it will reset the `event_index` and
-    /// increment the `chunk_index`, otherwise it will increment the `event_index` only
-    fn get_next_event<'s, 'r>(&'s mut self) -> Option<(&'r Event, &'r Chunk)>
-    where
-        'a: 'r,
-    {
-        let mut chunk = self.stream.chunks.get(self.chunk_index)?;
-        if let Some(event) = chunk.events.get(self.event_index) {
-            self.event_index += 1;
-            return Some((event, chunk));
+    /// increment the `chunk_index` until a chunk with events is found, otherwise it will
+    /// increment the `event_index` only. Chunks that cannot match the filter are skipped
+    /// before they are decoded
+    fn get_next_event(
+        &mut self,
+    ) -> Option<(LabelId, Entry, OffsetDateTime, Option<&'a Aggregate>)> {
+        loop {
+            let chunk = self.stream.chunks.get(self.chunk_index)?;
+            if self.event_index == 0
+                && self.events.is_none()
+                && let Some(filter) = &self.filter
+                && !filter.may_match(chunk)
+            {
+                self.chunk_index += 1;
+                continue;
+            }
+            if let Some(rollup) = chunk.rollup() {
+                if let Some(bucket) = rollup.buckets.get(self.event_index) {
+                    self.event_index += 1;
+                    let when = chunk.reference_time + Duration::milliseconds(bucket.ms as i64);
+                    if let Some(filter) = &self.filter
+                        && !filter.matches(bucket.label, when)
+                    {
+                        continue;
+                    }
+                    let aggregate = &bucket.aggregate;
+                    return Some((bucket.label, aggregate.entry(), when, Some(aggregate)));
+                }
+                self.chunk_index += 1;
+                self.event_index = 0;
+                continue;
+            }
+            let events = self.events.get_or_insert_with(|| chunk.events());
+            if let Some(event) = events.get(self.event_index) {
+                self.event_index += 1;
+                let when = chunk.reference_time + Duration::milliseconds(event.ms as i64);
+                if let Some(filter) = &self.filter
+                    && !filter.matches(event.label, when)
+                {
+                    continue;
+                }
+                // a wide counter without a value is skipped, `Procession::validate` reports it
+                let Some(entry) = events.entry(event) else {
+                    continue;
+                };
+                return Some((event.label, entry, when, None));
+            }
+            self.chunk_index += 1;
+            self.event_index = 0;
+            self.events = None;
         }
-        self.chunk_index += 1;
-        self.event_index = 0;
-        chunk = self.stream.chunks.get(self.chunk_index)?;
-        let ret = chunk.events.get(self.event_index)?;
-        self.event_index += 1;
-        Some((ret, chunk))
     }
 }
 
@@ -185,6 +389,8 @@ impl PartialEq<MetricRef<'_>> for Metric {
         self.when.eq(&other.when)
             && self.event.eq(&other.event)
             && self.key.eq(other.key.name())
+            && self.description.as_ref() == other.description
+            && self.rollup.as_ref() == other.rollup
             && self.labels.len() == other.key.labels().len()
             && self
                 .labels
@@ -202,9 +408,12 @@ impl PartialEq<Metric> for MetricRef<'_> {
 #[cfg(test)]
 mod tests {
     use metrics::{Key, Label};
-    use time::{Date, Time};
 
-    use crate::{event::Op, label_set::LabelSet};
+    use crate::{
+        event::{Event, Op, RawEntry},
+        label_set::LabelSet,
+        test_util::start,
+    };
 
     use super::*;
 
@@ -216,6 +425,14 @@ mod tests {
         insta::assert_json_snapshot!(flattened);
     }
 
+    #[test]
+    fn iter_small_stream() {
+        let time_stream = build_sized_stream(3, 10);
+        let iter = MetricsIterator::from(&time_stream);
+        let flattened: Vec<Metric> = iter.collect();
+        insta::assert_json_snapshot!(flattened);
+    }
+
     #[test]
     fn iter_ref_and_iter_match() {
         let time_stream = build_test_stream();
@@ -226,17 +443,81 @@ mod tests {
         }
     }
 
-    fn build_test_stream() -> Procession {
-        let start = OffsetDateTime::new_utc(
-            Date::from_calendar_date(2025, time::Month::January, 1).unwrap(),
-            Time::from_hms(0, 0, 0).unwrap(),
+    #[test]
+    fn metric_serializes_like_metric_ref() {
+        let time_stream = build_sized_stream(1, 5);
+        for (owned, metric) in time_stream.iter_owned().zip(time_stream.iter()) {
+            let json = serde_json::to_value(&owned).unwrap();
+            assert_eq!(json, serde_json::to_value(&metric).unwrap());
+            assert!(json.get("description").is_none() && json.get("rollup").is_none());
+            let bytes = postcard::to_stdvec(&owned).unwrap();
+            assert_eq!(postcard::from_bytes::<Metric>(&bytes).unwrap(), metric);
+        }
+    }
+
+    #[test]
+    fn filtered_iter_matches_filtered_events() {
+        let mut time_stream = build_test_stream();
+        let start = time_stream.chunks[10].reference_time;
+        let end = time_stream.chunks[20].reference_time;
+        let (key, label) = time_stream.labels.iter().nth(1).unwrap();
+        let key = key.clone();
+        let filter = Filter::new().labels([label]).range(start..end);
+        let expected: Vec<Metric> = MetricsIterator::from(MetricsRefIterator::from(&time_stream))
+            .zip(time_stream.iter())
+            .filter(|(_, r)| r.when >= start && r.when < end && r.key == &key)
+            .map(|(m, _)| m)
+            .collect();
+        assert!(!expected.is_empty());
+        let filtered: Vec<_> = time_stream.iter_filtered(filter.clone()).collect();
+        assert_eq!(expected.len(), filtered.len());
+        for (l, r) in expected.iter().zip(filtered) {
+            assert_eq!(l, &r);
+        }
+        time_stream.seal(Default::default());
+        assert!(time_stream.chunks[0].is_sealed());
+        let sealed: Vec<_> = time_stream.iter_filtered(filter).collect();
+        assert_eq!(expected.len(), sealed.len());
+        for (l, r) in expected.iter().zip(sealed) {
+            assert_eq!(l, &r);
+        }
+    }
+
+    #[test]
+    fn filter_skips_chunks() {
+        let time_stream = build_test_stream();
+        let chunk = &time_stream.chunks[1];
+        let (first, last) = chunk.time_range().unwrap();
+        assert_eq!(first, chunk.reference_time);
+        assert_eq!(last, chunk.reference_time + Duration::milliseconds(127));
+        assert!(Filter::new().may_match(chunk));
+        assert!(Filter::new().range(..=first).may_match(chunk));
+        assert!(!Filter::new().range(..first).may_match(chunk));
+        assert!(
+            !Filter::new()
+                .range(last + Duration::milliseconds(1)..)
+                .may_match(chunk)
         );
-        let mut labels = LabelSet([].into_iter().collect());
+        assert!(Filter::new().labels([0]).may_match(chunk));
+        assert!(!Filter::new().labels([LabelId::MAX]).may_match(chunk));
+        let empty = Chunk::new(chunk.reference_time);
+        assert!(!Filter::new().may_match(&empty));
+    }
+
+    fn build_test_stream() -> Procession {
+        build_sized_stream(128, 128)
+    }
+
+    /// A stream of `chunks` chunks a minute apart, each with `events` counter events spread
+    /// over 5 keys
+    fn build_sized_stream(chunks: i64, events: usize) -> Procession {
+        let start = start();
+        let mut labels = LabelSet::default();
         let k1 = Key::from_name("no-labels");
         let mut raw_labels = Vec::new();
-        raw_labels.push(labels.ensure_key(&k1));
+        raw_labels.push(labels.ensure_key(&k1).unwrap());
         let k2 = Key::from_parts("one-label", vec![Label::new("label", "value")]);
-        raw_labels.push(labels.ensure_key(&k2));
+        raw_labels.push(labels.ensure_key(&k2).unwrap());
         let k3 = Key::from_parts(
             "two-labels",
             vec![
@@ -244,7 +525,7 @@ mod tests {
                 Label::new("3label2", "value2"),
             ],
         );
-        raw_labels.push(labels.ensure_key(&k3));
+        raw_labels.push(labels.ensure_key(&k3).unwrap());
         let k4 = Key::from_parts(
             "three-labels",
             vec![
@@ -253,7 +534,7 @@ mod tests {
                 Label::new("4label3", "value3"),
             ],
         );
-        raw_labels.push(labels.ensure_key(&k4));
+        raw_labels.push(labels.ensure_key(&k4).unwrap());
         let k5 = Key::from_parts(
             "three-labels",
             vec![
@@ -263,14 +544,14 @@ mod tests {
                 Label::new("5label4", "value4"),
             ],
         );
-        raw_labels.push(labels.ensure_key(&k5));
+        raw_labels.push(labels.ensure_key(&k5).unwrap());
 
-        let streams = (0..128)
+        let streams = (0..chunks)
             .map(|v| {
                 let reference_time = start + Duration::minutes(v);
-                let events = (0..128)
+                let events = (0..events)
                     .map(|v| Event {
-                        entry: Entry::Counter {
+                        entry: RawEntry::Counter {
                             value: 1,
                             op: Op::Add,
                         },
@@ -278,15 +559,19 @@ mod tests {
                         label: raw_labels[v % 5],
                     })
                     .collect();
-                Chunk {
+                Chunk::from_events(
                     reference_time,
-                    events,
-                }
+                    Events {
+                        events,
+                        wide_values: Vec::new(),
+                    },
+                )
             })
             .collect();
         Procession {
             labels,
             chunks: streams,
+            ..Default::default()
         }
     }
 }
@@ -1,54 +1,364 @@
 // this module uses a hashmap with the `Key` type which shouldn't really
 // change during its lifetime
 #![allow(clippy::mutable_key_type)]
-use std::collections::BTreeMap;
+use std::{
+    alloc::Layout,
+    borrow::Cow,
+    collections::{BTreeMap, BTreeSet},
+    sync::Arc,
+};
 
-use metrics::Key;
-use serde::{Deserialize, Serialize, de::Visitor, ser::SerializeSeq};
+use metrics::{Key, Label, SharedString};
+use serde::{Deserialize, Serialize, de::Visitor};
+
+use crate::memory::{self, MemoryUsage};
+
+/// The identifier assigned to each unique [`metrics::Key`], this is a `u16` unless the
+/// `wide-labels` feature is enabled which makes it a `u32`
+#[cfg(not(feature = "wide-labels"))]
+pub type LabelId = u16;
+/// The identifier assigned to each unique [`metrics::Key`], this is a `u16` unless the
+/// `wide-labels` feature is enabled which makes it a `u32`
+#[cfg(feature = "wide-labels")]
+pub type LabelId = u32;
 
 /// A set of labels mapping from the original [`metrics::Key`] to a unique identifier
 /// and will be used to lookup what identifier to use when recording metrics events
-#[derive(Debug, PartialEq, Clone, Default)]
-pub struct LabelSet(pub BTreeMap<Key, u16>);
+#[derive(Debug, Clone)]
+pub struct LabelSet {
+    ids: BTreeMap<Key, LabelId>,
+    /// The reverse of `ids`, the key for each identifier is at the index of that identifier
+    keys: Vec<Option<Key>>,
+    /// The key holding `max_id`, kept out of `keys` so an overflow key doesn't size it to every
+    /// possible identifier
+    last: Option<Key>,
+    /// What to do once every identifier has been used
+    overflow_policy: OverflowPolicy,
+    /// The number of times a key was not given its own identifier
+    rejected: u64,
+    /// The largest identifier that can be assigned
+    max_id: LabelId,
+    /// One past the largest identifier in the set, the next one [`LabelSet::ensure_key`] will
+    /// assign. Identifiers from a deserialized or collected set aren't always dense
+    next_id: usize,
+    /// Every distinct name, label key and label value used by the keys in this set, each
+    /// key shares these strings instead of holding its own copy
+    strings: BTreeSet<Arc<str>>,
+}
+
+/// What to do when a new [`metrics::Key`] is registered after every identifier has
+/// been assigned
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum OverflowPolicy {
+    /// Reject the new key, the [`crate::recorder::ProcessionRecorder`] will hand out a handle
+    /// that discards every event while counting them
+    #[default]
+    Drop,
+    /// Record events for any new keys against a single shared key, see [`overflow_key`]
+    Overflow,
+    /// Reject the new key, the [`crate::recorder::ProcessionRecorder`] will hand out the
+    /// metrics crate's no-op handle
+    Noop,
+}
+
+/// The name of the key used for all new keys under [`OverflowPolicy::Overflow`]
+pub const OVERFLOW_KEY_NAME: &str = "metrics_procession_overflow";
+
+/// The key all new keys will be recorded against under [`OverflowPolicy::Overflow`], this
+/// key is marked with the label `overflow=true`
+pub fn overflow_key() -> Key {
+    static LABELS: [Label; 1] = [Label::from_static_parts("overflow", "true")];
+    Key::from_static_parts(OVERFLOW_KEY_NAME, &LABELS)
+}
+
+impl Default for LabelSet {
+    fn default() -> Self {
+        Self {
+            ids: BTreeMap::new(),
+            keys: Vec::new(),
+            last: None,
+            overflow_policy: OverflowPolicy::default(),
+            rejected: 0,
+            max_id: LabelId::MAX,
+            next_id: 0,
+            strings: BTreeSet::new(),
+        }
+    }
+}
+
+/// Two sets are equal when they map the same keys to the same identifiers
+impl PartialEq for LabelSet {
+    fn eq(&self, other: &Self) -> bool {
+        self.ids == other.ids
+    }
+}
+
+impl FromIterator<(Key, LabelId)> for LabelSet {
+    fn from_iter<T: IntoIterator<Item = (Key, LabelId)>>(iter: T) -> Self {
+        Self::from_ids(iter.into_iter().collect())
+    }
+}
 
 impl LabelSet {
+    /// Create a set from an existing mapping of keys to identifiers, interning their strings
+    /// and building the reverse index
+    fn from_ids(ids: BTreeMap<Key, LabelId>) -> Self {
+        Self::default().with_ids(ids)
+    }
+
+    /// Replace the keys of this set with an existing mapping of keys to identifiers
+    fn with_ids(mut self, ids: BTreeMap<Key, LabelId>) -> Self {
+        self.ids.clear();
+        let overflow = overflow_key();
+        // an overflow key holds the last identifier without using up the ones before it
+        self.next_id = ids
+            .iter()
+            .filter(|(key, id)| **id != self.max_id || **key != overflow)
+            .map(|(_, id)| *id as usize + 1)
+            .max()
+            .unwrap_or_default();
+        let len = ids
+            .values()
+            .filter(|id| **id != self.max_id)
+            .map(|id| *id as usize + 1)
+            .max()
+            .unwrap_or_default();
+        self.keys = vec![None; len];
+        self.last = None;
+        for (key, id) in ids {
+            let key = self.intern_key(&key);
+            self.set_reverse(id, key.clone());
+            self.ids.insert(key, id);
+        }
+        self
+    }
+
+    /// Record the key of an identifier in the reverse index, growing it when needed
+    fn set_reverse(&mut self, id: LabelId, key: Key) {
+        if id == self.max_id {
+            self.last = Some(key);
+            return;
+        }
+        let index = id as usize;
+        if index >= self.keys.len() {
+            self.keys.resize(index + 1, None);
+        }
+        self.keys[index] = Some(key);
+    }
+
+    /// The shared copy of the provided string, adding it to the set if it is new
+    fn intern(&mut self, s: &str) -> SharedString {
+        if let Some(shared) = self.strings.get(s) {
+            return SharedString::from(shared.clone());
+        }
+        let shared: Arc<str> = Arc::from(s);
+        self.strings.insert(shared.clone());
+        SharedString::from(shared)
+    }
+
+    /// A copy of the provided key using the shared copy of each of its strings
+    fn intern_key(&mut self, key: &Key) -> Key {
+        let name = self.intern(key.name());
+        let labels: Vec<Label> = key
+            .labels()
+            .map(|l| Label::new(self.intern(l.key()), self.intern(l.value())))
+            .collect();
+        Key::from_parts(name, labels)
+    }
+
+    /// Iterate over every distinct string used by the keys in this set
+    pub fn strings(&self) -> impl Iterator<Item = &str> {
+        self.strings.iter().map(|s| s.as_ref())
+    }
+
+    /// A breakdown of the heap memory held by this set, the nodes of the maps are estimated
+    /// since their exact layout depends on the order keys were inserted
+    pub fn memory_usage(&self) -> MemoryUsage {
+        // every key is interned so its labels are an exactly sized `Vec` while its strings
+        // are shared with `strings`
+        let key_labels = |key: &Key| key.labels().len() * size_of::<Label>();
+        let mut labels = memory::btree_size::<Key, LabelId>(self.ids.len())
+            + self.ids.keys().map(key_labels).sum::<usize>()
+            + self.keys.capacity() * size_of::<Option<Key>>()
+            + self
+                .keys
+                .iter()
+                .chain([&self.last])
+                .flatten()
+                .map(key_labels)
+                .sum::<usize>()
+            + memory::btree_size::<Arc<str>, ()>(self.strings.len());
+        let mut strings = 0;
+        for s in &self.strings {
+            // the reference counts are stored in the same allocation as the string
+            let allocation = Layout::new::<[usize; 2]>()
+                .extend(Layout::for_value::<str>(s))
+                .map(|(layout, _)| layout.pad_to_align().size())
+                .unwrap_or_default();
+            strings += s.len();
+            labels += allocation - s.len();
+        }
+        MemoryUsage {
+            labels,
+            strings,
+            ..Default::default()
+        }
+    }
+
+    /// Record the key for a newly assigned identifier in the reverse index
+    fn insert(&mut self, key: Key, id: LabelId) {
+        let key = self.intern_key(&key);
+        let index = id as usize;
+        self.next_id = self.next_id.max(index + 1);
+        self.set_reverse(id, key.clone());
+        self.ids.insert(key, id);
+    }
+
+    /// Use the provided [`OverflowPolicy`] once every identifier has been assigned
+    pub fn with_overflow_policy(mut self, overflow_policy: OverflowPolicy) -> Self {
+        self.overflow_policy = overflow_policy;
+        self
+    }
+
+    /// Replace the [`OverflowPolicy`] used once every identifier has been assigned
+    pub fn set_overflow_policy(&mut self, overflow_policy: OverflowPolicy) {
+        self.overflow_policy = overflow_policy;
+    }
+
+    /// The [`OverflowPolicy`] used once every identifier has been assigned
+    pub fn overflow_policy(&self) -> OverflowPolicy {
+        self.overflow_policy
+    }
+
+    /// The number of times [`LabelSet::ensure_key`] was unable to give a key its own identifier
+    pub fn rejected(&self) -> u64 {
+        self.rejected
+    }
+
+    /// The number of keys in this set
+    pub fn len(&self) -> usize {
+        self.ids.len()
+    }
+
+    /// If this set has no keys
+    pub fn is_empty(&self) -> bool {
+        self.ids.is_empty()
+    }
+
+    /// Iterate over each key in this set along with its identifier
+    pub fn iter(&self) -> impl Iterator<Item = (&Key, LabelId)> {
+        self.ids.iter().map(|(k, v)| (k, *v))
+    }
+
+    /// The identifier of each key in this set
+    pub fn ids(&self) -> &BTreeMap<Key, LabelId> {
+        &self.ids
+    }
+
+    /// Iterate over each key in this set
+    pub fn keys(&self) -> impl Iterator<Item = &Key> {
+        self.ids.keys()
+    }
+
     /// Get the identifier for the provided key
-    pub fn get(&self, key: &Key) -> Option<u16> {
-        self.0.get(key).copied()
+    pub fn get(&self, key: &Key) -> Option<LabelId> {
+        self.ids.get(key).copied()
+    }
+
+    /// Get the key for the provided identifier
+    pub fn key(&self, id: LabelId) -> Option<&Key> {
+        if id == self.max_id {
+            return self.last.as_ref();
+        }
+        self.keys.get(id as usize)?.as_ref()
     }
 
     /// ensure the [`metrics::Key`] is in the set, inserting a clone if not
-    /// already present, returning the correct identifier for the provided key
-    pub fn ensure_key(&mut self, key: &Key) -> u16 {
-        if let Some(v) = self.0.get(key) {
-            return *v;
+    /// already present, returning the correct identifier for the provided key.
+    ///
+    /// Once every identifier has been assigned, the [`OverflowPolicy`] decides if `None` is
+    /// returned or the identifier for the [`overflow_key`]
+    pub fn ensure_key(&mut self, key: &Key) -> Option<LabelId> {
+        if let Some(v) = self.ids.get(key) {
+            return Some(*v);
+        }
+        let next = self.next_id;
+        // the last identifier is reserved for the overflow key when overflowing
+        let reserved = usize::from(self.overflow_policy == OverflowPolicy::Overflow);
+        if next + reserved <= self.max_id as usize {
+            let v = LabelId::try_from(next).ok()?;
+            self.insert(key.clone(), v);
+            return Some(v);
+        }
+        self.rejected += 1;
+        if self.overflow_policy != OverflowPolicy::Overflow {
+            return None;
+        }
+        let overflow = overflow_key();
+        if let Some(v) = self.ids.get(&overflow) {
+            return Some(*v);
+        }
+        if self.ids.values().any(|v| *v == self.max_id) {
+            // the policy changed after every identifier was already assigned
+            return None;
+        }
+        self.insert(overflow, self.max_id);
+        Some(self.max_id)
+    }
+}
+
+/// Records the width of [`LabelId`] in a serialized [`crate::procession::Procession`], captures
+/// without this value were recorded with 16 bit identifiers.
+///
+/// A 16 bit capture can be read by either build while a 16 bit build will refuse to read a
+/// 32 bit capture with a message pointing to the `wide-labels` feature
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub(crate) struct LabelIdBits;
+
+impl Serialize for LabelIdBits {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: serde::Serializer,
+    {
+        serializer.serialize_u32(LabelId::BITS)
+    }
+}
+
+impl<'de> Deserialize<'de> for LabelIdBits {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        let bits = u32::deserialize(deserializer)?;
+        match bits {
+            16 | 32 if bits <= LabelId::BITS => Ok(Self),
+            32 => Err(serde::de::Error::custom(
+                "capture was recorded with 32 bit label ids, enable the `wide-labels` feature to read it",
+            )),
+            _ => Err(serde::de::Error::custom(format!(
+                "unsupported label id width: {bits}"
+            ))),
         }
-        let v = u16::try_from(self.0.len()).unwrap_or_else(|_| {
-            eprintln!("too many labels!!!");
-            u16::MAX
-        });
-        self.0.insert(key.clone(), v);
-        v
     }
 }
 
-/// Helper struct for serializing the [`LabelSet`] set to avoid needing to re-allocate the
-/// strings owned by the [`metrics::Key`] type along with its value to make it possible
-/// to deserialize a serialized `LabelSet` with the correct key<->id mapping
-#[derive(Debug, Serialize)]
+/// Helper struct for deserializing the previous form of a [`LabelSet`], where each key
+/// held its own strings along with its value. The strings are borrowed from the input when
+/// possible and owned otherwise, like when reading from an [`std::io::Read`]
+#[derive(Debug)]
 struct SerKey<'a> {
-    key_name: &'a str,
+    key_name: Cow<'a, str>,
     labels: Vec<SerLabel<'a>>,
-    value: u16,
+    value: LabelId,
 }
 
 /// Helper struct for serializing just the key-value pair owned by a [`metrics::Label`]
-/// this will use a tuple of string references
+/// this will use a tuple of strings
 #[derive(Debug, Serialize, Deserialize)]
-struct SerLabel<'a>(&'a str, &'a str);
+struct SerLabel<'a>(#[serde(borrow)] Cow<'a, str>, #[serde(borrow)] Cow<'a, str>);
 impl<'a> From<SerLabel<'a>> for metrics::Label {
     fn from(value: SerLabel<'a>) -> Self {
-        metrics::Label::new(value.0.to_string(), value.1.to_string())
+        metrics::Label::new(value.0.into_owned(), value.1.into_owned())
     }
 }
 
@@ -61,24 +371,86 @@ impl metrics::IntoLabels for SerLabels<'_> {
     }
 }
 
+/// Helper for (de)serializing a [`LabelSet`] with each distinct string stored once, the
+/// keys refer to their name and labels by their position in `strings`
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename = "LabelSet")]
+struct SerLabelSet<'a> {
+    #[serde(borrow)]
+    strings: Vec<CowStr<'a>>,
+    keys: Vec<SerInternedKey>,
+}
+
+/// Helper for (de)serializing a [`metrics::Key`] as positions in [`SerLabelSet::strings`]
+#[derive(Debug, Serialize, Deserialize)]
+struct SerInternedKey {
+    name: u32,
+    labels: Vec<(u32, u32)>,
+    value: LabelId,
+}
+
+/// Helper for deserializing a string that is borrowed when possible
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(transparent)]
+struct CowStr<'a>(#[serde(borrow)] Cow<'a, str>);
+
+/// Serialized as a table of every distinct string followed by the keys referring to them
 impl Serialize for LabelSet {
     fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
     where
         S: serde::Serializer,
     {
-        let mut m = serializer.serialize_seq(Some(self.0.len()))?;
-        for (k, v) in self.0.iter() {
-            let ser_key = SerKey {
-                key_name: k.name(),
-                labels: k.labels().map(|l| SerLabel(l.key(), l.value())).collect(),
+        let mut positions = BTreeMap::new();
+        let mut strings = Vec::new();
+        let mut position = |s| {
+            *positions.entry(s).or_insert_with(|| {
+                strings.push(CowStr(Cow::Borrowed(s)));
+                strings.len() as u32 - 1
+            })
+        };
+        let keys = self
+            .ids
+            .iter()
+            .map(|(k, v)| SerInternedKey {
+                name: position(k.name()),
+                labels: k
+                    .labels()
+                    .map(|l| (position(l.key()), position(l.value())))
+                    .collect(),
                 value: *v,
-            };
-            m.serialize_element(&ser_key)?;
+            })
+            .collect();
+        SerLabelSet { strings, keys }.serialize(serializer)
+    }
+}
+
+impl SerLabelSet<'_> {
+    /// Resolve the keys against the string table, each string is only allocated once
+    fn into_label_set<E: serde::de::Error>(self) -> Result<LabelSet, E> {
+        let mut ret = LabelSet::default();
+        let strings: Vec<SharedString> = self.strings.iter().map(|s| ret.intern(&s.0)).collect();
+        let get = |i: u32| {
+            strings
+                .get(i as usize)
+                .cloned()
+                .ok_or_else(|| E::custom(format!("string index {i} out of range")))
+        };
+        let mut ids = BTreeMap::new();
+        for key in self.keys {
+            let labels = key
+                .labels
+                .into_iter()
+                .map(|(k, v)| Ok(Label::new(get(k)?, get(v)?)))
+                .collect::<Result<Vec<_>, E>>()?;
+            ids.insert(Key::from_parts(get(key.name)?, labels), key.value);
         }
-        m.end()
+        Ok(ret.with_ids(ids))
     }
 }
 
+/// Deserialized from the string table written by the `Serialize` implementation, a
+/// self-describing format like JSON can also provide the previous form of a sequence of keys
+/// each holding their own strings
 impl<'de> Deserialize<'de> for LabelSet {
     fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
     where
@@ -90,8 +462,17 @@ impl<'de> Deserialize<'de> for LabelSet {
             type Value = LabelSet;
 
             fn expecting(&self, formatter: &mut std::fmt::Formatter) -> std::fmt::Result {
-                formatter.write_str("sequence of label set entries")
+                formatter.write_str("a label set")
             }
+
+            fn visit_map<A>(self, map: A) -> Result<Self::Value, A::Error>
+            where
+                A: serde::de::MapAccess<'de>,
+            {
+                SerLabelSet::deserialize(serde::de::value::MapAccessDeserializer::new(map))?
+                    .into_label_set()
+            }
+
             fn visit_seq<A>(self, mut seq: A) -> Result<Self::Value, A::Error>
             where
                 A: serde::de::SeqAccess<'de>,
@@ -103,14 +484,18 @@ impl<'de> Deserialize<'de> for LabelSet {
                         labels,
                         value,
                     } = element;
-                    let key = Key::from_parts(key_name.to_string(), SerLabels(labels));
+                    let key = Key::from_parts(key_name.into_owned(), SerLabels(labels));
                     ret.insert(key, value);
                 }
-                Ok(LabelSet(ret))
+                Ok(LabelSet::from_ids(ret))
             }
         }
 
-        deserializer.deserialize_seq(LabelSetVisitor)
+        if deserializer.is_human_readable() {
+            deserializer.deserialize_any(LabelSetVisitor)
+        } else {
+            SerLabelSet::deserialize(deserializer)?.into_label_set()
+        }
     }
 }
 
@@ -119,6 +504,16 @@ impl<'de> Deserialize<'de> for SerKey<'de> {
     where
         D: serde::Deserializer<'de>,
     {
+        #[derive(Deserialize)]
+        #[serde(field_identifier, rename_all = "snake_case")]
+        enum Field {
+            KeyName,
+            Labels,
+            Value,
+            #[serde(other)]
+            Other,
+        }
+
         struct EntryVisitor;
         impl<'de> Visitor<'de> for EntryVisitor {
             type Value = SerKey<'de>;
@@ -131,41 +526,225 @@ impl<'de> Deserialize<'de> for SerKey<'de> {
             where
                 A: serde::de::MapAccess<'de>,
             {
-                let mut key_name: Option<&str> = None;
-                let mut labels: Option<Vec<SerLabel<'_>>> = None;
-                let mut value: Option<u16> = None;
-                while let Some((k, v)) = map.next_entry()? {
-                    match (k, v) {
-                        ("key_name", SerKeyValue::KeyName(name)) => key_name = Some(name),
-                        ("value", SerKeyValue::Value(i)) => value = Some(i),
-                        ("labels", SerKeyValue::Labels(ls)) => labels = Some(ls),
-                        _ => {}
-                    }
-                    if key_name.is_some() && labels.is_some() && value.is_some() {
-                        break;
+                let mut key_name: Option<Cow<'de, str>> = None;
+                let mut labels: Option<Vec<SerLabel<'de>>> = None;
+                let mut value: Option<LabelId> = None;
+                while let Some(field) = map.next_key()? {
+                    match field {
+                        Field::KeyName => key_name = Some(map.next_value::<CowStr>()?.0),
+                        Field::Labels => labels = Some(map.next_value()?),
+                        Field::Value => value = Some(map.next_value()?),
+                        Field::Other => {
+                            map.next_value::<serde::de::IgnoredAny>()?;
+                        }
                     }
                 }
                 Ok(SerKey {
-                    key_name: key_name.ok_or_else(|| {
-                        serde::de::Error::custom("key_name missing from label set entry")
-                    })?,
-                    labels: labels.ok_or_else(|| {
-                        serde::de::Error::custom("key_name missing from label set entry")
-                    })?,
-                    value: value.ok_or_else(|| {
-                        serde::de::Error::custom("key_name missing from label set entry")
-                    })?,
+                    key_name: key_name
+                        .ok_or_else(|| serde::de::Error::missing_field("key_name"))?,
+                    labels: labels.ok_or_else(|| serde::de::Error::missing_field("labels"))?,
+                    value: value.ok_or_else(|| serde::de::Error::missing_field("value"))?,
+                })
+            }
+
+            fn visit_seq<A>(self, mut seq: A) -> Result<Self::Value, A::Error>
+            where
+                A: serde::de::SeqAccess<'de>,
+            {
+                let missing = |i| serde::de::Error::invalid_length(i, &"a label set entry");
+                Ok(SerKey {
+                    key_name: seq.next_element::<CowStr>()?.ok_or_else(|| missing(0))?.0,
+                    labels: seq.next_element()?.ok_or_else(|| missing(1))?,
+                    value: seq.next_element()?.ok_or_else(|| missing(2))?,
                 })
             }
         }
 
-        #[derive(Debug, Deserialize)]
-        #[serde(untagged)]
-        enum SerKeyValue<'a> {
-            KeyName(&'a str),
-            Labels(Vec<SerLabel<'a>>),
-            Value(u16),
+        deserializer.deserialize_struct("SerKey", &["key_name", "labels", "value"], EntryVisitor)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::procession::Procession;
+
+    use super::*;
+
+    fn full_set(overflow_policy: OverflowPolicy) -> LabelSet {
+        let mut labels = LabelSet {
+            max_id: 3,
+            ..Default::default()
+        }
+        .with_overflow_policy(overflow_policy);
+        for i in 0..3 {
+            assert_eq!(
+                labels.ensure_key(&Key::from_name(format!("key{i}"))),
+                Some(i)
+            );
+        }
+        labels
+    }
+
+    #[test]
+    fn drop_rejects_new_keys() {
+        for policy in [OverflowPolicy::Drop, OverflowPolicy::Noop] {
+            let mut labels = full_set(policy);
+            assert_eq!(labels.ensure_key(&Key::from_name("key3")), Some(3));
+            assert_eq!(labels.ensure_key(&Key::from_name("key4")), None);
+            assert_eq!(labels.ensure_key(&Key::from_name("key5")), None);
+            // existing keys are still available
+            assert_eq!(labels.ensure_key(&Key::from_name("key0")), Some(0));
+            assert_eq!(labels.rejected(), 2);
+            assert_eq!(labels.len(), 4);
+        }
+    }
+
+    #[test]
+    fn overflow_shares_a_key() {
+        let mut labels = full_set(OverflowPolicy::Overflow);
+        assert_eq!(labels.ensure_key(&Key::from_name("key3")), Some(3));
+        assert_eq!(labels.ensure_key(&Key::from_name("key4")), Some(3));
+        assert_eq!(labels.get(&overflow_key()), Some(3));
+        assert_eq!(labels.get(&Key::from_name("key3")), None);
+        assert_eq!(labels.rejected(), 2);
+        assert_eq!(labels.len(), 4);
+    }
+
+    #[test]
+    fn sparse_ids() {
+        let mut labels: LabelSet = [(Key::from_name("one"), 1), (Key::from_name("five"), 5)]
+            .into_iter()
+            .collect();
+        // the reverse index covers every identifier so looking up a key never scans the set
+        assert_eq!(labels.keys.len(), 6);
+        assert_eq!(labels.ensure_key(&Key::from_name("six")), Some(6));
+        assert_eq!(labels.ensure_key(&Key::from_name("seven")), Some(7));
+        assert_eq!(labels.key(1), Some(&Key::from_name("one")));
+        assert_eq!(labels.key(6), Some(&Key::from_name("six")));
+        assert_eq!(labels.ids().len(), 4);
+        let json = serde_json::to_string(&labels).unwrap();
+        let mut labels: LabelSet = serde_json::from_str(&json).unwrap();
+        assert_eq!(labels.ensure_key(&Key::from_name("eight")), Some(8));
+        // an overflow key doesn't use up the identifiers before it
+        let mut labels = LabelSet::from_iter([(overflow_key(), LabelId::MAX)])
+            .with_overflow_policy(OverflowPolicy::Overflow);
+        assert_eq!(labels.ensure_key(&Key::from_name("zero")), Some(0));
+        assert_eq!(labels.key(LabelId::MAX), Some(&overflow_key()));
+        assert_eq!(labels.key(0), Some(&Key::from_name("zero")));
+        assert_eq!(labels.keys.len(), 1);
+    }
+
+    #[test]
+    fn reverse_index() {
+        let mut labels = full_set(OverflowPolicy::Overflow);
+        labels.ensure_key(&Key::from_name("key3"));
+        labels.ensure_key(&Key::from_name("key4"));
+        for (key, id) in labels.iter() {
+            assert_eq!(labels.key(id), Some(key));
+        }
+        assert_eq!(labels.key(3), Some(&overflow_key()));
+        let json = serde_json::to_string(&labels).unwrap();
+        let labels: LabelSet = serde_json::from_str(&json).unwrap();
+        for i in 0..3 {
+            assert_eq!(labels.key(i), Some(&Key::from_name(format!("key{i}"))));
         }
-        deserializer.deserialize_map(EntryVisitor)
+        assert_eq!(labels.key(4), None);
+        // sparse identifiers are still found
+        let sparse: LabelSet = [(Key::from_name("sparse"), 10)].into_iter().collect();
+        assert_eq!(sparse.key(10), Some(&Key::from_name("sparse")));
+        assert_eq!(sparse.key(0), None);
+    }
+
+    #[test]
+    fn deserialize_from_reader() {
+        let labels: LabelSet = [
+            (Key::from_name("plain"), 0),
+            (
+                Key::from_parts("escaped \"name\"", &[("quote\"d", "new\nline")]),
+                1,
+            ),
+        ]
+        .into_iter()
+        .collect();
+        let mut procession = Procession {
+            labels,
+            ..Default::default()
+        };
+        procession.insert_entry(
+            crate::event::Entry::Counter {
+                value: 1,
+                op: crate::event::Op::Add,
+            },
+            1,
+        );
+        let json = serde_json::to_vec(&procession).unwrap();
+        let from_reader: Procession = serde_json::from_reader(json.as_slice()).unwrap();
+        assert_eq!(from_reader, procession);
+        let from_slice: Procession = serde_json::from_slice(&json).unwrap();
+        assert_eq!(from_slice, procession);
+        let bytes = postcard::to_stdvec(&procession).unwrap();
+        let from_postcard: Procession = postcard::from_bytes(&bytes).unwrap();
+        assert_eq!(from_postcard, procession);
+    }
+
+    #[test]
+    fn strings_are_interned() {
+        let mut labels = LabelSet::default();
+        for name in ["requests", "errors"] {
+            for status in ["200", "500"] {
+                let key = Key::from_parts(
+                    name.to_string(),
+                    vec![
+                        Label::new("service".to_string(), "foo".to_string()),
+                        Label::new("status".to_string(), status.to_string()),
+                    ],
+                );
+                labels.ensure_key(&key);
+            }
+        }
+        assert_eq!(labels.len(), 4);
+        let mut strings: Vec<_> = labels.strings().collect();
+        strings.sort();
+        assert_eq!(
+            strings,
+            [
+                "200", "500", "errors", "foo", "requests", "service", "status"
+            ]
+        );
+        // every key shares the same copy of `service=foo`
+        let services: Vec<_> = labels
+            .keys()
+            .map(|k| k.labels().next().unwrap().value().as_ptr())
+            .collect();
+        assert!(services.iter().all(|p| *p == services[0]));
+        let key = labels.key(0).unwrap();
+        assert_eq!(key.name().as_ptr(), labels.key(1).unwrap().name().as_ptr());
+        // and each string is only serialized once
+        let json = serde_json::to_string(&labels).unwrap();
+        assert_eq!(json.matches("\"foo\"").count(), 1);
+        let back: LabelSet = serde_json::from_str(&json).unwrap();
+        assert_eq!(back, labels);
+        assert_eq!(back.strings().count(), 7);
+        let bytes = postcard::to_stdvec(&labels).unwrap();
+        let back: LabelSet = postcard::from_bytes(&bytes).unwrap();
+        assert_eq!(back, labels);
+        let invalid = r#"{"strings":["a"],"keys":[{"name":1,"labels":[],"value":0}]}"#;
+        let err = serde_json::from_str::<LabelSet>(invalid).unwrap_err();
+        assert!(err.to_string().contains("out of range"), "{err}");
+    }
+
+    #[test]
+    fn label_id_width_is_recorded() {
+        let json = serde_json::to_value(Procession::default()).unwrap();
+        assert_eq!(json["label_id_bits"], LabelId::BITS);
+        let legacy = r#"{"chunks":[],"labels":[{"key_name":"k","labels":[],"value":1}]}"#;
+        let legacy: Procession = serde_json::from_str(legacy).unwrap();
+        assert_eq!(legacy.labels.get(&Key::from_name("k")), Some(1));
+        let wide = r#"{"label_id_bits":32,"chunks":[],"labels":[{"key_name":"k","labels":[],"value":70000}]}"#;
+        let wide = serde_json::from_str::<Procession>(wide);
+        #[cfg(feature = "wide-labels")]
+        assert_eq!(wide.unwrap().labels.get(&Key::from_name("k")), Some(70000));
+        #[cfg(not(feature = "wide-labels"))]
+        assert!(wide.unwrap_err().to_string().contains("wide-labels"));
     }
 }
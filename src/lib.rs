@@ -1,104 +1,130 @@
 #![doc = include_str!("../README.md")]
+pub mod capture;
 pub mod chunk;
+pub mod clock;
+pub mod codec;
+pub mod description;
 pub mod event;
 pub mod iter;
 pub mod label_set;
+pub mod memory;
 pub mod procession;
 pub mod recorder;
+pub mod retention;
+pub mod rollup;
+#[cfg(any(feature = "json", feature = "postcard"))]
+pub mod stream;
+#[cfg(test)]
+mod test_util;
+pub mod validate;
+pub mod view;
+pub mod wal;
 
 #[cfg(test)]
 mod tests {
-    use metrics::{Key, Label};
+    use metrics::{Key, KeyName, Label, Unit};
 
     use crate::{
-        chunk::Chunk,
-        event::{Entry, Event, Op},
+        chunk::{Chunk, Events},
+        description::{Description, Descriptions},
+        event::{Event, Op, RawEntry},
         label_set::LabelSet,
         procession::Procession,
+        test_util::start,
     };
 
     #[test]
     fn ser_de() {
-        let labels = LabelSet(
-            [
-                (
-                    Key::from_parts("label1", vec![Label::new("key", "value")]),
-                    1,
-                ),
-                (
-                    Key::from_parts(
-                        "label2",
-                        vec![Label::new("key", "value"), Label::new("other", "value")],
-                    ),
-                    2,
+        let labels: LabelSet = [
+            (
+                Key::from_parts("label1", vec![Label::new("key", "value")]),
+                1,
+            ),
+            (
+                Key::from_parts(
+                    "label2",
+                    vec![Label::new("key", "value"), Label::new("other", "value")],
                 ),
-                (Key::from_parts("label3", vec![]), 3),
-            ]
-            .into_iter()
-            .collect(),
-        );
+                2,
+            ),
+            (Key::from_parts("label3", vec![]), 3),
+        ]
+        .into_iter()
+        .collect();
         let streams = Procession {
             labels,
             chunks: vec![
-                Chunk {
-                    reference_time: time::OffsetDateTime::new_utc(
-                        time::Date::from_calendar_date(2025, time::Month::January, 1).unwrap(),
-                        time::Time::from_hms(0, 0, 0).unwrap(),
-                    ),
-                    events: vec![
-                        Event {
-                            entry: Entry::Counter {
-                                value: 1,
-                                op: Op::Add,
+                Chunk::from_events(
+                    start(),
+                    Events {
+                        events: vec![
+                            Event {
+                                entry: RawEntry::Counter {
+                                    value: 1,
+                                    op: Op::Add,
+                                },
+                                ms: 0,
+                                label: 1,
+                            },
+                            Event {
+                                entry: RawEntry::Gauge {
+                                    value: 1.0,
+                                    op: Op::Set,
+                                },
+                                ms: 1,
+                                label: 2,
                             },
-                            ms: 0,
-                            label: 1,
-                        },
-                        Event {
-                            entry: Entry::Gauge {
-                                value: 1.0,
-                                op: Op::Set,
+                            Event {
+                                entry: RawEntry::Histogram { value: 1.0 },
+                                ms: 2,
+                                label: 3,
                             },
-                            ms: 1,
-                            label: 2,
-                        },
-                        Event {
-                            entry: Entry::Histogram { value: 1.0 },
-                            ms: 2,
-                            label: 3,
-                        },
-                    ],
-                },
-                Chunk {
-                    reference_time: time::OffsetDateTime::new_utc(
-                        time::Date::from_calendar_date(2025, time::Month::January, 1).unwrap(),
-                        time::Time::from_hms(1, 0, 0).unwrap(),
-                    ),
-                    events: vec![
-                        Event {
-                            entry: Entry::Counter {
-                                value: 1,
-                                op: Op::Set,
+                        ],
+                        wide_values: Vec::new(),
+                    },
+                ),
+                Chunk::from_events(
+                    start() + time::Duration::hours(1),
+                    Events {
+                        events: vec![
+                            Event {
+                                entry: RawEntry::Counter {
+                                    value: 1,
+                                    op: Op::Set,
+                                },
+                                ms: 0,
+                                label: 1,
+                            },
+                            Event {
+                                entry: RawEntry::Gauge {
+                                    value: 1.0,
+                                    op: Op::Add,
+                                },
+                                ms: 1,
+                                label: 2,
                             },
-                            ms: 0,
-                            label: 1,
-                        },
-                        Event {
-                            entry: Entry::Gauge {
-                                value: 1.0,
-                                op: Op::Add,
+                            Event {
+                                entry: RawEntry::Histogram { value: 1.0 },
+                                ms: 2,
+                                label: 3,
                             },
-                            ms: 1,
-                            label: 2,
-                        },
-                        Event {
-                            entry: Entry::Histogram { value: 1.0 },
-                            ms: 2,
-                            label: 3,
-                        },
-                    ],
-                },
+                        ],
+                        wide_values: Vec::new(),
+                    },
+                ),
             ],
+            descriptions: Descriptions(
+                [(
+                    KeyName::from("label1"),
+                    Description {
+                        unit: Some(Unit::Bytes),
+                        text: "the first label".to_string(),
+                    },
+                )]
+                .into_iter()
+                .collect(),
+            ),
+            ..Default::default()
         };
         let json = serde_json::to_string_pretty(&streams).unwrap();
         let back = serde_json::from_str(&json).unwrap();
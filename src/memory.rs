@@ -0,0 +1,249 @@
+//! Accounting for the heap memory held by a [`crate::procession::Procession`]
+use std::{
+    iter::Sum,
+    mem::{MaybeUninit, size_of},
+    ops::{Add, AddAssign},
+};
+
+/// A breakdown of the heap memory held by a [`crate::procession::Procession`] (or one of its
+/// parts), in bytes
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct MemoryUsage {
+    /// The bytes holding events, this is the length of the open events, the encoded bytes of
+    /// sealed chunks or the buckets of rolled up chunks
+    pub events_used: usize,
+    /// The bytes allocated for events, the same as `events_used` plus any spare capacity
+    pub events_reserved: usize,
+    /// The `Vec` holding each [`crate::chunk::Chunk`]
+    pub chunks: usize,
+    /// The label dictionary, both maps between keys and identifiers, the labels of each key
+    /// and the bookkeeping of the interned strings
+    pub labels: usize,
+    /// The bytes of each distinct interned string
+    pub strings: usize,
+    /// The units and help text provided for each metric name
+    pub descriptions: usize,
+}
+
+impl MemoryUsage {
+    /// The total number of bytes allocated
+    pub fn total(&self) -> usize {
+        self.events_reserved + self.chunks + self.labels + self.strings + self.descriptions
+    }
+
+    /// The events held by the provided `Vec`
+    pub(crate) fn events<T>(events: &Vec<T>) -> Self {
+        Self {
+            events_used: events.len() * size_of::<T>(),
+            events_reserved: events.capacity() * size_of::<T>(),
+            ..Default::default()
+        }
+    }
+}
+
+impl Add for MemoryUsage {
+    type Output = Self;
+
+    fn add(mut self, rhs: Self) -> Self {
+        self += rhs;
+        self
+    }
+}
+
+impl AddAssign for MemoryUsage {
+    fn add_assign(&mut self, rhs: Self) {
+        self.events_used += rhs.events_used;
+        self.events_reserved += rhs.events_reserved;
+        self.chunks += rhs.chunks;
+        self.labels += rhs.labels;
+        self.strings += rhs.strings;
+        self.descriptions += rhs.descriptions;
+    }
+}
+
+impl Sum for MemoryUsage {
+    fn sum<I: Iterator<Item = Self>>(iter: I) -> Self {
+        iter.fold(Self::default(), Add::add)
+    }
+}
+
+/// The number of elements each node of a `BTreeMap` can hold
+const BTREE_CAPACITY: usize = 11;
+
+/// The layout of a leaf node of a `BTreeMap`
+#[allow(dead_code)]
+struct LeafNode<K, V> {
+    parent: *const (),
+    parent_idx: u16,
+    len: u16,
+    keys: [MaybeUninit<K>; BTREE_CAPACITY],
+    vals: [MaybeUninit<V>; BTREE_CAPACITY],
+}
+
+/// The layout of an internal node of a `BTreeMap`
+#[allow(dead_code)]
+struct InternalNode<K, V> {
+    data: LeafNode<K, V>,
+    edges: [*const (); BTREE_CAPACITY + 1],
+}
+
+/// An estimate of the bytes allocated for the nodes of a `BTreeMap` (or `BTreeSet` with a
+/// `V` of `()`) with `len` elements. The exact number of nodes depends on the order the
+/// elements were inserted, each node ends up with around 6 elements when they are inserted in
+/// order and closer to 8 when they are not, so the nodes are assumed to hold 8
+pub(crate) fn btree_size<K, V>(len: usize) -> usize {
+    const FILL: usize = 8;
+    if len == 0 {
+        return 0;
+    }
+    let mut nodes = len.div_ceil(FILL);
+    let mut size = nodes * size_of::<LeafNode<K, V>>();
+    while nodes > 1 {
+        nodes = nodes.div_ceil(FILL + 1);
+        size += nodes * size_of::<InternalNode<K, V>>();
+    }
+    size
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        alloc::{GlobalAlloc, Layout, System},
+        cell::Cell,
+    };
+
+    use metrics::{Key, KeyName, Label};
+    use time::{Duration, OffsetDateTime};
+
+    use crate::{
+        codec::SealOptions,
+        event::{Entry, Op},
+        procession::Procession,
+    };
+
+    /// Counts the bytes allocated by the current thread while it is tracking, other threads
+    /// (like the rest of the test suite) only pay for the check
+    struct Counting;
+
+    thread_local! {
+        static TRACKING: Cell<bool> = const { Cell::new(false) };
+        static ALLOCATED: Cell<isize> = const { Cell::new(0) };
+    }
+
+    fn track(delta: isize) {
+        if TRACKING.try_with(Cell::get).unwrap_or_default() {
+            let _ = ALLOCATED.try_with(|a| a.set(a.get() + delta));
+        }
+    }
+
+    unsafe impl GlobalAlloc for Counting {
+        unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+            let ptr = unsafe { System.alloc(layout) };
+            if !ptr.is_null() {
+                track(layout.size() as isize);
+            }
+            ptr
+        }
+
+        unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+            track(-(layout.size() as isize));
+            unsafe { System.dealloc(ptr, layout) }
+        }
+
+        unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+            let new = unsafe { System.realloc(ptr, layout, new_size) };
+            if !new.is_null() {
+                track(new_size as isize - layout.size() as isize);
+            }
+            new
+        }
+    }
+
+    #[global_allocator]
+    static GLOBAL: Counting = Counting;
+
+    /// Run `f` returning its result along with the bytes it left allocated
+    fn measure<T>(f: impl FnOnce() -> T) -> (T, usize) {
+        ALLOCATED.set(0);
+        TRACKING.set(true);
+        let value = f();
+        TRACKING.set(false);
+        (value, ALLOCATED.get() as usize)
+    }
+
+    fn assert_close(measured: usize, accounted: usize) {
+        let error = measured.abs_diff(accounted);
+        assert!(
+            error * 20 <= measured,
+            "accounted for {accounted} bytes but {measured} were allocated"
+        );
+    }
+
+    fn build() -> Procession {
+        let start = OffsetDateTime::UNIX_EPOCH;
+        let mut procession = Procession::default();
+        let mut labels = Vec::new();
+        for service in ["api", "worker", "scheduler"] {
+            for status in 0..20 {
+                let key = Key::from_parts(
+                    format!("{service}_requests"),
+                    vec![
+                        Label::new("service", service.to_string()),
+                        Label::new("status", format!("{}", 200 + status)),
+                    ],
+                );
+                labels.push(procession.ensure_label(&key).unwrap());
+            }
+            procession.describe(
+                KeyName::from(format!("{service}_requests")),
+                None,
+                format!("requests handled by {service}").into(),
+            );
+        }
+        // enough to seal a few chunks and leave the last one partly filled
+        for i in 0..20_000i64 {
+            let entry = Entry::Gauge {
+                value: (i % 100) as _,
+                op: Op::Set,
+            };
+            procession.insert_entry_at(
+                start + Duration::milliseconds(i * 17),
+                entry,
+                labels[i as usize % labels.len()],
+            );
+        }
+        procession.seal(SealOptions::default());
+        procession
+    }
+
+    #[test]
+    fn matches_allocations() {
+        let (procession, measured) = measure(build);
+        let usage = procession.memory_usage();
+        assert_close(measured, usage.total());
+        assert!(usage.events_used <= usage.events_reserved);
+        assert_eq!(
+            usage.strings,
+            procession.labels.strings().map(str::len).sum::<usize>()
+        );
+        // everything but the labels is freed before measuring
+        let (labels, measured) = measure(|| build().labels);
+        assert_close(measured, labels.memory_usage().total());
+    }
+
+    #[test]
+    fn counts_spare_capacity() {
+        let (procession, measured) = measure(|| {
+            let mut procession = build();
+            procession.roll_up(
+                OffsetDateTime::UNIX_EPOCH + Duration::minutes(3),
+                Duration::seconds(10),
+            );
+            procession
+        });
+        let usage = procession.memory_usage();
+        assert_close(measured, usage.total());
+        // the last chunk is still open and has room to grow
+        assert!(usage.events_used < usage.events_reserved);
+    }
+}
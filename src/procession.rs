@@ -1,123 +1,597 @@
+use std::{collections::BTreeMap, ops::RangeBounds, sync::Arc};
+
 use time::Duration;
 
-use metrics::{Key, Label};
+use metrics::{Key, KeyName, Label, SharedString, Unit};
 use serde::{Deserialize, Serialize};
 use time::OffsetDateTime;
 
 use crate::{
-    chunk::Chunk,
-    event::{Entry, Event},
-    iter::{Metric, MetricRef, MetricsIterator, MetricsRefIterator},
-    label_set::LabelSet,
+    chunk::{Chunk, Events},
+    clock::{self, Clock},
+    codec::SealOptions,
+    description::{Description, Descriptions},
+    event::{Entry, Event, Float, Op, RawEntry, ValueBits},
+    iter::{Filter, Metric, MetricRef, MetricsIterator, MetricsRefIterator},
+    label_set::{LabelId, LabelIdBits, LabelSet},
+    memory::MemoryUsage,
+    retention::Retention,
+    rollup::{self, Aggregate, Bucket, Rollup, RollupPolicy},
 };
 
 /// This represents a time series of metrics collected over some length of time
-#[derive(Debug, PartialEq, Clone, Serialize, Deserialize, Default)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Procession {
+    /// The width of the label identifiers, only used for serialization
+    #[serde(default)]
+    pub(crate) label_id_bits: LabelIdBits,
+    /// The width of the gauge and histogram values, only used for serialization
+    #[serde(default)]
+    pub(crate) value_bits: ValueBits,
     /// The series of chunks representing ~65 seconds of time in each chunk
     pub chunks: Vec<Chunk>,
     /// The set of all unique keys and labels currently in the set
     pub labels: LabelSet,
+    /// The units and help text provided for any metric names
+    #[serde(default)]
+    pub descriptions: Descriptions,
+    /// The limits on how many chunks will be kept
+    #[serde(skip)]
+    pub(crate) retention: Retention,
+    /// How chunks are sealed once they are no longer the last chunk, `None` keeps every
+    /// chunk open
+    #[serde(skip)]
+    pub(crate) sealing: Option<SealOptions>,
+    /// When old chunks are rolled up, `None` keeps every event
+    #[serde(skip)]
+    pub(crate) rollup: Option<RollupPolicy>,
+    /// The source of timestamps for newly inserted entries
+    #[serde(skip, default = "clock::system")]
+    pub(crate) clock: Arc<dyn Clock>,
+}
+
+impl Default for Procession {
+    fn default() -> Self {
+        Self {
+            label_id_bits: Default::default(),
+            value_bits: Default::default(),
+            chunks: Default::default(),
+            labels: Default::default(),
+            descriptions: Default::default(),
+            retention: Default::default(),
+            sealing: None,
+            rollup: None,
+            clock: clock::system(),
+        }
+    }
+}
+
+/// Two series are equal when they contain the same data, regardless of their
+/// [`Retention`] or [`Clock`]
+impl PartialEq for Procession {
+    fn eq(&self, other: &Self) -> bool {
+        self.chunks == other.chunks
+            && self.labels == other.labels
+            && self.descriptions == other.descriptions
+    }
 }
 
 impl Procession {
-    /// A naive attempt to calculate the memory size of the current state
+    /// Use the provided [`Clock`] for timestamping newly inserted entries
+    pub fn with_clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    /// The [`Clock`] used for timestamping newly inserted entries
+    pub fn clock(&self) -> &Arc<dyn Clock> {
+        &self.clock
+    }
+
+    /// Set the [`Retention`] limits for this series
+    pub fn with_retention(mut self, retention: Retention) -> Self {
+        self.retention = retention;
+        self
+    }
+
+    /// Replace the [`Retention`] limits for this series, evicting any chunks that
+    /// exceed the new limits
+    pub fn set_retention(&mut self, retention: Retention) {
+        self.retention = retention;
+        self.enforce_retention(self.clock.now());
+    }
+
+    /// The current [`Retention`] limits for this series
+    pub fn retention(&self) -> Retention {
+        self.retention
+    }
+
+    /// Seal chunks with the provided [`SealOptions`] once a newer chunk is started, `None` will
+    /// keep every chunk open
+    pub fn with_sealing(mut self, sealing: Option<SealOptions>) -> Self {
+        self.sealing = sealing;
+        self
+    }
+
+    /// Replace how chunks are sealed once a newer chunk is started, this only applies to
+    /// chunks started after this call, see [`Procession::seal`] to re-seal existing chunks
+    pub fn set_sealing(&mut self, sealing: Option<SealOptions>) {
+        self.sealing = sealing;
+    }
+
+    /// How chunks are sealed once a newer chunk is started
+    pub fn sealing(&self) -> Option<SealOptions> {
+        self.sealing
+    }
+
+    /// Roll up chunks according to the provided [`RollupPolicy`] whenever a newer chunk is
+    /// started, `None` will keep every event
+    pub fn with_rollup(mut self, rollup: Option<RollupPolicy>) -> Self {
+        self.rollup = rollup;
+        self
+    }
+
+    /// Replace when old chunks are rolled up, any chunks already old enough are rolled up
+    /// immediately
+    pub fn set_rollup(&mut self, rollup: Option<RollupPolicy>) {
+        self.rollup = rollup;
+        self.enforce_rollup(self.clock.now());
+    }
+
+    /// When old chunks are rolled up
+    pub fn rollup(&self) -> Option<RollupPolicy> {
+        self.rollup
+    }
+
+    /// Roll up any chunks older than the [`RollupPolicy`] allows
+    pub fn enforce_rollup(&mut self, now: OffsetDateTime) {
+        if let Some(policy) = self.rollup {
+            self.roll_up(now - policy.after, policy.step);
+        }
+    }
+
+    /// Evict chunks from the front of the series until it is within the [`Retention`] limits
+    pub fn enforce_retention(&mut self, now: OffsetDateTime) {
+        let evict = self.retention.chunks_to_evict(self, now);
+        self.chunks.drain(..evict);
+    }
+
+    /// Seal every chunk but the last, which is still being written to, encoding their events
+    /// with the provided [`SealOptions`] to reduce their size in memory
+    pub fn seal(&mut self, options: SealOptions) {
+        let sealable = self.chunks.len().saturating_sub(1);
+        for chunk in &mut self.chunks[..sealable] {
+            chunk.seal(options);
+        }
+    }
+
+    /// The total memory used by the current state, including any spare capacity, see
+    /// [`Procession::memory_usage`] for a breakdown
     pub fn memory_size(&self) -> usize {
-        use std::{collections::HashSet, mem::size_of};
-        let mut shared_string_set = HashSet::new();
-        let labels_size = self
-            .labels
-            .0
-            .keys()
-            .map(|k| {
-                let k_size = if shared_string_set.insert(k.name()) {
-                    k.name().len()
-                } else {
-                    0
-                } + size_of::<Key>();
-                let l_size = k.labels().fold(0, |acc, l| {
-                    let l_size = if shared_string_set.insert(l.key()) {
-                        l.key().len()
-                    } else {
-                        0
-                    } + size_of::<Label>();
-                    let v_size = if shared_string_set.insert(l.value()) {
-                        l.value().len()
-                    } else {
-                        0
-                    } + size_of::<Label>();
-                    acc + l_size + v_size
-                });
-                k_size + l_size + size_of::<u16>()
-            })
-            .sum::<usize>();
-        let chunk_size = self.chunks.iter().map(|c| c.memory_size()).sum::<usize>();
-        labels_size + chunk_size + size_of::<Self>()
+        size_of::<Self>() + self.memory_usage().total()
     }
 
-    /// Insert a new entry into the last (or newly last) [`Chunk`]
-    pub fn insert_entry(&mut self, entry: Entry, label: u16) {
-        let now = OffsetDateTime::now_utc();
+    /// A breakdown of the heap memory held by the current state
+    pub fn memory_usage(&self) -> MemoryUsage {
+        let chunks = MemoryUsage {
+            chunks: self.chunks.capacity() * size_of::<Chunk>(),
+            ..Default::default()
+        };
+        chunks
+            + self.chunks.iter().map(Chunk::memory_usage).sum()
+            + self.labels.memory_usage()
+            + self.descriptions.memory_usage()
+    }
+
+    /// Insert a new entry into the last (or newly last) [`Chunk`] timestamped by this
+    /// series' [`Clock`]
+    pub fn insert_entry(&mut self, entry: Entry, label: LabelId) {
+        let now = self.clock.now();
         let (last, ms) = self.last_chunk_and_ms(now);
-        last.push(Event { entry, ms, label });
+        last.push_entry(ms, label, entry);
+    }
+
+    /// Insert a new entry that happened at the provided time, placing it in the [`Chunk`]
+    /// covering that time after any existing events from the same millisecond. Entries newer
+    /// than the last chunk are handled like [`Procession::insert_entry`], older entries are
+    /// placed into the chunk whose window contains them or a new chunk is started at `when`
+    pub fn insert_entry_at(&mut self, when: OffsetDateTime, entry: Entry, label: LabelId) {
+        let window = Duration::milliseconds(i64::from(u16::MAX));
+        // the first chunk starting after `when`
+        let index = self.chunks.partition_point(|c| c.reference_time <= when);
+        if index == self.chunks.len() {
+            let (last, ms) = self.last_chunk_and_ms(when);
+            last.insert_entry(ms, label, entry);
+            return;
+        }
+        if let Some(previous) = index.checked_sub(1)
+            && when - self.chunks[previous].reference_time <= window
+        {
+            let chunk = &self.chunks[previous];
+            let ms = (when - chunk.reference_time).whole_milliseconds() as u16;
+            let mut entry = entry;
+            // a rollup keeps absolute gauge values, a change is applied to the value before it
+            if let (Some(rollup), Entry::Gauge { value, op }) = (chunk.rollup(), entry)
+                && op != Op::Set
+            {
+                let before = rollup
+                    .gauge_before(ms, label)
+                    .unwrap_or_else(|| self.gauge_before(previous, label));
+                entry = Entry::Gauge {
+                    value: rollup::apply(before, value, op),
+                    op: Op::Set,
+                };
+            }
+            self.chunks[previous].insert_entry(ms, label, entry);
+            return;
+        }
+        // this is never the last chunk, it's left open for any other late entries and sealed on
+        // the next rollover
+        let mut chunk = Chunk::new(when);
+        chunk.push_entry(0, label, entry);
+        self.chunks.insert(index, chunk);
+        self.enforce_retention(self.clock.now());
+    }
+
+    /// Move every event from `other` into this series, the label identifiers from `other` are
+    /// remapped through [`LabelSet::ensure_key`] and the chunks of both series are interleaved
+    /// by their `reference_time`. A chunk that doesn't overlap any other is moved as is,
+    /// overlapping chunks are re-chunked so the result iterates in time order. Rolled up
+    /// chunks are merged bucket by bucket and any overlapping events are rolled up with them.
+    /// Events whose key is rejected by the [`crate::label_set::OverflowPolicy`] are dropped and
+    /// descriptions already present in this series are kept
+    pub fn merge(&mut self, other: Procession) {
+        let Procession {
+            chunks,
+            labels,
+            descriptions,
+            ..
+        } = other;
+        for (name, description) in descriptions.0 {
+            self.descriptions.0.entry(name).or_insert(description);
+        }
+        let mut remap = BTreeMap::new();
+        let mut remap = |label: LabelId| {
+            *remap.entry(label).or_insert_with(|| {
+                labels
+                    .key(label)
+                    .and_then(|key| self.labels.ensure_key(key))
+            })
+        };
+        let mut theirs = chunks
+            .iter()
+            .map(|chunk| relabel(chunk, &mut remap))
+            .peekable();
+        let mut mine = std::mem::take(&mut self.chunks).into_iter().peekable();
+        let window = Duration::milliseconds(i64::from(u16::MAX));
+        let mut group: Vec<Chunk> = Vec::new();
+        let mut group_end = OffsetDateTime::UNIX_EPOCH;
+        loop {
+            // chunks from this series go first when both start at the same time
+            let next = match (mine.peek(), theirs.peek()) {
+                (Some(a), Some(b)) if b.reference_time < a.reference_time => theirs.next(),
+                (Some(_), _) => mine.next(),
+                (None, _) => theirs.next(),
+            };
+            if next
+                .as_ref()
+                .is_none_or(|chunk| chunk.reference_time > group_end)
+            {
+                rechunk(std::mem::take(&mut group), &mut self.chunks);
+            }
+            let Some(chunk) = next else {
+                break;
+            };
+            let end = chunk.reference_time + window;
+            group_end = if group.is_empty() {
+                end
+            } else {
+                group_end.max(end)
+            };
+            group.push(chunk);
+        }
+        if let Some(options) = self.sealing {
+            self.seal(options);
+        }
+        if let Some(last) = self.chunks.last_mut() {
+            last.unseal();
+        }
+        self.enforce_retention(self.clock.now());
+    }
+
+    /// A new series with only the events that occurred within the provided range, chunks
+    /// outside of the range are skipped without being decoded
+    pub fn slice(&self, range: impl RangeBounds<OffsetDateTime>) -> Procession {
+        self.select(&Filter::new().range(range), |_| true)
+    }
+
+    /// A new series with only the events that match the `predicate`
+    pub fn extract(&self, predicate: impl FnMut(&MetricRef) -> bool) -> Procession {
+        self.select(&Filter::new(), predicate)
+    }
+
+    /// Remove any events that do not match the `predicate`. Every key keeps its label id, so
+    /// handles already registered through a [`crate::recorder::ProcessionRecorder`] keep
+    /// recording under the same key, use [`Procession::extract`] for a series with only the
+    /// keys that are still referenced
+    pub fn retain(&mut self, predicate: impl FnMut(&MetricRef) -> bool) {
+        self.chunks = self.select_chunks(&Filter::new(), predicate, |_, label| Some(label));
+        if let Some(options) = self.sealing {
+            self.seal(options);
+        }
+        if let Some(last) = self.chunks.last_mut() {
+            last.unseal();
+        }
+    }
+
+    /// Copy the events that match both the `filter` and the `predicate` into a new series with
+    /// the same settings. Each chunk keeps its reference time while the [`LabelSet`] and
+    /// [`Descriptions`] only include the keys that are still referenced
+    fn select(&self, filter: &Filter, predicate: impl FnMut(&MetricRef) -> bool) -> Procession {
+        let mut labels = LabelSet::default().with_overflow_policy(self.labels.overflow_policy());
+        let mut descriptions = Descriptions::default();
+        let mut remap = BTreeMap::new();
+        let chunks = self.select_chunks(filter, predicate, |metric, label| {
+            *remap.entry(label).or_insert_with(|| {
+                if let Some(description) = metric.description {
+                    let name = KeyName::from(metric.key.name().to_string());
+                    descriptions.insert(name, description.clone());
+                }
+                labels.ensure_key(metric.key)
+            })
+        });
+        let mut ret = Procession {
+            chunks,
+            labels,
+            descriptions,
+            retention: self.retention,
+            sealing: self.sealing,
+            rollup: self.rollup,
+            clock: self.clock.clone(),
+            ..Default::default()
+        };
+        if let Some(options) = ret.sealing {
+            ret.seal(options);
+        }
+        ret
+    }
+
+    /// The chunks holding only the events that match both the `filter` and the `predicate`,
+    /// each kept event is given the label returned by `relabel`, or dropped if it returns `None`
+    fn select_chunks(
+        &self,
+        filter: &Filter,
+        mut predicate: impl FnMut(&MetricRef) -> bool,
+        mut relabel: impl FnMut(&MetricRef, LabelId) -> Option<LabelId>,
+    ) -> Vec<Chunk> {
+        let mut ret = Vec::new();
+        // the label for an event that should be kept
+        let mut keep = |metric: &MetricRef, label: LabelId| {
+            if !filter.matches(label, metric.when) || !predicate(metric) {
+                return None;
+            }
+            relabel(metric, label)
+        };
+        for chunk in self.chunks.iter().filter(|c| filter.may_match(c)) {
+            let at = |ms: u16| chunk.reference_time + Duration::milliseconds(i64::from(ms));
+            if let Some(rollup) = chunk.rollup() {
+                let mut selected = Rollup::new(rollup.step_ms);
+                for bucket in &rollup.buckets {
+                    let Some(key) = self.labels.key(bucket.label) else {
+                        continue;
+                    };
+                    let metric = MetricRef {
+                        when: at(bucket.ms),
+                        event: bucket.aggregate.entry(),
+                        key,
+                        description: self.descriptions.get(key.name()),
+                        rollup: Some(&bucket.aggregate),
+                    };
+                    if let Some(label) = keep(&metric, bucket.label) {
+                        selected.buckets.push(Bucket {
+                            label,
+                            ..bucket.clone()
+                        });
+                    }
+                }
+                if !selected.buckets.is_empty() {
+                    ret.push(Chunk::from_rollup(chunk.reference_time, selected));
+                }
+                continue;
+            }
+            let events = chunk.events();
+            let mut selected = Events::default();
+            for event in events.iter() {
+                let (Some(key), Some(entry)) = (self.labels.key(event.label), events.entry(event))
+                else {
+                    continue;
+                };
+                let metric = MetricRef {
+                    when: at(event.ms),
+                    event: entry,
+                    key,
+                    description: self.descriptions.get(key.name()),
+                    rollup: None,
+                };
+                if let Some(label) = keep(&metric, event.label) {
+                    let entry = selected.raw_entry(entry);
+                    selected.push(Event {
+                        entry,
+                        ms: event.ms,
+                        label,
+                    });
+                }
+            }
+            if !selected.is_empty() {
+                ret.push(Chunk::from_events(chunk.reference_time, selected));
+            }
+        }
+        ret
+    }
+
+    /// Replace every chunk whose events all occurred before `before` with a [`Rollup`] of
+    /// `step` sized buckets, see [`crate::rollup`]. The last chunk is never rolled up since it
+    /// is the one currently being written to and chunks that were already rolled up are kept
+    pub fn roll_up(&mut self, before: OffsetDateTime, step: Duration) {
+        let step_ms = rollup::step_ms(step);
+        let rollable = self.chunks.len().saturating_sub(1);
+        // the value of each gauge at the end of the previous chunk
+        let mut gauges = BTreeMap::new();
+        for index in 0..rollable {
+            let chunk = &self.chunks[index];
+            if chunk.is_rolled_up() {
+                continue;
+            }
+            if chunk.reference_time + Duration::milliseconds(i64::from(chunk.last_ms())) >= before {
+                break;
+            }
+            let events = chunk.events();
+            for event in events.iter() {
+                if let RawEntry::Gauge {
+                    op: Op::Add | Op::Sub,
+                    ..
+                } = event.entry
+                    && !gauges.contains_key(&event.label)
+                {
+                    gauges.insert(event.label, self.gauge_before(index, event.label));
+                }
+            }
+            let rollup = Rollup::from_events(&events, step_ms, &mut gauges);
+            let reference_time = chunk.reference_time;
+            drop(events);
+            self.chunks[index] = Chunk::from_rollup(reference_time, rollup);
+        }
+    }
+
+    /// The absolute value of the gauge for `label` at the end of the chunks before `index`,
+    /// found from the last [`Op::Set`] or rolled up bucket plus any changes after it. A gauge
+    /// that was never set starts from zero
+    fn gauge_before(&self, index: usize, label: LabelId) -> Float {
+        let mut changes = Vec::new();
+        let mut base = None;
+        'chunks: for chunk in self.chunks[..index].iter().rev() {
+            if let Some(rollup) = chunk.rollup() {
+                base = rollup.gauge_before(u16::MAX, label);
+                if base.is_some() {
+                    break;
+                }
+                continue;
+            }
+            let events = chunk.events();
+            for event in events.iter().rev().filter(|e| e.label == label) {
+                match event.entry {
+                    RawEntry::Gauge { value, op: Op::Set } => {
+                        base = Some(value);
+                        break 'chunks;
+                    }
+                    RawEntry::Gauge { value, op } => changes.push((value, op)),
+                    _ => {}
+                }
+            }
+        }
+        changes
+            .into_iter()
+            .rev()
+            .fold(base.unwrap_or_default(), |previous, (value, op)| {
+                rollup::apply(previous, value, op)
+            })
     }
 
     /// Find the last chunk in this [Procession] along with the number of milliseconds
     /// since the reference time on that chunk. If either there are no chunks already
     /// available _or_ the number of milliseconds since the last chunk's reference time
     /// would exceed [u16::MAX] a new chunk is added and a mutable reference to that chunk
-    /// is returned with a ms value of 0
+    /// is returned with a ms value of 0, adding a chunk will also seal the previous last chunk
+    /// along with any older chunk left open by late entries and evict any chunks that exceed
+    /// the [`Retention`] limits
     pub fn last_chunk_and_ms(&mut self, now: OffsetDateTime) -> (&mut Chunk, u16) {
         if self.chunks.is_empty() {
-            self.chunks.push(Chunk::default());
+            self.chunks.push(Chunk::new(now));
         }
         let mut duration = self
             .chunks
             .last()
-            .map(|c| (now - c.reference_time))
+            .map(|c| now - c.reference_time)
             .unwrap_or_default();
         if duration > Duration::milliseconds(i64::from(u16::MAX)) {
+            if let Some(options) = self.sealing {
+                for chunk in self.chunks.iter_mut().filter(|c| !c.is_sealed()) {
+                    chunk.seal(options);
+                }
+            }
             self.chunks.push(Chunk::new(now));
+            self.enforce_retention(now);
+            self.enforce_rollup(now);
             duration = Duration::ZERO;
         }
-        let ms = u16::try_from(duration.whole_milliseconds()).unwrap_or(u16::MAX);
+        // events slightly older than the reference time are clamped to the start of the chunk
+        let ms = duration.whole_milliseconds().clamp(0, i128::from(u16::MAX)) as u16;
         (self.chunks.last_mut().unwrap(), ms)
     }
 
-    /// Ensure the provided key is in the [`labels`]
-    pub fn ensure_label(&mut self, k: &Key) -> u16 {
+    /// Record the unit and help text for a metric name, replacing any previous description
+    pub fn describe(&mut self, name: KeyName, unit: Option<Unit>, text: SharedString) {
+        self.descriptions.insert(
+            name,
+            Description {
+                unit,
+                text: text.into_owned(),
+            },
+        );
+    }
+
+    /// Ensure the provided key is in the [`labels`], returning `None` if the key was rejected
+    /// by the [`crate::label_set::OverflowPolicy`]
+    pub fn ensure_label(&mut self, k: &Key) -> Option<LabelId> {
         self.labels.ensure_key(k)
     }
 
     /// create an iterator for the raw metric events currently recorded that will be tied to the
     /// lifetime of this instance of the [`Procession`]
-    pub fn iter(&self) -> MetricsRefIterator {
+    pub fn iter(&self) -> MetricsRefIterator<'_> {
         MetricsRefIterator::from(self)
     }
 
     /// create an iterator for the raw metric events currently recorded providing owned
     /// version of all events
-    pub fn iter_owned(&self) -> MetricsIterator {
+    pub fn iter_owned(&self) -> MetricsIterator<'_> {
         self.iter().into()
     }
+
+    /// create an iterator for the raw metric events that match the provided [`Filter`], sealed
+    /// chunks that cannot match are skipped without being decoded
+    pub fn iter_filtered(&self, filter: Filter) -> MetricsRefIterator<'_> {
+        self.iter().with_filter(filter)
+    }
+
+    /// The chunks with at least one event for the provided key along with their position in
+    /// [`Procession::chunks`], this will not decode any sealed chunks
+    pub fn chunks_containing(&self, key: &Key) -> impl Iterator<Item = (usize, &Chunk)> {
+        let label = self.labels.get(key);
+        self.chunks
+            .iter()
+            .enumerate()
+            .filter(move |(_, chunk)| label.is_some_and(|label| chunk.contains_label(label)))
+    }
 }
 
 impl FromIterator<Metric> for Procession {
     fn from_iter<T: IntoIterator<Item = Metric>>(iter: T) -> Self {
-        let mut iter = iter.into_iter().peekable();
         let mut ret = Self::default();
-        if let Some(first) = iter.peek() {
-            let start = first.when;
-            ret.chunks.push(Chunk::new(start));
-        }
         for event in iter {
+            if let Some(description) = event.description {
+                ret.descriptions
+                    .insert(KeyName::from(event.key.clone()), description);
+            }
             let labels = event
                 .labels
                 .into_iter()
                 .map(|(k, v)| Label::new(k, v))
                 .collect::<Vec<_>>();
-            let label = ret.ensure_label(&Key::from_parts(event.key, labels));
-            ret.insert_entry(event.event, label);
+            let Some(label) = ret.ensure_label(&Key::from_parts(event.key, labels)) else {
+                continue;
+            };
+            ret.insert_entry_at(event.when, event.event, label);
         }
         ret
     }
@@ -125,16 +599,699 @@ impl FromIterator<Metric> for Procession {
 
 impl<'a> FromIterator<MetricRef<'a>> for Procession {
     fn from_iter<T: IntoIterator<Item = MetricRef<'a>>>(iter: T) -> Self {
-        let mut iter = iter.into_iter().peekable();
         let mut ret = Self::default();
-        if let Some(first) = iter.peek() {
-            let start = first.when;
-            ret.chunks.push(Chunk::new(start));
-        }
         for event in iter {
-            let label = ret.ensure_label(event.key);
-            ret.insert_entry(event.event, label);
+            if let Some(description) = event.description {
+                ret.descriptions.insert(
+                    KeyName::from(event.key.name().to_string()),
+                    description.clone(),
+                );
+            }
+            let Some(label) = ret.ensure_label(event.key) else {
+                continue;
+            };
+            ret.insert_entry_at(event.when, event.event, label);
         }
         ret
     }
 }
+
+/// A copy of the provided chunk with its label identifiers changed by `remap`, events and
+/// buckets whose label isn't remapped are dropped
+fn relabel(chunk: &Chunk, mut remap: impl FnMut(LabelId) -> Option<LabelId>) -> Chunk {
+    if let Some(rollup) = chunk.rollup() {
+        let buckets = rollup
+            .buckets
+            .iter()
+            .filter_map(|bucket| {
+                Some(Bucket {
+                    label: remap(bucket.label)?,
+                    ..bucket.clone()
+                })
+            })
+            .collect();
+        let rollup = Rollup {
+            buckets,
+            ..Rollup::new(rollup.step_ms)
+        };
+        return Chunk::from_rollup(chunk.reference_time, rollup);
+    }
+    let events = chunk.events();
+    let relabeled = Events {
+        events: events
+            .iter()
+            .filter_map(|event| {
+                Some(Event {
+                    label: remap(event.label)?,
+                    ..*event
+                })
+            })
+            .collect(),
+        wide_values: events.wide_values.clone(),
+    };
+    Chunk::from_events(chunk.reference_time, relabeled)
+}
+
+/// Append the chunks of `group` to `out`, the windows of these chunks overlap so their events
+/// are combined in time order into new chunks. A group with a rolled up chunk is rolled up
+/// with the largest step in the group, a single chunk is appended as is
+fn rechunk(group: Vec<Chunk>, out: &mut Vec<Chunk>) {
+    if group.len() < 2 {
+        out.extend(group);
+        return;
+    }
+    let window = Duration::milliseconds(i64::from(u16::MAX));
+    let at = |chunk: &Chunk, ms: u16| chunk.reference_time + Duration::milliseconds(i64::from(ms));
+    let step_ms = group
+        .iter()
+        .filter_map(|c| c.rollup())
+        .map(|r| r.step_ms)
+        .max();
+    let Some(step_ms) = step_ms else {
+        let mut events = Vec::new();
+        for chunk in &group {
+            let chunk_events = chunk.events();
+            events.extend(chunk_events.iter().filter_map(|event| {
+                Some((at(chunk, event.ms), event.label, chunk_events.entry(event)?))
+            }));
+        }
+        // a stable sort keeps events from the same millisecond in their original order
+        events.sort_by_key(|(when, ..)| *when);
+        let mut current: Option<Chunk> = None;
+        for (when, label, entry) in events {
+            let chunk = match current.take() {
+                Some(chunk) if when - chunk.reference_time <= window => current.insert(chunk),
+                previous => {
+                    out.extend(previous);
+                    current.insert(Chunk::new(when))
+                }
+            };
+            let ms = (when - chunk.reference_time).whole_milliseconds() as u16;
+            chunk.push_entry(ms, label, entry);
+        }
+        out.extend(current);
+        return;
+    };
+    // the value of each gauge at the end of the chunks before, for the events rolled up here
+    let mut gauges = BTreeMap::new();
+    let mut buckets = Vec::new();
+    for chunk in &group {
+        let rollup = match chunk.rollup() {
+            Some(rollup) => rollup.clone(),
+            None => Rollup::from_events(&chunk.events(), step_ms, &mut gauges),
+        };
+        for bucket in rollup.buckets {
+            if let Aggregate::Gauge { last, .. } = bucket.aggregate {
+                gauges.insert(bucket.label, last);
+            }
+            buckets.push((at(chunk, bucket.ms), bucket.label, bucket.aggregate));
+        }
+    }
+    buckets.sort_by_key(|(when, ..)| *when);
+    let mut current: Option<(OffsetDateTime, Rollup)> = None;
+    for (when, label, aggregate) in buckets {
+        let (reference_time, rollup) = match current.take() {
+            Some((reference_time, rollup)) if when - reference_time <= window => {
+                current.insert((reference_time, rollup))
+            }
+            previous => {
+                out.extend(previous.map(|(at, rollup)| Chunk::from_rollup(at, rollup)));
+                current.insert((when, Rollup::new(step_ms)))
+            }
+        };
+        let ms = (when - *reference_time).whole_milliseconds() as u16;
+        rollup.insert_aggregate(ms, label, aggregate);
+    }
+    out.extend(current.map(|(at, rollup)| Chunk::from_rollup(at, rollup)));
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{clock::ManualClock, event::Op, recorder::ProcessionRecorder, test_util::start};
+
+    use super::*;
+
+    fn counter(value: u64) -> Entry {
+        Entry::Counter { value, op: Op::Add }
+    }
+
+    #[test]
+    fn insert_entry_at_orders_events() {
+        let mut procession = Procession::default();
+        // seconds after `start` for each event, deliberately out of order
+        let offsets = [120, 5, 0, 300, 6, 70, 5, 119];
+        for (value, offset) in offsets.into_iter().enumerate() {
+            procession.insert_entry_at(
+                start() + Duration::seconds(offset),
+                counter(value as u64),
+                0,
+            );
+        }
+        let mut expected: Vec<_> = offsets.into_iter().enumerate().collect();
+        // a stable sort keeps events from the same millisecond in insertion order
+        expected.sort_by_key(|(_, offset)| *offset);
+        let actual: Vec<_> = procession.iter().map(|m| (m.when, m.event)).collect();
+        let expected: Vec<_> = expected
+            .into_iter()
+            .map(|(value, offset)| (start() + Duration::seconds(offset), counter(value as u64)))
+            .collect();
+        assert_eq!(actual, expected);
+        // events before the first chunk, or beyond the window of the chunk before them, start
+        // a new chunk
+        let reference_times: Vec<_> = procession.chunks.iter().map(|c| c.reference_time).collect();
+        assert_eq!(
+            reference_times,
+            [0, 5, 119, 120, 300]
+                .map(|offset| start() + Duration::seconds(offset))
+                .to_vec()
+        );
+    }
+
+    #[test]
+    fn sealed_chunks_iterate_the_same() {
+        let mut procession = Procession::default();
+        for i in 0..1000 {
+            let entry = Entry::Gauge {
+                value: (i / 10) as _,
+                op: Op::Set,
+            };
+            procession.insert_entry_at(start() + Duration::milliseconds(i * 500), entry, 0);
+        }
+        let open = procession.clone();
+        procession.seal(SealOptions::default());
+        let (last, sealed) = procession.chunks.split_last().unwrap();
+        assert!(!last.is_sealed());
+        assert!(sealed.iter().all(|c| c.is_sealed()));
+        assert!(procession.memory_size() * 2 < open.memory_size());
+        assert_eq!(procession, open);
+        let metrics: Vec<_> = procession.iter().map(|m| (m.when, m.event)).collect();
+        let expected: Vec<_> = open.iter().map(|m| (m.when, m.event)).collect();
+        assert_eq!(metrics, expected);
+        let json = serde_json::to_string(&procession).unwrap();
+        assert_eq!(json, serde_json::to_string(&open).unwrap());
+    }
+
+    #[test]
+    fn chunks_containing_uses_the_index() {
+        let mut procession = Procession::default();
+        let keys = [
+            Key::from_name("a"),
+            Key::from_name("b"),
+            Key::from_name("c"),
+        ];
+        for key in &keys {
+            procession.ensure_label(key);
+        }
+        for i in 0..600 {
+            let label = if i % 100 < 50 { 0 } else { 1 };
+            procession.insert_entry_at(start() + Duration::seconds(i), counter(1), label);
+            if i % 200 == 0 {
+                let entry = Entry::Histogram { value: 1.0 };
+                procession.insert_entry_at(start() + Duration::seconds(i), entry, 2);
+            }
+        }
+        procession.seal(SealOptions::default());
+        let index = procession.chunks[0].index();
+        assert_eq!(index.labels, [0, 1, 2]);
+        assert_eq!((index.first_ms, index.last_ms), (0, 65_000));
+        assert_eq!((index.counters, index.gauges, index.histograms), (66, 0, 1));
+        for (key, label) in keys.iter().zip(0..) {
+            let expected: Vec<_> = procession
+                .chunks
+                .iter()
+                .enumerate()
+                .filter(|(_, c)| c.events().iter().any(|e| e.label == label))
+                .map(|(i, _)| i)
+                .collect();
+            let actual: Vec<_> = procession.chunks_containing(key).map(|(i, _)| i).collect();
+            assert_eq!(actual, expected);
+        }
+        assert_eq!(procession.chunks_containing(&keys[2]).count(), 3);
+        assert_eq!(
+            procession
+                .chunks_containing(&Key::from_name("missing"))
+                .count(),
+            0
+        );
+    }
+
+    #[test]
+    fn merge_remaps_labels() {
+        let shared = Key::from_name("shared");
+        let mut left = Procession::default().with_sealing(Some(SealOptions::default()));
+        let mut right = Procession::default();
+        let left_only = left.ensure_label(&Key::from_name("left")).unwrap();
+        let left_shared = left.ensure_label(&shared).unwrap();
+        let right_shared = right.ensure_label(&shared).unwrap();
+        let right_only = right.ensure_label(&Key::from_name("right")).unwrap();
+        assert_eq!((left_only, right_shared), (0, 0));
+        for i in 0..400 {
+            let label = if i % 2 == 0 { left_only } else { left_shared };
+            left.insert_entry_at(start() + Duration::seconds(i * 3), counter(1), label);
+            let label = if i % 2 == 0 { right_shared } else { right_only };
+            // offset by 500ms so every event has its own timestamp
+            let when = start() + Duration::seconds(i * 2) + Duration::milliseconds(500);
+            right.insert_entry_at(when, counter(2), label);
+        }
+        right.describe(KeyName::from("shared"), None, "from the right".into());
+        let mut expected: Vec<_> = left
+            .iter()
+            .chain(right.iter())
+            .map(|m| (m.when, m.key.clone(), m.event))
+            .collect();
+        expected.sort_by_key(|(when, ..)| *when);
+        left.merge(right);
+        let (last, sealed) = left.chunks.split_last().unwrap();
+        assert!(!last.is_sealed());
+        assert!(sealed.iter().all(|c| c.is_sealed()));
+        let actual: Vec<_> = left
+            .iter()
+            .map(|m| (m.when, m.key.clone(), m.event))
+            .collect();
+        assert_eq!(actual, expected);
+        assert_eq!(left.labels.len(), 3);
+        assert_eq!(
+            left.descriptions.get("shared").unwrap().text,
+            "from the right"
+        );
+    }
+
+    fn http_and_db() -> Procession {
+        let mut procession = Procession::default().with_sealing(Some(SealOptions::default()));
+        let keys = [
+            Key::from_name("db_queries"),
+            Key::from_parts("http_requests", vec![Label::new("status", "200")]),
+            Key::from_parts("http_requests", vec![Label::new("status", "500")]),
+        ];
+        let labels: Vec<_> = keys
+            .iter()
+            .map(|k| procession.ensure_label(k).unwrap())
+            .collect();
+        procession.describe(KeyName::from("db_queries"), None, "queries".into());
+        procession.describe(KeyName::from("http_requests"), None, "requests".into());
+        for i in 0..900 {
+            let when = start() + Duration::seconds(i);
+            procession.insert_entry_at(when, counter(i as u64), labels[i as usize % 3]);
+        }
+        procession
+    }
+
+    #[test]
+    fn slice_trims_chunks() {
+        let procession = http_and_db();
+        let range = start() + Duration::seconds(100)..start() + Duration::seconds(400);
+        let slice = procession.slice(range.clone());
+        let expected: Vec<_> = procession
+            .iter()
+            .filter(|m| range.contains(&m.when))
+            .map(|m| (m.when, m.key.clone(), m.event))
+            .collect();
+        let actual: Vec<_> = slice
+            .iter()
+            .map(|m| (m.when, m.key.clone(), m.event))
+            .collect();
+        assert_eq!(actual.len(), 300);
+        assert_eq!(actual, expected);
+        assert_eq!(slice.chunks.len(), 6);
+        assert!(slice.chunks[0].reference_time <= range.start);
+        assert_eq!(slice.labels.len(), 3);
+        // the slice can be shared on its own
+        let json = serde_json::to_string(&slice).unwrap();
+        assert_eq!(serde_json::from_str::<Procession>(&json).unwrap(), slice);
+    }
+
+    #[test]
+    fn extract_and_retain_compact_labels() {
+        let mut procession = http_and_db();
+        let errors = procession.extract(|m| m.key.labels().any(|l| l.value() == "500"));
+        assert_eq!(errors.labels.len(), 1);
+        assert_eq!(
+            errors
+                .labels
+                .get(&procession.labels.key(2).unwrap().clone()),
+            Some(0)
+        );
+        assert_eq!(errors.iter().count(), 300);
+        assert_eq!(errors.descriptions.0.len(), 1);
+        assert!(
+            errors
+                .iter()
+                .all(|m| m.description.unwrap().text == "requests")
+        );
+        let before = procession.iter().count();
+        procession.retain(|m| m.key.name().starts_with("http_"));
+        assert_eq!(procession.iter().count(), before - 300);
+        // the keys keep their ids even when none of their events are left
+        assert_eq!(procession.labels.len(), 3);
+        assert_eq!(
+            procession.labels.get(&Key::from_name("db_queries")),
+            Some(0)
+        );
+        assert!(procession.descriptions.get("db_queries").is_some());
+        assert!(procession.iter().all(|m| m.key.name() == "http_requests"));
+        let (last, sealed) = procession.chunks.split_last().unwrap();
+        assert!(!last.is_sealed());
+        assert!(sealed.iter().all(|c| c.is_sealed()));
+    }
+
+    #[test]
+    fn handles_record_after_retain() {
+        let recorder = ProcessionRecorder::default();
+        metrics::with_local_recorder(&recorder, || {
+            let db = metrics::counter!("db_queries");
+            let http = metrics::counter!("http_requests");
+            db.increment(1);
+            http.increment(2);
+            recorder.lock().retain(|m| m.key.name() == "http_requests");
+            db.increment(3);
+            http.increment(4);
+        });
+        let recorded: Vec<_> = recorder
+            .lock()
+            .iter()
+            .map(|m| (m.key.name().to_string(), m.event))
+            .collect();
+        assert_eq!(
+            recorded,
+            [
+                ("http_requests".to_string(), counter(2)),
+                ("db_queries".to_string(), counter(3)),
+                ("http_requests".to_string(), counter(4)),
+            ]
+        );
+    }
+
+    #[test]
+    fn rolled_up_gauges_keep_their_value() {
+        let gauge = |value, op| Entry::Gauge { value, op };
+        let at = |seconds| start() + Duration::seconds(seconds);
+        let mut procession = Procession::default();
+        procession.insert_entry_at(at(0), gauge(100.0, Op::Set), 0);
+        procession.insert_entry_at(at(70), gauge(5.0, Op::Add), 0);
+        procession.insert_entry_at(at(140), Entry::Histogram { value: 1.0 }, 1);
+        procession.insert_entry_at(at(300), gauge(1.0, Op::Sub), 0);
+        procession.roll_up(at(250), Duration::seconds(10));
+        assert_eq!(procession.chunks.len(), 4);
+        assert!(procession.chunks[..3].iter().all(|c| c.is_rolled_up()));
+        // the third chunk has no bucket for the gauge, the change applies to the earlier value
+        procession.insert_entry_at(at(150), gauge(5.0, Op::Add), 0);
+        let gauges: Vec<_> = procession
+            .iter()
+            .filter_map(|m| match m.event {
+                Entry::Gauge { value, op } => Some((value, op)),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(
+            gauges,
+            [
+                (100.0, Op::Set),
+                (105.0, Op::Set),
+                (110.0, Op::Set),
+                (1.0, Op::Sub)
+            ]
+        );
+    }
+
+    #[test]
+    fn rollups_replace_old_chunks() {
+        let clock = Arc::new(ManualClock::new(start()));
+        let policy = RollupPolicy::new(Duration::minutes(5), Duration::seconds(10));
+        let mut procession = Procession::default()
+            .with_clock(clock.clone())
+            .with_rollup(Some(policy));
+        let mut raw = Procession::default();
+        let keys = [
+            Key::from_name("requests"),
+            Key::from_name("in_flight"),
+            Key::from_name("latency"),
+        ];
+        for key in &keys {
+            procession.ensure_label(key);
+            raw.ensure_label(key);
+        }
+        for i in 0..12_000 {
+            let entries = [
+                counter(1),
+                Entry::Gauge {
+                    value: 1.0,
+                    op: if i % 4 == 0 { Op::Sub } else { Op::Add },
+                },
+                Entry::Histogram {
+                    value: (i % 100) as _,
+                },
+            ];
+            for (label, entry) in entries.into_iter().enumerate() {
+                procession.insert_entry(entry, label as LabelId);
+                raw.insert_entry_at(clock.now(), entry, label as LabelId);
+            }
+            clock.advance(Duration::milliseconds(100));
+        }
+        // 20 minutes of events, anything older than 5 minutes has been rolled up
+        let cutoff = clock.now() - Duration::minutes(5);
+        assert!(procession.chunks.iter().any(|c| c.is_rolled_up()));
+        for chunk in &procession.chunks {
+            let (_, last) = chunk.time_range().unwrap();
+            // chunks are rolled up when a new chunk is started, up to a chunk after the cutoff
+            if chunk.is_rolled_up() {
+                assert!(last < cutoff);
+            } else {
+                assert!(last + Duration::seconds(66) >= cutoff);
+            }
+        }
+        let rolled_up = procession
+            .chunks
+            .iter()
+            .filter(|c| c.is_rolled_up())
+            .count();
+        let size = |p: &Procession| {
+            p.chunks[..rolled_up]
+                .iter()
+                .map(|c| c.memory_size())
+                .sum::<usize>()
+        };
+        assert!(size(&procession) * 4 < size(&raw));
+        // every rolled up counter sums to the same total
+        let total = |p: &Procession| {
+            p.iter()
+                .filter_map(|m| match m.event {
+                    Entry::Counter { value, .. } => Some(value),
+                    _ => None,
+                })
+                .sum::<u64>()
+        };
+        assert_eq!(total(&procession), 12_000);
+        let rolled_up = procession
+            .chunks
+            .iter()
+            .filter(|c| c.is_rolled_up())
+            .count();
+        let rolled_up_end = procession.chunks[rolled_up].reference_time;
+        let gauges: Vec<_> = procession
+            .iter()
+            .filter(|m| m.when < rolled_up_end && m.key.name() == "in_flight")
+            .collect();
+        assert!(gauges.iter().all(|m| m.rollup.is_some()));
+        assert!(
+            gauges
+                .windows(2)
+                .all(|w| w[1].when - w[0].when <= Duration::seconds(10))
+        );
+        // the rolled up gauge matches the raw gauge at the end of each step
+        let mut raw_gauge = 0.0;
+        let mut raw_values = raw
+            .iter()
+            .filter(|m| m.key.name() == "in_flight")
+            .map(|m| {
+                let Entry::Gauge { value, op } = m.event else {
+                    unreachable!()
+                };
+                raw_gauge = match op {
+                    Op::Add => raw_gauge + value,
+                    Op::Sub => raw_gauge - value,
+                    Op::Set => value,
+                };
+                (m.when, raw_gauge)
+            })
+            .peekable();
+        for (index, gauge) in gauges.iter().enumerate() {
+            // the last step of a chunk ends with the chunk
+            let step_end = gauges
+                .get(index + 1)
+                .map_or(rolled_up_end, |next| next.when)
+                .min(gauge.when + Duration::seconds(10));
+            let mut last = None;
+            while let Some((_, value)) = raw_values.next_if(|(when, _)| *when < step_end) {
+                last = Some(value);
+            }
+            assert_eq!(
+                gauge.event,
+                Entry::Gauge {
+                    value: last.unwrap(),
+                    op: Op::Set
+                }
+            );
+        }
+        let Some(Aggregate::Histogram(sketch)) = procession
+            .iter()
+            .find(|m| m.key.name() == "latency")
+            .and_then(|m| m.rollup)
+        else {
+            panic!("expected a rolled up histogram");
+        };
+        assert_eq!(sketch.count, 100);
+        assert_eq!(sketch.quantile(1.0), Some(99.0));
+        // rolled up chunks survive serialization, slicing and merging
+        let json = serde_json::to_string(&procession).unwrap();
+        assert_eq!(
+            serde_json::from_str::<Procession>(&json).unwrap(),
+            procession
+        );
+        let bytes = postcard::to_stdvec(&procession).unwrap();
+        assert_eq!(
+            postcard::from_bytes::<Procession>(&bytes).unwrap(),
+            procession
+        );
+        let slice = procession.slice(..rolled_up_end);
+        assert_eq!(slice.chunks.len(), rolled_up);
+        assert!(slice.chunks.iter().all(|c| c.is_rolled_up()));
+        let mut merged = Procession::default();
+        merged.merge(procession.clone());
+        assert_eq!(total(&merged), 12_000);
+        assert_eq!(merged.chunks, procession.chunks);
+        // overlapping rollups are merged bucket by bucket, keeping their sketches
+        let mut doubled = procession.clone();
+        doubled.merge(procession.clone());
+        assert_eq!(total(&doubled), 24_000);
+        let rollups = |p: &Procession| -> Vec<Rollup> {
+            p.chunks
+                .iter()
+                .filter_map(|c| c.rollup())
+                .cloned()
+                .collect()
+        };
+        let (original, doubled) = (rollups(&procession), rollups(&doubled));
+        assert_eq!(original.len(), doubled.len());
+        for (original, doubled) in original.iter().zip(&doubled) {
+            assert_eq!(original.buckets.len(), doubled.buckets.len());
+            for (original, doubled) in original.buckets.iter().zip(&doubled.buckets) {
+                assert_eq!((original.ms, original.label), (doubled.ms, doubled.label));
+                match (&original.aggregate, &doubled.aggregate) {
+                    (Aggregate::Histogram(original), Aggregate::Histogram(doubled)) => {
+                        assert_eq!(doubled.count, original.count * 2);
+                        assert_eq!(doubled.quantile(0.5), original.quantile(0.5));
+                    }
+                    (Aggregate::Gauge { .. }, gauge) => assert_eq!(gauge, &original.aggregate),
+                    (Aggregate::Counter { value, .. }, Aggregate::Counter { value: v, .. }) => {
+                        assert_eq!(*v, value * 2)
+                    }
+                    _ => panic!("aggregates changed type"),
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn rollover_seals_the_last_chunk() {
+        let clock = Arc::new(ManualClock::new(start()));
+        let mut procession = Procession::default()
+            .with_clock(clock.clone())
+            .with_sealing(Some(SealOptions::default()));
+        for i in 0..600 {
+            let entry = Entry::Gauge {
+                value: (i % 7) as _,
+                op: Op::Set,
+            };
+            procession.insert_entry(entry, 0);
+            clock.advance(Duration::seconds(1));
+        }
+        assert_eq!(procession.chunks.len(), 10);
+        let (last, sealed) = procession.chunks.split_last().unwrap();
+        assert!(!last.is_sealed());
+        assert!(sealed.iter().all(|c| c.is_sealed()));
+        assert_eq!(procession.iter().count(), 600);
+        // entries older than the last chunk go into their chunk, leaving it open until the next
+        // rollover
+        for ms in [1500, 1700, 1900] {
+            procession.insert_entry_at(start() + Duration::milliseconds(ms), counter(1), 0);
+        }
+        let before = start() - Duration::seconds(10);
+        procession.insert_entry_at(before, counter(1), 0);
+        assert!(!procession.chunks[0].is_sealed());
+        assert!(!procession.chunks[1].is_sealed());
+        assert_eq!(procession.chunks[1].len(), 69);
+        clock.advance(Duration::seconds(60));
+        procession.insert_entry(counter(1), 0);
+        assert_eq!(procession.chunks.len(), 12);
+        let (last, sealed) = procession.chunks.split_last().unwrap();
+        assert!(!last.is_sealed());
+        assert!(sealed.iter().all(|c| c.is_sealed()));
+        assert_eq!(procession.iter().count(), 605);
+    }
+
+    #[cfg(feature = "lz4")]
+    #[test]
+    fn lz4_sealing() {
+        use crate::codec::Compression;
+
+        let clock = Arc::new(ManualClock::new(start()));
+        let options = SealOptions {
+            compression: Compression::Lz4,
+            ..Default::default()
+        };
+        let mut procession = Procession::default()
+            .with_clock(clock.clone())
+            .with_sealing(Some(options));
+        let mut open = Procession::default();
+        for i in 0..10_000 {
+            procession.insert_entry(counter(i % 3), (i % 5) as LabelId);
+            open.insert_entry_at(clock.now(), counter(i % 3), (i % 5) as LabelId);
+            clock.advance(Duration::milliseconds(100));
+        }
+        assert_eq!(procession, open);
+        assert!(procession.memory_size() * 2 < open.memory_size());
+    }
+
+    #[cfg(feature = "zstd")]
+    #[test]
+    fn zstd_sealing() {
+        use crate::codec::Compression;
+
+        let mut procession = Procession::default();
+        for i in 0..10_000 {
+            let when = start() + Duration::milliseconds(i * 100);
+            procession.insert_entry_at(when, counter((i % 3) as u64), (i % 5) as LabelId);
+        }
+        let open = procession.clone();
+        procession.seal(SealOptions {
+            compression: Compression::Zstd(0),
+            ..Default::default()
+        });
+        assert_eq!(procession, open);
+        assert!(procession.memory_size() * 2 < open.memory_size());
+    }
+
+    #[test]
+    fn from_iter_keeps_timestamps() {
+        let mut procession = Procession::default();
+        for i in 0..10 {
+            procession.insert_entry_at(start() + Duration::seconds(i * 40), counter(1), 0);
+        }
+        procession.labels = [(Key::from_name("ticks"), 0)].into_iter().collect();
+        let owned: Procession = procession.iter_owned().collect();
+        assert_eq!(owned, procession);
+        let borrowed: Procession = procession.iter().collect();
+        assert_eq!(borrowed, procession);
+        // the order doesn't matter either
+        let reversed: Procession = procession
+            .iter()
+            .collect::<Vec<_>>()
+            .into_iter()
+            .rev()
+            .collect();
+        assert_eq!(
+            reversed.iter().map(|m| m.when).collect::<Vec<_>>(),
+            procession.iter().map(|m| m.when).collect::<Vec<_>>()
+        );
+    }
+}
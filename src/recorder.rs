@@ -1,57 +1,423 @@
-use std::sync::{Arc, Mutex, MutexGuard};
+use std::{
+    io,
+    sync::{
+        Arc, Mutex, MutexGuard,
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+    },
+};
 
 use metrics::{CounterFn, GaugeFn, HistogramFn, Recorder};
+use serde::Serialize;
+use time::OffsetDateTime;
 
 use crate::{
-    event::{Entry, Op},
+    clock::Clock,
+    codec::SealOptions,
+    event::{Entry, Float, Op},
+    label_set::{LabelId, OverflowPolicy},
     procession::Procession,
+    retention::Retention,
+    rollup::RollupPolicy,
+    wal::WriteAheadLog,
 };
 
+/// The number of events a single shard will buffer before every shard is merged into
+/// the [`Procession`]
+const SHARD_CAPACITY: usize = 4096;
+
+/// A [`metrics::Recorder`] that collects all events into a [`Procession`]
+///
+/// By default every event takes the lock on the shared [`Procession`], for heavily
+/// multi-threaded applications [`ProcessionRecorder::sharded`] will buffer events per-thread
+/// and only merge them into the [`Procession`] when it is locked, serialized or a shard fills up.
+///
+/// Use [`ProcessionRecorder::builder`] to configure the [`Clock`], [`Retention`], sealing,
+/// rollups, sharding or [`WriteAheadLog`] of a new recorder.
 #[derive(Debug, Clone, Default)]
-pub struct ProcessionRecorder(Arc<Mutex<Procession>>);
+pub struct ProcessionRecorder(Arc<Inner>);
+
+#[derive(Debug)]
+struct Inner {
+    procession: Mutex<Procession>,
+    /// The buffers events are recorded into when sharded, this will be empty when each
+    /// event is written directly into the `procession`
+    shards: Box<[Mutex<Vec<Pending>>]>,
+    /// The `procession`'s clock, used to timestamp events recorded into shards
+    clock: Arc<dyn Clock>,
+    /// The number of events discarded because their key was rejected under [`OverflowPolicy::Drop`]
+    dropped: AtomicU64,
+    /// The log every registration, description and event is written to, if any
+    wal: Option<Mutex<WriteAheadLog>>,
+    /// The number of records that failed to be written to the `wal`
+    log_errors: AtomicU64,
+}
+
+impl Default for Inner {
+    fn default() -> Self {
+        ProcessionRecorderBuilder::default().into_inner()
+    }
+}
+
+/// Configuration for a new [`ProcessionRecorder`]
+#[derive(Debug, Default)]
+pub struct ProcessionRecorderBuilder {
+    procession: Procession,
+    shards: usize,
+    wal: Option<WriteAheadLog>,
+}
+
+impl ProcessionRecorderBuilder {
+    /// Record into the provided [`Procession`] instead of an empty one, the recorder
+    /// will use this [`Procession`]'s [`Clock`] and [`Retention`] unless they are
+    /// replaced by this builder
+    pub fn procession(mut self, procession: Procession) -> Self {
+        self.procession = procession;
+        self
+    }
+
+    /// Buffer events into this many shards, see [`ProcessionRecorder::sharded`], a value
+    /// of 0 (the default) will write every event directly into the [`Procession`]
+    pub fn shards(mut self, shards: usize) -> Self {
+        self.shards = shards;
+        self
+    }
+
+    /// Use the provided [`Clock`] to timestamp events
+    pub fn clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.procession = self.procession.with_clock(clock);
+        self
+    }
+
+    /// Limit how much history will be kept
+    pub fn retention(mut self, retention: Retention) -> Self {
+        self.procession = self.procession.with_retention(retention);
+        self
+    }
+
+    /// Seal chunks with the provided [`SealOptions`] once a newer chunk is started, `None` will
+    /// keep every chunk open
+    pub fn sealing(mut self, sealing: Option<SealOptions>) -> Self {
+        self.procession = self.procession.with_sealing(sealing);
+        self
+    }
+
+    /// Roll up old chunks according to the provided [`RollupPolicy`], `None` will keep
+    /// every event
+    pub fn rollup(mut self, rollup: Option<RollupPolicy>) -> Self {
+        self.procession = self.procession.with_rollup(rollup);
+        self
+    }
+
+    /// Decide what to do with new keys once every label identifier has been used
+    pub fn overflow_policy(mut self, overflow_policy: OverflowPolicy) -> Self {
+        self.procession.labels.set_overflow_policy(overflow_policy);
+        self
+    }
+
+    /// Write every label registration, description and event to the provided
+    /// [`WriteAheadLog`] as it is recorded, so the [`Procession`] can be rebuilt with
+    /// [`crate::wal::recover`] after a crash. The keys and descriptions already in the
+    /// [`Procession`] are written to the log when the recorder is built
+    ///
+    /// With [`shards`](Self::shards) events are written to the log when the shards are merged
+    /// instead of as they are recorded, so recording threads never contend on the log. A crash
+    /// loses the events still buffered in shards, at most 4096 per shard, use
+    /// [`ProcessionRecorder::flush_log`] to bound that
+    pub fn write_ahead_log(mut self, wal: WriteAheadLog) -> Self {
+        self.wal = Some(wal);
+        self
+    }
+
+    pub fn build(self) -> ProcessionRecorder {
+        ProcessionRecorder(Arc::new(self.into_inner()))
+    }
+
+    fn into_inner(self) -> Inner {
+        let shards = (0..self.shards)
+            .map(|_| Mutex::new(Vec::with_capacity(SHARD_CAPACITY)))
+            .collect();
+        let mut log_errors = 0;
+        let wal = self.wal.map(|mut wal| {
+            if wal.log_keys(&self.procession).is_err() {
+                log_errors += 1;
+            }
+            Mutex::new(wal)
+        });
+        Inner {
+            clock: self.procession.clock().clone(),
+            procession: Mutex::new(self.procession),
+            shards,
+            dropped: AtomicU64::new(0),
+            wal,
+            log_errors: AtomicU64::new(log_errors),
+        }
+    }
+}
+
+/// An event waiting in a shard to be merged into the [`Procession`]
+#[derive(Debug)]
+struct Pending {
+    when: OffsetDateTime,
+    entry: Entry,
+    label: LabelId,
+}
 
 impl ProcessionRecorder {
-    pub fn lock(&self) -> MutexGuard<Procession> {
-        self.0.lock().unwrap_or_else(|e| e.into_inner())
+    pub fn builder() -> ProcessionRecorderBuilder {
+        ProcessionRecorderBuilder::default()
+    }
+
+    /// Create a recorder that buffers events into `shards` separate buffers, each thread
+    /// will always write to the same shard so a shard count matching the number of recording
+    /// threads (e.g. [`std::thread::available_parallelism`]) will avoid any lock contention
+    /// while recording.
+    ///
+    /// Buffered events are merged into time-ordered [`crate::chunk::Chunk`]s when the
+    /// [`Procession`] is locked or serialized.
+    pub fn sharded(shards: usize) -> Self {
+        Self::builder().shards(shards.max(1)).build()
     }
+
+    /// Lock the underlying [`Procession`], merging any events buffered in shards first
+    pub fn lock(&self) -> MutexGuard<'_, Procession> {
+        let mut procession = self.procession();
+        self.0.merge_shards(&mut procession);
+        procession
+    }
+
     pub fn memory_size(&self) -> usize {
-        self.0.lock().unwrap().memory_size()
+        self.lock().memory_size()
+    }
+
+    /// Limit how much history the underlying [`Procession`] will keep, any chunks exceeding
+    /// the new limits are evicted immediately
+    pub fn set_retention(&self, retention: Retention) {
+        self.lock().set_retention(retention);
+    }
+
+    /// Replace how chunks are sealed once a newer chunk is started
+    pub fn set_sealing(&self, sealing: Option<SealOptions>) {
+        self.procession().set_sealing(sealing);
+    }
+
+    /// Replace when old chunks are rolled up, any chunks already old enough are rolled up
+    /// immediately
+    pub fn set_rollup(&self, rollup: Option<RollupPolicy>) {
+        self.lock().set_rollup(rollup);
+    }
+
+    /// Replace the [`OverflowPolicy`] used for new keys once every label identifier has been used
+    pub fn set_overflow_policy(&self, overflow_policy: OverflowPolicy) {
+        self.procession()
+            .labels
+            .set_overflow_policy(overflow_policy);
+    }
+
+    /// The number of registrations that were not given their own label identifier
+    pub fn rejected_registrations(&self) -> u64 {
+        self.procession().labels.rejected()
+    }
+
+    /// The number of events discarded because their key was rejected under [`OverflowPolicy::Drop`]
+    pub fn dropped_events(&self) -> u64 {
+        self.0.dropped.load(Ordering::Relaxed)
+    }
+
+    /// The number of records that could not be written to the [`WriteAheadLog`]
+    pub fn log_errors(&self) -> u64 {
+        self.0.log_errors.load(Ordering::Relaxed)
+    }
+
+    /// Merge any events buffered in shards, writing them to the [`WriteAheadLog`], then flush
+    /// any records buffered by its writer
+    pub fn flush_log(&self) -> io::Result<()> {
+        drop(self.lock());
+        match &self.0.wal {
+            Some(wal) => wal.lock().unwrap_or_else(|e| e.into_inner()).flush(),
+            None => Ok(()),
+        }
+    }
+
+    /// Lock the underlying [`Procession`] without merging any shards
+    fn procession(&self) -> MutexGuard<'_, Procession> {
+        self.0.procession.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Record the description for a metric name, writing it to the log if there is one
+    fn describe(
+        &self,
+        key: metrics::KeyName,
+        unit: Option<metrics::Unit>,
+        description: metrics::SharedString,
+    ) {
+        let mut procession = self.procession();
+        procession.describe(key.clone(), unit, description);
+        if let Some(description) = procession.descriptions.get(key.as_str()) {
+            self.0.log(|wal| wal.log_description(&key, description));
+        }
+    }
+
+    /// Get the label identifier for the provided key
+    fn register(&self, key: &metrics::Key) -> Registration {
+        let mut procession = self.procession();
+        let known = procession.labels.len();
+        match procession.ensure_label(key) {
+            Some(label) => {
+                // this may be the overflow key instead of `key`
+                if procession.labels.len() > known
+                    && let Some(key) = procession.labels.key(label)
+                {
+                    self.0.log(|wal| wal.log_label(key, label));
+                }
+                Registration::Label(label)
+            }
+            None if procession.labels.overflow_policy() == OverflowPolicy::Noop => {
+                Registration::Noop
+            }
+            None => Registration::Dropped,
+        }
+    }
+
+    /// Record a single entry either directly into the [`Procession`] or into this thread's shard
+    fn record(&self, entry: Entry, label: LabelId) {
+        if self.0.shards.is_empty() {
+            let mut procession = self.procession();
+            if self.0.wal.is_none() {
+                procession.insert_entry(entry, label);
+                return;
+            }
+            // the log is written while holding the lock so it is in the same order
+            let now = self.0.clock.now();
+            self.0.log(|wal| wal.log_event(now, entry, label));
+            procession.insert_entry_at(now, entry, label);
+            return;
+        }
+        let shard = &self.0.shards[shard_index(self.0.shards.len())];
+        let full = {
+            let mut buffer = shard.lock().unwrap_or_else(|e| e.into_inner());
+            // The time is captured while holding the shard's lock so each shard is
+            // always in time order. Events are only logged once they are merged so the
+            // shards never wait on the log
+            let when = self.0.clock.now();
+            buffer.push(Pending { when, entry, label });
+            buffer.len() >= SHARD_CAPACITY
+        };
+        if full {
+            drop(self.lock());
+        }
+    }
+}
+
+impl Inner {
+    /// Write a record to the log if there is one, counting any failure
+    fn log(&self, f: impl FnOnce(&mut WriteAheadLog) -> io::Result<()>) {
+        if let Some(wal) = &self.wal {
+            let mut wal = wal.lock().unwrap_or_else(|e| e.into_inner());
+            if f(&mut wal).is_err() {
+                self.log_errors.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+    }
+
+    /// Drain every shard into the provided [`Procession`] in time order, writing each event to
+    /// the log if there is one.
+    ///
+    /// Only events recorded before this method started are merged, anything recorded
+    /// into an already drained shard while this is running will be left for the next merge
+    /// to avoid inserting events out of order.
+    fn merge_shards(&self, procession: &mut Procession) {
+        if self.shards.is_empty() {
+            return;
+        }
+        let cutoff = self.clock.now();
+        let mut pending = Vec::new();
+        for shard in &self.shards {
+            let mut buffer = shard.lock().unwrap_or_else(|e| e.into_inner());
+            let split = buffer.partition_point(|p| p.when <= cutoff);
+            pending.extend(buffer.drain(..split));
+        }
+        pending.sort_by_key(|p| p.when);
+        let mut wal = self
+            .wal
+            .as_ref()
+            .map(|wal| wal.lock().unwrap_or_else(|e| e.into_inner()));
+        for Pending { when, entry, label } in pending {
+            if let Some(wal) = &mut wal
+                && wal.log_event(when, entry, label).is_err()
+            {
+                self.log_errors.fetch_add(1, Ordering::Relaxed);
+            }
+            procession.insert_entry_at(when, entry, label);
+        }
+    }
+}
+
+/// Get the shard the current thread should record into, threads are assigned
+/// shards in a round-robin fashion the first time they record an event
+fn shard_index(shard_count: usize) -> usize {
+    static NEXT_SHARD: AtomicUsize = AtomicUsize::new(0);
+    thread_local! {
+        static SHARD: usize = NEXT_SHARD.fetch_add(1, Ordering::Relaxed);
+    }
+    SHARD.with(|shard| *shard % shard_count)
+}
+
+/// Serializes the underlying [`Procession`], merging any events buffered in shards first
+impl Serialize for ProcessionRecorder {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: serde::Serializer,
+    {
+        self.lock().serialize(serializer)
     }
 }
 
 impl Recorder for ProcessionRecorder {
     fn describe_counter(
         &self,
-        _: metrics::KeyName,
-        _: Option<metrics::Unit>,
-        _: metrics::SharedString,
+        key: metrics::KeyName,
+        unit: Option<metrics::Unit>,
+        description: metrics::SharedString,
     ) {
+        self.describe(key, unit, description);
     }
 
     fn describe_gauge(
         &self,
-        _: metrics::KeyName,
-        _: Option<metrics::Unit>,
-        _: metrics::SharedString,
+        key: metrics::KeyName,
+        unit: Option<metrics::Unit>,
+        description: metrics::SharedString,
     ) {
+        self.describe(key, unit, description);
     }
 
     fn describe_histogram(
         &self,
-        _: metrics::KeyName,
-        _: Option<metrics::Unit>,
-        _: metrics::SharedString,
+        key: metrics::KeyName,
+        unit: Option<metrics::Unit>,
+        description: metrics::SharedString,
     ) {
+        self.describe(key, unit, description);
     }
 
     fn register_counter(&self, key: &metrics::Key, _: &metrics::Metadata<'_>) -> metrics::Counter {
-        let label = self.0.lock().unwrap().ensure_label(key);
-        metrics::Counter::from_arc(Arc::new(Counter(label, self.clone())))
+        match self.register(key) {
+            Registration::Label(label) => {
+                metrics::Counter::from_arc(Arc::new(Counter(label, self.clone())))
+            }
+            Registration::Dropped => metrics::Counter::from_arc(Arc::new(Dropped(self.clone()))),
+            Registration::Noop => metrics::Counter::noop(),
+        }
     }
 
     fn register_gauge(&self, key: &metrics::Key, _: &metrics::Metadata<'_>) -> metrics::Gauge {
-        let label = self.0.lock().unwrap().ensure_label(key);
-        metrics::Gauge::from_arc(Arc::new(Gauge(label, self.clone())))
+        match self.register(key) {
+            Registration::Label(label) => {
+                metrics::Gauge::from_arc(Arc::new(Gauge(label, self.clone())))
+            }
+            Registration::Dropped => metrics::Gauge::from_arc(Arc::new(Dropped(self.clone()))),
+            Registration::Noop => metrics::Gauge::noop(),
+        }
     }
 
     fn register_histogram(
@@ -59,12 +425,27 @@ impl Recorder for ProcessionRecorder {
         key: &metrics::Key,
         _: &metrics::Metadata<'_>,
     ) -> metrics::Histogram {
-        let label = self.0.lock().unwrap().ensure_label(key);
-        metrics::Histogram::from_arc(Arc::new(Histo(label, self.clone())))
+        match self.register(key) {
+            Registration::Label(label) => {
+                metrics::Histogram::from_arc(Arc::new(Histo(label, self.clone())))
+            }
+            Registration::Dropped => metrics::Histogram::from_arc(Arc::new(Dropped(self.clone()))),
+            Registration::Noop => metrics::Histogram::noop(),
+        }
     }
 }
 
-struct Counter(u16, ProcessionRecorder);
+/// The result of registering a key with the [`Procession`]
+enum Registration {
+    /// The key was assigned this label identifier
+    Label(LabelId),
+    /// The key was rejected under [`OverflowPolicy::Drop`]
+    Dropped,
+    /// The key was rejected under [`OverflowPolicy::Noop`]
+    Noop,
+}
+
+struct Counter(LabelId, ProcessionRecorder);
 
 impl CounterFn for Counter {
     fn increment(&self, value: u64) {
@@ -78,25 +459,17 @@ impl CounterFn for Counter {
 
 impl Counter {
     pub fn insert(&self, value: u64, op: Op) {
-        let Ok(value) = u32::try_from(value) else {
-            eprintln!("value has exceeded a u32, skipping event");
-            return;
-        };
-        self.1
-            .0
-            .lock()
-            .unwrap()
-            .insert_entry(Entry::Counter { value, op }, self.0);
+        self.1.record(Entry::Counter { value, op }, self.0);
     }
 }
 
-struct Gauge(u16, ProcessionRecorder);
+struct Gauge(LabelId, ProcessionRecorder);
 
 impl GaugeFn for Gauge {
     fn increment(&self, value: f64) {
-        self.1.0.lock().unwrap().insert_entry(
+        self.1.record(
             Entry::Gauge {
-                value: value as f32,
+                value: value as Float,
                 op: Op::Add,
             },
             self.0,
@@ -104,9 +477,9 @@ impl GaugeFn for Gauge {
     }
 
     fn decrement(&self, value: f64) {
-        self.1.0.lock().unwrap().insert_entry(
+        self.1.record(
             Entry::Gauge {
-                value: value as f32,
+                value: value as Float,
                 op: Op::Sub,
             },
             self.0,
@@ -114,9 +487,9 @@ impl GaugeFn for Gauge {
     }
 
     fn set(&self, value: f64) {
-        self.1.0.lock().unwrap().insert_entry(
+        self.1.record(
             Entry::Gauge {
-                value: value as f32,
+                value: value as Float,
                 op: Op::Set,
             },
             self.0,
@@ -124,21 +497,65 @@ impl GaugeFn for Gauge {
     }
 }
 
-struct Histo(u16, ProcessionRecorder);
+struct Histo(LabelId, ProcessionRecorder);
 
 impl HistogramFn for Histo {
     fn record(&self, value: f64) {
-        self.1.0.lock().unwrap().insert_entry(
+        self.1.record(
             Entry::Histogram {
-                value: value as f32,
+                value: value as Float,
             },
             self.0,
         );
     }
 }
 
+/// The handle for any key rejected under [`OverflowPolicy::Drop`], every event
+/// is discarded and counted
+struct Dropped(ProcessionRecorder);
+
+impl Dropped {
+    fn drop_event(&self) {
+        self.0.0.dropped.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+impl CounterFn for Dropped {
+    fn increment(&self, _: u64) {
+        self.drop_event();
+    }
+
+    fn absolute(&self, _: u64) {
+        self.drop_event();
+    }
+}
+
+impl GaugeFn for Dropped {
+    fn increment(&self, _: f64) {
+        self.drop_event();
+    }
+
+    fn decrement(&self, _: f64) {
+        self.drop_event();
+    }
+
+    fn set(&self, _: f64) {
+        self.drop_event();
+    }
+}
+
+impl HistogramFn for Dropped {
+    fn record(&self, _: f64) {
+        self.drop_event();
+    }
+}
+
 #[cfg(test)]
 mod tests {
+    use time::Duration;
+
+    use crate::{clock::ManualClock, description::Description, test_util::start};
+
     use super::*;
 
     #[test]
@@ -152,6 +569,9 @@ mod tests {
             let h = metrics::histogram!("one_histo");
             let h2 = metrics::histogram!("with_label", "tid" => format!("{:?}", std::thread::current().id()));
 
+            metrics::describe_counter!("one_counter", metrics::Unit::Bytes, "bytes sent");
+            metrics::describe_histogram!("with_label", "time taken");
+
             for i in 0..1000 {
                 ct.increment(1);
                 ct2.increment(2);
@@ -161,5 +581,181 @@ mod tests {
                 h2.record((i / 2) as f64);
             }
         });
+        let procession = recorder.lock();
+        let counter = procession
+            .iter()
+            .find(|m| m.key.name() == "one_counter")
+            .unwrap();
+        assert_eq!(
+            counter.description,
+            Some(&Description {
+                unit: Some(metrics::Unit::Bytes),
+                text: "bytes sent".to_string(),
+            })
+        );
+        let histos = procession
+            .iter()
+            .filter(|m| m.key.name() == "with_label")
+            .collect::<Vec<_>>();
+        assert!(!histos.is_empty());
+        assert!(histos.iter().all(|m| m.description.unwrap().unit.is_none()));
+        assert!(
+            procession
+                .iter()
+                .filter(|m| m.key.name() == "one_gauge")
+                .all(|m| m.description.is_none())
+        );
+    }
+
+    #[test]
+    fn sharded_threads_merge_in_order() {
+        let recorder = ProcessionRecorder::sharded(4);
+        let metadata = metrics::Metadata::new(module_path!(), metrics::Level::INFO, None);
+        std::thread::scope(|s| {
+            for t in 0..8 {
+                let recorder = &recorder;
+                let metadata = &metadata;
+                s.spawn(move || {
+                    let key = metrics::Key::from_parts(
+                        "threaded",
+                        vec![metrics::Label::new("thread", t.to_string())],
+                    );
+                    let ct = recorder.register_counter(&key, metadata);
+                    let h = recorder.register_histogram(&key, metadata);
+                    for i in 0..SHARD_CAPACITY {
+                        ct.increment(1);
+                        h.record(i as f64);
+                    }
+                });
+            }
+        });
+        let procession = recorder.lock();
+        let events: Vec<_> = procession.iter().collect();
+        assert_eq!(events.len(), 8 * 2 * SHARD_CAPACITY);
+        assert!(events.windows(2).all(|w| w[0].when <= w[1].when));
+        assert!(
+            recorder
+                .0
+                .shards
+                .iter()
+                .all(|s| s.lock().unwrap().is_empty())
+        );
+    }
+
+    #[test]
+    fn manual_clock_timestamps() {
+        let start = start();
+        let clock = Arc::new(ManualClock::new(start));
+        for shards in [0, 2] {
+            clock.set(start);
+            let recorder = ProcessionRecorder::builder()
+                .clock(clock.clone())
+                .shards(shards)
+                .build();
+            metrics::with_local_recorder(&recorder, || {
+                let ct = metrics::counter!("ticks");
+                for _ in 0..3 {
+                    ct.increment(1);
+                    clock.advance(Duration::seconds(30));
+                }
+            });
+            let procession = recorder.lock();
+            let when: Vec<_> = procession.iter().map(|m| m.when).collect();
+            assert_eq!(
+                when,
+                vec![
+                    start,
+                    start + Duration::seconds(30),
+                    start + Duration::seconds(60)
+                ]
+            );
+            // 60 seconds is within the first chunk's window
+            assert_eq!(procession.chunks.len(), 1);
+        }
+    }
+
+    #[test]
+    fn wide_counters_are_lossless() {
+        let recorder = ProcessionRecorder::default();
+        metrics::with_local_recorder(&recorder, || {
+            let ct = metrics::counter!("bytes");
+            ct.absolute(u64::MAX);
+            ct.increment(1);
+            ct.absolute(u64::from(u32::MAX) + 1);
+        });
+        let expected = vec![
+            Entry::Counter {
+                value: u64::MAX,
+                op: Op::Set,
+            },
+            Entry::Counter {
+                value: 1,
+                op: Op::Add,
+            },
+            Entry::Counter {
+                value: u64::from(u32::MAX) + 1,
+                op: Op::Set,
+            },
+        ];
+        let procession = recorder.lock();
+        assert_eq!(procession.chunks[0].events().wide_values.len(), 2);
+        let events: Vec<_> = procession.iter().map(|m| m.event).collect();
+        assert_eq!(events, expected);
+        let json = serde_json::to_string(&*procession).unwrap();
+        let round_tripped: Procession = serde_json::from_str(&json).unwrap();
+        let events: Vec<_> = round_tripped.iter().map(|m| m.event).collect();
+        assert_eq!(events, expected);
+    }
+
+    #[test]
+    #[cfg(not(feature = "wide-labels"))]
+    fn overflow_policies() {
+        let fill = |recorder: &ProcessionRecorder| {
+            metrics::with_local_recorder(recorder, || {
+                for i in 0..=u16::MAX as u32 + 2 {
+                    metrics::counter!("many", "i" => i.to_string()).increment(1);
+                }
+            });
+        };
+        let keys = u16::MAX as usize + 1;
+
+        let dropped = ProcessionRecorder::default();
+        fill(&dropped);
+        assert_eq!(dropped.rejected_registrations(), 2);
+        assert_eq!(dropped.dropped_events(), 2);
+        assert_eq!(dropped.lock().labels.len(), keys);
+
+        let noop = ProcessionRecorder::builder()
+            .overflow_policy(OverflowPolicy::Noop)
+            .build();
+        fill(&noop);
+        assert_eq!(noop.rejected_registrations(), 2);
+        assert_eq!(noop.dropped_events(), 0);
+        let events = |p: &Procession| p.chunks.iter().map(|c| c.len()).sum::<usize>();
+        assert_eq!(events(&noop.lock()), keys);
+
+        let overflow = ProcessionRecorder::builder()
+            .overflow_policy(OverflowPolicy::Overflow)
+            .build();
+        fill(&overflow);
+        assert_eq!(overflow.rejected_registrations(), 3);
+        let procession = overflow.lock();
+        assert_eq!(procession.labels.len(), keys);
+        let overflow_label = procession
+            .labels
+            .get(&crate::label_set::overflow_key())
+            .unwrap();
+        let overflowed = procession
+            .chunks
+            .iter()
+            .map(|c| {
+                c.events()
+                    .iter()
+                    .filter(|e| e.label == overflow_label)
+                    .count()
+            })
+            .sum::<usize>();
+        assert_eq!(overflowed, 3);
+        assert_eq!(events(&procession), keys + 2);
     }
 }
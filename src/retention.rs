@@ -0,0 +1,196 @@
+//! Limits on how much history a [`crate::procession::Procession`] will hold on to
+use time::{Duration, OffsetDateTime};
+
+use crate::{chunk::Chunk, procession::Procession};
+
+/// The limits placed on a [`Procession`], when any of these are exceeded whole chunks are
+/// evicted from the front of the series, like a flight recorder. The last chunk is never evicted
+/// since it is the one currently being written to.
+///
+/// The default value will retain everything.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct Retention {
+    /// Only keep chunks with events that occurred within this much time of now, as measured by
+    /// the series' [`crate::clock::Clock`]
+    pub max_age: Option<Duration>,
+    /// Only keep this many chunks
+    pub max_chunks: Option<usize>,
+    /// Only keep as many chunks as will fit within this many bytes, as measured by
+    /// [`Procession::memory_size`]
+    pub max_bytes: Option<usize>,
+}
+
+impl Retention {
+    /// Keep the chunks with events from the last `max_age` of time according to the series' clock
+    pub fn max_age(max_age: Duration) -> Self {
+        Self {
+            max_age: Some(max_age),
+            ..Default::default()
+        }
+    }
+
+    /// Keep the last `max_chunks` chunks
+    pub fn max_chunks(max_chunks: usize) -> Self {
+        Self {
+            max_chunks: Some(max_chunks),
+            ..Default::default()
+        }
+    }
+
+    /// Keep as many chunks as will fit within `max_bytes`
+    pub fn max_bytes(max_bytes: usize) -> Self {
+        Self {
+            max_bytes: Some(max_bytes),
+            ..Default::default()
+        }
+    }
+
+    /// Calculate how many chunks from the front of the provided [`Procession`] need to be
+    /// evicted for it to be within these limits at the time provided
+    pub(crate) fn chunks_to_evict(&self, procession: &Procession, now: OffsetDateTime) -> usize {
+        let chunks = &procession.chunks;
+        // the last chunk is always kept
+        let evictable = chunks.len().saturating_sub(1);
+        let mut evict = 0;
+        if let Some(max_chunks) = self.max_chunks {
+            evict = evict.max(chunks.len().saturating_sub(max_chunks.max(1)));
+        }
+        if let Some(max_age) = self.max_age {
+            let cutoff = now - max_age;
+            let expired = chunks
+                .iter()
+                .take_while(|chunk| {
+                    chunk.reference_time + Duration::milliseconds(i64::from(chunk.last_ms()))
+                        < cutoff
+                })
+                .count();
+            evict = evict.max(expired);
+        }
+        if let Some(max_bytes) = self.max_bytes {
+            // evicted chunks leave their slot in `chunks` to be reused by the next chunk so
+            // the spare slots aren't counted
+            let spare = (chunks.capacity() - chunks.len()) * size_of::<Chunk>();
+            let mut size = procession.memory_size() - spare;
+            let mut over = 0;
+            for chunk in chunks.iter().take(evictable) {
+                if size <= max_bytes {
+                    break;
+                }
+                size -= chunk.memory_size();
+                over += 1;
+            }
+            evict = evict.max(over);
+        }
+        evict.min(evictable)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        clock::ManualClock,
+        event::{Entry, Op},
+        test_util::start,
+    };
+
+    use super::*;
+
+    /// Insert one event every other minute, each event will start a new chunk
+    fn fill(procession: &mut Procession, chunks: i64) {
+        for chunk in 0..chunks {
+            let (chunk, ms) = procession.last_chunk_and_ms(start() + Duration::minutes(chunk * 2));
+            chunk.push_entry(
+                ms,
+                0,
+                Entry::Counter {
+                    value: 1,
+                    op: Op::Add,
+                },
+            );
+        }
+    }
+
+    #[test]
+    fn unbounded_keeps_everything() {
+        let mut procession = Procession::default();
+        fill(&mut procession, 10);
+        assert_eq!(procession.chunks.len(), 10);
+    }
+
+    #[test]
+    fn max_chunks() {
+        let mut procession = Procession::default().with_retention(Retention::max_chunks(3));
+        fill(&mut procession, 10);
+        assert_eq!(procession.chunks.len(), 3);
+        assert_eq!(
+            procession.chunks[0].reference_time,
+            start() + Duration::minutes(14)
+        );
+    }
+
+    #[test]
+    fn max_age() {
+        let mut procession =
+            Procession::default().with_retention(Retention::max_age(Duration::minutes(5)));
+        fill(&mut procession, 10);
+        assert_eq!(procession.chunks.len(), 3);
+        assert_eq!(
+            procession.chunks[0].reference_time,
+            start() + Duration::minutes(14)
+        );
+    }
+
+    #[test]
+    fn max_bytes() {
+        let mut unbounded = Procession::default();
+        fill(&mut unbounded, 4);
+        let budget = unbounded.memory_size();
+        let mut procession = Procession::default().with_retention(Retention::max_bytes(budget));
+        fill(&mut procession, 10);
+        assert_eq!(procession.chunks.len(), 4);
+        // the slots left behind by evicted chunks are reused rather than counted
+        let spare = (procession.chunks.capacity() - procession.chunks.len()) * size_of::<Chunk>();
+        assert!(procession.memory_size() - spare <= budget);
+    }
+
+    #[test]
+    fn never_evicts_the_last_chunk() {
+        let mut procession = Procession::default().with_retention(Retention::max_bytes(0));
+        fill(&mut procession, 10);
+        assert_eq!(procession.chunks.len(), 1);
+        assert_eq!(
+            procession.chunks[0].reference_time,
+            start() + Duration::minutes(18)
+        );
+    }
+
+    #[test]
+    fn set_retention_evicts_immediately() {
+        let mut procession = Procession::default();
+        fill(&mut procession, 10);
+        procession.set_retention(Retention::max_chunks(2));
+        assert_eq!(procession.chunks.len(), 2);
+    }
+
+    #[test]
+    fn late_entries_are_aged_by_the_clock() {
+        let clock = std::sync::Arc::new(ManualClock::new(start() + Duration::minutes(22)));
+        let mut procession = Procession::default()
+            .with_clock(clock)
+            .with_retention(Retention::max_age(Duration::minutes(5)));
+        fill(&mut procession, 10);
+        assert_eq!(procession.chunks.len(), 3);
+        // starts a chunk between the ones at 14 and 16 minutes, everything before 17 minutes
+        // is older than `max_age` by the clock
+        let entry = Entry::Counter {
+            value: 1,
+            op: Op::Add,
+        };
+        procession.insert_entry_at(start() + Duration::seconds(15 * 60 + 30), entry, 0);
+        assert_eq!(procession.chunks.len(), 1);
+        assert_eq!(
+            procession.chunks[0].reference_time,
+            start() + Duration::minutes(18)
+        );
+    }
+}
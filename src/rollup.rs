@@ -0,0 +1,767 @@
+//! Coarser summaries of old [`crate::chunk::Chunk`]s, millisecond resolution is valuable for
+//! recent events but for events from hours ago a summary of each key over a longer step keeps
+//! the trend available in a fraction of the memory
+use std::collections::BTreeMap;
+
+use serde::{Deserialize, Serialize};
+use time::Duration;
+
+use crate::{
+    chunk::Events,
+    codec::{self, DecodeError, Reader},
+    event::{Entry, Float, Op},
+    label_set::LabelId,
+    memory::MemoryUsage,
+};
+
+/// When and how a [`crate::procession::Procession`] will roll up its chunks, whenever a new
+/// chunk is started any chunk with only events older than `after` is replaced with a
+/// [`Rollup`] of `step` sized buckets
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RollupPolicy {
+    /// How old the events in a chunk must be before it is rolled up
+    pub after: Duration,
+    /// The width of each bucket, this is clamped to between 1 millisecond and the width
+    /// of a chunk (65.535 seconds)
+    pub step: Duration,
+}
+
+impl RollupPolicy {
+    /// Roll up chunks older than `after` into buckets of `step`
+    pub fn new(after: Duration, step: Duration) -> Self {
+        Self { after, step }
+    }
+}
+
+/// Convert a step into the number of milliseconds in each bucket
+pub(crate) fn step_ms(step: Duration) -> u16 {
+    step.whole_milliseconds().clamp(1, i128::from(u16::MAX)) as u16
+}
+
+/// The events of a chunk combined into one [`Aggregate`] per key and type for each step
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Rollup {
+    /// The width of each bucket in milliseconds, never 0
+    #[serde(deserialize_with = "deserialize_step")]
+    pub step_ms: u16,
+    /// The buckets ordered by their `ms` value
+    pub buckets: Vec<Bucket>,
+}
+
+/// A `step_ms` of 0 would divide by zero when an entry is added to the rollup
+fn deserialize_step<'de, D>(deserializer: D) -> Result<u16, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    match u16::deserialize(deserializer)? {
+        0 => Err(serde::de::Error::custom(DecodeError::ZeroStep)),
+        step_ms => Ok(step_ms),
+    }
+}
+
+/// The events of a single key over one step of a [`Rollup`]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Bucket {
+    /// The number of milliseconds between the owning chunk's `reference_time` and the start
+    /// of this bucket
+    pub ms: u16,
+    /// The label identifier of the key these events were recorded for
+    pub label: LabelId,
+    /// The combined events
+    pub aggregate: Aggregate,
+}
+
+/// The combined value of every event of one type for a key within a step
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum Aggregate {
+    /// The sum of the counter events, a step that included an `absolute` value is recorded
+    /// as a `Set` of that value plus any increments after it
+    Counter { value: u64, op: Op },
+    /// The value of the gauge at the end of the step along with the smallest and largest
+    /// values it held during the step
+    Gauge { last: Float, min: Float, max: Float },
+    /// A sketch of the recorded histogram values
+    Histogram(Sketch),
+}
+
+impl Aggregate {
+    /// Start a new aggregate from a single entry, `gauge` is the value of the gauge before
+    /// this entry and is updated to the value after it
+    fn new(entry: Entry, gauge: &mut Float) -> Self {
+        match entry {
+            Entry::Counter { value, op } => Self::Counter { value, op },
+            Entry::Gauge { value, op } => {
+                *gauge = apply(*gauge, value, op);
+                Self::Gauge {
+                    last: *gauge,
+                    min: *gauge,
+                    max: *gauge,
+                }
+            }
+            Entry::Histogram { value } => {
+                let mut sketch = Sketch::default();
+                sketch.insert(widen(value));
+                Self::Histogram(sketch)
+            }
+        }
+    }
+
+    /// If the provided entry is the same type as this aggregate
+    fn accepts(&self, entry: &Entry) -> bool {
+        matches!(
+            (self, entry),
+            (Self::Counter { .. }, Entry::Counter { .. })
+                | (Self::Gauge { .. }, Entry::Gauge { .. })
+                | (Self::Histogram(_), Entry::Histogram { .. })
+        )
+    }
+
+    /// If the provided aggregate is the same type as this one
+    fn same_type(&self, other: &Aggregate) -> bool {
+        std::mem::discriminant(self) == std::mem::discriminant(other)
+    }
+
+    /// Fold an aggregate of the same type from a later or overlapping step into this one, a
+    /// counter `Set` or the `last` value of a gauge from `later` replaces this one's
+    fn merge(&mut self, later: Aggregate) {
+        match (self, later) {
+            (
+                Self::Counter { value, op },
+                Self::Counter {
+                    value: v,
+                    op: Op::Set,
+                },
+            ) => {
+                *value = v;
+                *op = Op::Set;
+            }
+            (
+                Self::Counter { value, .. },
+                Self::Counter {
+                    value: v,
+                    op: Op::Add,
+                },
+            ) => {
+                *value = value.saturating_add(v);
+            }
+            (
+                Self::Counter { value, .. },
+                Self::Counter {
+                    value: v,
+                    op: Op::Sub,
+                },
+            ) => {
+                *value = value.saturating_sub(v);
+            }
+            (
+                Self::Gauge { last, min, max },
+                Self::Gauge {
+                    last: l,
+                    min: lo,
+                    max: hi,
+                },
+            ) => {
+                *last = l;
+                *min = min.min(lo);
+                *max = max.max(hi);
+            }
+            (Self::Histogram(sketch), Self::Histogram(other)) => sketch.merge(&other),
+            _ => {}
+        }
+    }
+
+    /// Fold another entry of the same type into this aggregate
+    fn add(&mut self, entry: Entry, gauge: &mut Float) {
+        match (self, entry) {
+            (
+                Self::Counter { value, op },
+                Entry::Counter {
+                    value: v,
+                    op: Op::Set,
+                },
+            ) => {
+                *value = v;
+                *op = Op::Set;
+            }
+            (
+                Self::Counter { value, .. },
+                Entry::Counter {
+                    value: v,
+                    op: Op::Add,
+                },
+            ) => {
+                *value = value.saturating_add(v);
+            }
+            (
+                Self::Counter { value, .. },
+                Entry::Counter {
+                    value: v,
+                    op: Op::Sub,
+                },
+            ) => {
+                *value = value.saturating_sub(v);
+            }
+            (Self::Gauge { last, min, max }, Entry::Gauge { value, op }) => {
+                *gauge = apply(*last, value, op);
+                *last = *gauge;
+                *min = min.min(*gauge);
+                *max = max.max(*gauge);
+            }
+            (Self::Histogram(sketch), Entry::Histogram { value }) => sketch.insert(widen(value)),
+            _ => {}
+        }
+    }
+
+    /// A single [`Entry`] standing in for this aggregate, counters are represented exactly,
+    /// gauges are set to their last value and histograms record their mean
+    pub fn entry(&self) -> Entry {
+        match self {
+            Self::Counter { value, op } => Entry::Counter {
+                value: *value,
+                op: *op,
+            },
+            Self::Gauge { last, .. } => Entry::Gauge {
+                value: *last,
+                op: Op::Set,
+            },
+            Self::Histogram(sketch) => Entry::Histogram {
+                value: sketch.mean() as Float,
+            },
+        }
+    }
+}
+
+/// Apply a gauge operation to the previous value of the gauge
+pub(crate) fn apply(previous: Float, value: Float, op: Op) -> Float {
+    match op {
+        Op::Add => previous + value,
+        Op::Sub => previous - value,
+        Op::Set => value,
+    }
+}
+
+// `Float` is already an `f64` with the `f64-values` feature
+#[allow(clippy::unnecessary_cast)]
+fn widen(value: Float) -> f64 {
+    value as f64
+}
+
+impl Rollup {
+    /// An empty rollup with buckets of `step_ms` milliseconds
+    pub fn new(step_ms: u16) -> Self {
+        Self {
+            step_ms: step_ms.max(1),
+            buckets: Vec::new(),
+        }
+    }
+
+    /// Combine the provided events into buckets of `step_ms` milliseconds. Gauges updated with
+    /// `increment` or `decrement` start from their value in `gauges` (or zero) and `gauges` is
+    /// updated with their value at the end of these events
+    pub fn from_events(
+        events: &Events,
+        step_ms: u16,
+        gauges: &mut BTreeMap<LabelId, Float>,
+    ) -> Self {
+        let mut ret = Self::new(step_ms);
+        for event in events.iter() {
+            let Some(entry) = events.entry(event) else {
+                continue;
+            };
+            let gauge = gauges.entry(event.label).or_default();
+            ret.add(event.ms, event.label, entry, gauge);
+        }
+        // a rolled up chunk rarely grows so any spare capacity is released
+        ret.buckets.shrink_to_fit();
+        ret
+    }
+
+    /// Fold an entry into the bucket covering `ms`. A gauge updated with `increment` or
+    /// `decrement` is applied to its value in the closest earlier bucket, later buckets are
+    /// not adjusted
+    pub fn insert(&mut self, ms: u16, label: LabelId, entry: Entry) {
+        let mut gauge = self.gauge_before(ms, label).unwrap_or_default();
+        self.add(ms, label, entry, &mut gauge);
+    }
+
+    /// Fold an aggregate into the bucket covering `ms`, merging it with any bucket of the same
+    /// key and type already there
+    pub fn insert_aggregate(&mut self, ms: u16, label: LabelId, aggregate: Aggregate) {
+        let ms = ms - ms % self.step_ms;
+        let start = self.buckets.partition_point(|b| b.ms < ms);
+        let end = self.buckets.partition_point(|b| b.ms <= ms);
+        if let Some(bucket) = self.buckets[start..end]
+            .iter_mut()
+            .find(|b| b.label == label && b.aggregate.same_type(&aggregate))
+        {
+            bucket.aggregate.merge(aggregate);
+            return;
+        }
+        self.buckets.insert(
+            end,
+            Bucket {
+                ms,
+                label,
+                aggregate,
+            },
+        );
+    }
+
+    /// The last value of the gauge for `label` in a bucket starting at or before `ms`
+    pub fn gauge_before(&self, ms: u16, label: LabelId) -> Option<Float> {
+        self.buckets
+            .iter()
+            .rev()
+            .filter(|b| b.ms <= ms && b.label == label)
+            .find_map(|b| match b.aggregate {
+                Aggregate::Gauge { last, .. } => Some(last),
+                _ => None,
+            })
+    }
+
+    fn add(&mut self, ms: u16, label: LabelId, entry: Entry, gauge: &mut Float) {
+        let ms = ms - ms % self.step_ms;
+        let start = self.buckets.partition_point(|b| b.ms < ms);
+        let end = self.buckets.partition_point(|b| b.ms <= ms);
+        if let Some(bucket) = self.buckets[start..end]
+            .iter_mut()
+            .find(|b| b.label == label && b.aggregate.accepts(&entry))
+        {
+            if let Aggregate::Gauge { last, .. } = bucket.aggregate {
+                *gauge = last;
+            }
+            bucket.aggregate.add(entry, gauge);
+            return;
+        }
+        let aggregate = Aggregate::new(entry, gauge);
+        self.buckets.insert(
+            end,
+            Bucket {
+                ms,
+                label,
+                aggregate,
+            },
+        );
+    }
+
+    /// A breakdown of the heap memory held by the buckets of this rollup
+    pub fn memory_usage(&self) -> MemoryUsage {
+        let sketches = self.buckets.iter().filter_map(|b| match &b.aggregate {
+            Aggregate::Histogram(sketch) => Some(sketch.memory_usage()),
+            _ => None,
+        });
+        MemoryUsage::events(&self.buckets) + sketches.sum()
+    }
+
+    /// Append the step and buckets of this rollup to `out`, see [`crate::capture`]
+    pub(crate) fn encode(&self, out: &mut Vec<u8>) {
+        codec::write_varint(out, u128::from(self.step_ms));
+        codec::write_varint(out, self.buckets.len() as u128);
+        for bucket in &self.buckets {
+            codec::write_varint(out, u128::from(bucket.ms));
+            codec::write_varint(out, bucket.label as u128);
+            match &bucket.aggregate {
+                Aggregate::Counter { value, op } => {
+                    out.push(codec::COUNTER + codec::op_bits(*op));
+                    codec::write_varint(out, u128::from(*value));
+                }
+                Aggregate::Gauge { last, min, max } => {
+                    out.push(codec::GAUGE);
+                    for value in [last, min, max] {
+                        out.extend_from_slice(&value.to_le_bytes());
+                    }
+                }
+                Aggregate::Histogram(sketch) => {
+                    out.push(codec::HISTOGRAM);
+                    sketch.encode(out);
+                }
+            }
+        }
+    }
+
+    /// Decode a rollup written by [`Rollup::encode`], `float_bytes` is the width of the gauge
+    /// values in the build that wrote it
+    pub(crate) fn decode(reader: &mut Reader<'_>, float_bytes: u8) -> Result<Self, DecodeError> {
+        let step_ms = reader.varint_u64()?;
+        let step_ms = match u16::try_from(step_ms) {
+            Ok(0) => return Err(DecodeError::ZeroStep),
+            Ok(step_ms) => step_ms,
+            Err(_) => return Err(DecodeError::InvalidVarint),
+        };
+        let len = reader.length()?;
+        let mut buckets = Vec::with_capacity(len);
+        for _ in 0..len {
+            let ms = reader.varint_u64()?;
+            let ms = u16::try_from(ms).map_err(|_| DecodeError::MsOutOfRange(ms as i64))?;
+            let label = reader.varint_u64()?;
+            let label =
+                LabelId::try_from(label).map_err(|_| DecodeError::LabelOutOfRange(label))?;
+            let aggregate = match reader.byte()? {
+                kind @ codec::COUNTER..codec::HISTOGRAM => Aggregate::Counter {
+                    op: codec::op(kind - codec::COUNTER),
+                    value: reader.varint_u64()?,
+                },
+                codec::GAUGE => Aggregate::Gauge {
+                    last: reader.float(float_bytes)?,
+                    min: reader.float(float_bytes)?,
+                    max: reader.float(float_bytes)?,
+                },
+                codec::HISTOGRAM => Aggregate::Histogram(Sketch::decode(reader)?),
+                kind => return Err(DecodeError::UnknownKind(kind)),
+            };
+            buckets.push(Bucket {
+                ms,
+                label,
+                aggregate,
+            });
+        }
+        Ok(Self { step_ms, buckets })
+    }
+}
+
+/// The relative error of the quantiles estimated by a [`Sketch`]
+const RELATIVE_ACCURACY: f64 = 0.02;
+
+/// The ratio between the upper and lower bounds of each bucket of a [`Sketch`]
+fn gamma() -> f64 {
+    (1.0 + RELATIVE_ACCURACY) / (1.0 - RELATIVE_ACCURACY)
+}
+
+/// A summary of histogram values that can estimate any quantile within 2% of the actual
+/// value, values are counted in logarithmically sized buckets (like DDSketch) so the size of a
+/// sketch depends on the range of the values and not how many were recorded
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Sketch {
+    /// The number of values recorded
+    pub count: u64,
+    /// The sum of every value recorded
+    pub sum: f64,
+    /// The smallest value recorded
+    pub min: f64,
+    /// The largest value recorded
+    pub max: f64,
+    /// The number of values too close to zero to have a bucket
+    zeros: u64,
+    /// The bucket index and count of the positive values, ordered by index
+    positive: Vec<(i32, u32)>,
+    /// The bucket index and count of the negative values by their magnitude, ordered by index
+    negative: Vec<(i32, u32)>,
+}
+
+impl Default for Sketch {
+    fn default() -> Self {
+        Self {
+            count: 0,
+            sum: 0.0,
+            min: f64::INFINITY,
+            max: f64::NEG_INFINITY,
+            zeros: 0,
+            positive: Vec::new(),
+            negative: Vec::new(),
+        }
+    }
+}
+
+impl Sketch {
+    /// Record a new value, `NaN` is ignored
+    pub fn insert(&mut self, value: f64) {
+        if value.is_nan() {
+            return;
+        }
+        self.count += 1;
+        self.sum += value;
+        self.min = self.min.min(value);
+        self.max = self.max.max(value);
+        if value.abs() < f64::MIN_POSITIVE {
+            self.zeros += 1;
+        } else if value > 0.0 {
+            increment(&mut self.positive, bucket_index(value), 1);
+        } else {
+            increment(&mut self.negative, bucket_index(-value), 1);
+        }
+    }
+
+    /// Add every value recorded by `other` to this sketch
+    pub fn merge(&mut self, other: &Sketch) {
+        self.count += other.count;
+        self.sum += other.sum;
+        self.min = self.min.min(other.min);
+        self.max = self.max.max(other.max);
+        self.zeros += other.zeros;
+        for &(index, count) in &other.positive {
+            increment(&mut self.positive, index, count);
+        }
+        for &(index, count) in &other.negative {
+            increment(&mut self.negative, index, count);
+        }
+    }
+
+    /// The mean of the recorded values, zero if nothing was recorded
+    pub fn mean(&self) -> f64 {
+        if self.count == 0 {
+            return 0.0;
+        }
+        self.sum / self.count as f64
+    }
+
+    /// Estimate the value at the provided quantile (between 0 and 1), `None` if nothing
+    /// was recorded
+    pub fn quantile(&self, quantile: f64) -> Option<f64> {
+        if self.count == 0 {
+            return None;
+        }
+        let rank = (quantile.clamp(0.0, 1.0) * (self.count - 1) as f64).round() as u64;
+        // the extremes are known exactly
+        if rank == 0 {
+            return Some(self.min);
+        }
+        if rank == self.count - 1 {
+            return Some(self.max);
+        }
+        let mut seen = 0;
+        // from the most negative value to the most positive
+        let values = self
+            .negative
+            .iter()
+            .rev()
+            .map(|(index, count)| (-bucket_value(*index), u64::from(*count)))
+            .chain(std::iter::once((0.0, self.zeros)))
+            .chain(
+                self.positive
+                    .iter()
+                    .map(|(index, count)| (bucket_value(*index), u64::from(*count))),
+            );
+        for (value, count) in values {
+            seen += count;
+            if seen > rank {
+                return Some(value.clamp(self.min, self.max));
+            }
+        }
+        Some(self.max)
+    }
+
+    /// A breakdown of the heap memory held by the buckets of this sketch
+    pub fn memory_usage(&self) -> MemoryUsage {
+        MemoryUsage::events(&self.positive) + MemoryUsage::events(&self.negative)
+    }
+
+    fn encode(&self, out: &mut Vec<u8>) {
+        codec::write_varint(out, u128::from(self.count));
+        for value in [self.sum, self.min, self.max] {
+            out.extend_from_slice(&value.to_le_bytes());
+        }
+        codec::write_varint(out, u128::from(self.zeros));
+        for buckets in [&self.positive, &self.negative] {
+            codec::write_varint(out, buckets.len() as u128);
+            for (index, count) in buckets {
+                codec::write_varint(out, codec::zigzag(i128::from(*index)));
+                codec::write_varint(out, u128::from(*count));
+            }
+        }
+    }
+
+    fn decode(reader: &mut Reader<'_>) -> Result<Self, DecodeError> {
+        let mut ret = Self {
+            count: reader.varint_u64()?,
+            sum: reader.f64()?,
+            min: reader.f64()?,
+            max: reader.f64()?,
+            zeros: reader.varint_u64()?,
+            ..Default::default()
+        };
+        for buckets in [&mut ret.positive, &mut ret.negative] {
+            let len = reader.length()?;
+            buckets.reserve_exact(len);
+            for _ in 0..len {
+                let index = i32::try_from(codec::unzigzag(reader.varint()?))
+                    .map_err(|_| DecodeError::InvalidVarint)?;
+                let count =
+                    u32::try_from(reader.varint_u64()?).map_err(|_| DecodeError::InvalidVarint)?;
+                buckets.push((index, count));
+            }
+        }
+        Ok(ret)
+    }
+}
+
+/// The bucket a positive value is counted in
+fn bucket_index(value: f64) -> i32 {
+    (value.ln() / gamma().ln()).ceil() as i32
+}
+
+/// The value representing a bucket, within the relative accuracy of every value in it
+fn bucket_value(index: i32) -> f64 {
+    2.0 * gamma().powi(index) / (gamma() + 1.0)
+}
+
+fn increment(buckets: &mut Vec<(i32, u32)>, index: i32, count: u32) {
+    match buckets.binary_search_by_key(&index, |(i, _)| *i) {
+        Ok(found) => buckets[found].1 = buckets[found].1.saturating_add(count),
+        Err(insert) => buckets.insert(insert, (index, count)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::event::Event;
+
+    use super::*;
+
+    #[test]
+    fn sketch_quantiles() {
+        let mut sketch = Sketch::default();
+        assert_eq!(sketch.quantile(0.5), None);
+        for value in 1..=10_000 {
+            sketch.insert(f64::from(value));
+        }
+        assert_eq!(sketch.count, 10_000);
+        assert_eq!(sketch.mean(), 5000.5);
+        for (quantile, expected) in [(0.0, 1.0), (0.5, 5000.0), (0.99, 9900.0), (1.0, 10_000.0)] {
+            let actual = sketch.quantile(quantile).unwrap();
+            assert!(
+                (actual - expected).abs() <= expected * RELATIVE_ACCURACY,
+                "p{quantile}: {actual} != {expected}"
+            );
+        }
+        // the size depends on the range of values, not the count
+        assert!(sketch.positive.len() < 500);
+        let mut mixed = Sketch::default();
+        for value in [-10.0, -2.0, 0.0, 1.0, 10.0] {
+            mixed.insert(value);
+        }
+        assert_eq!(mixed.quantile(0.0), Some(-10.0));
+        assert_eq!(mixed.quantile(0.5), Some(0.0));
+        assert_eq!(mixed.quantile(1.0), Some(10.0));
+        assert!((mixed.quantile(0.25).unwrap() + 2.0).abs() <= 2.0 * RELATIVE_ACCURACY);
+    }
+
+    #[test]
+    fn aggregates_per_step() {
+        let mut events = Events::default();
+        let entries = [
+            Entry::Counter {
+                value: 1,
+                op: Op::Add,
+            },
+            Entry::Gauge {
+                value: 1.0,
+                op: Op::Add,
+            },
+            Entry::Histogram { value: 2.0 },
+        ];
+        for ms in 0..30_000u16 {
+            let entry = events.raw_entry(entries[usize::from(ms % 3)]);
+            events.push(Event {
+                entry,
+                ms,
+                label: LabelId::from(ms % 3),
+            });
+        }
+        let mut gauges = [(1, 10.0)].into_iter().collect();
+        let rollup = Rollup::from_events(&events, 10_000, &mut gauges);
+        assert_eq!(rollup.buckets.len(), 9);
+        assert!(rollup.buckets.is_sorted_by_key(|b| b.ms));
+        let counters: Vec<_> = rollup
+            .buckets
+            .iter()
+            .filter(|b| b.label == 0)
+            .map(|b| (b.ms, b.aggregate.entry()))
+            .collect();
+        assert_eq!(
+            counters,
+            [(0, 3334), (10_000, 3333), (20_000, 3333)]
+                .map(|(ms, value)| (ms, Entry::Counter { value, op: Op::Add }))
+        );
+        let Aggregate::Gauge { last, min, max } = rollup.buckets[1].aggregate else {
+            panic!("expected a gauge");
+        };
+        assert_eq!((last, min, max), (3343.0, 11.0, 3343.0));
+        assert_eq!(gauges[&1], 10_010.0);
+        let Aggregate::Histogram(sketch) = &rollup.buckets[2].aggregate else {
+            panic!("expected a histogram");
+        };
+        assert_eq!(sketch.count, 3333);
+        assert_eq!(sketch.mean(), 2.0);
+        // an absolute value resets the sum
+        let mut rollup = rollup;
+        rollup.insert(
+            25_000,
+            0,
+            Entry::Counter {
+                value: 7,
+                op: Op::Set,
+            },
+        );
+        rollup.insert(
+            29_999,
+            0,
+            Entry::Counter {
+                value: 1,
+                op: Op::Add,
+            },
+        );
+        let bucket = rollup
+            .buckets
+            .iter()
+            .find(|b| b.ms == 20_000 && b.label == 0);
+        assert_eq!(
+            bucket.unwrap().aggregate.entry(),
+            Entry::Counter {
+                value: 8,
+                op: Op::Set
+            }
+        );
+    }
+
+    #[test]
+    fn wide_counters() {
+        let wide = Entry::Counter {
+            value: u64::from(u32::MAX) + 1,
+            op: Op::Add,
+        };
+        let mut events = Events::default();
+        let entry = events.raw_entry(wide);
+        let event = Event {
+            entry,
+            ms: 0,
+            label: 0,
+        };
+        assert_eq!(events.entry(&event), Some(wide));
+        assert_eq!(Events::default().entry(&event), None);
+        events.push(event);
+        let mut chunk = crate::chunk::Chunk::from_rollup(
+            time::OffsetDateTime::UNIX_EPOCH,
+            Rollup::from_events(&events, 1_000, &mut BTreeMap::new()),
+        );
+        chunk.push_entry(10, 0, wide);
+        let bucket = &chunk.rollup().unwrap().buckets[0];
+        assert_eq!(
+            bucket.aggregate.entry(),
+            Entry::Counter {
+                value: 2 * (u64::from(u32::MAX) + 1),
+                op: Op::Add
+            }
+        );
+    }
+
+    #[test]
+    fn zero_step() {
+        let rollup = Rollup {
+            step_ms: 0,
+            buckets: Vec::new(),
+        };
+        let chunk = crate::chunk::Chunk::from_rollup(time::OffsetDateTime::UNIX_EPOCH, rollup);
+        assert_eq!(
+            crate::chunk::Chunk::from_bytes(&chunk.to_bytes()),
+            Err(DecodeError::ZeroStep)
+        );
+        let json = serde_json::to_string(&chunk).unwrap();
+        let err = serde_json::from_str::<crate::chunk::Chunk>(&json).unwrap_err();
+        assert!(err.to_string().contains("step of 0"), "{err}");
+        let json = serde_json::to_string(&Rollup::new(0)).unwrap();
+        assert_eq!(
+            serde_json::from_str::<Rollup>(&json).unwrap(),
+            Rollup::new(1)
+        );
+    }
+}
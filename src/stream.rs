@@ -0,0 +1,516 @@
+//! Streaming decoders yielding [`Metric`]s from any [`std::io::Read`], for the json-lines, json
+//! array and postcard formats produced by the `serialize` example
+//!
+//! Only a single metric is held in memory at a time no matter how large the input is, the
+//! json formats need the `json` feature and postcard the `postcard` feature
+#[cfg(feature = "json")]
+use std::io::BufRead;
+use std::{
+    fmt::Display,
+    io::{self, BufReader, Read},
+};
+
+use crate::iter::Metric;
+
+/// An error while streaming metrics
+#[derive(Debug)]
+pub enum StreamError {
+    /// Reading from the source failed, this includes running out of input part way through a
+    /// metric
+    Io(io::Error),
+    /// A metric was not valid json or not a [`Metric`]
+    #[cfg(feature = "json")]
+    Json(serde_json::Error),
+    /// A metric was not a postcard encoded [`Metric`]
+    #[cfg(feature = "postcard")]
+    Postcard(postcard::Error),
+    /// A postcard encoded metric needed more scratch space than the limit set on [`Postcard`]
+    #[cfg(feature = "postcard")]
+    ScratchLimit(usize),
+    /// A byte other than what was expected was found in between the elements of a json array
+    UnexpectedByte { expected: &'static str, found: u8 },
+}
+
+impl Display for StreamError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Io(e) => write!(f, "failed to read metrics: {e}"),
+            #[cfg(feature = "json")]
+            Self::Json(e) => write!(f, "invalid json metric: {e}"),
+            #[cfg(feature = "postcard")]
+            Self::Postcard(e) => write!(f, "invalid postcard metric: {e}"),
+            #[cfg(feature = "postcard")]
+            Self::ScratchLimit(limit) => {
+                write!(
+                    f,
+                    "a postcard metric needs more than {limit} bytes of scratch space"
+                )
+            }
+            Self::UnexpectedByte { expected, found } => write!(
+                f,
+                "expected {expected} found `{}`",
+                char::from(*found).escape_default()
+            ),
+        }
+    }
+}
+
+impl std::error::Error for StreamError {}
+
+impl From<io::Error> for StreamError {
+    fn from(value: io::Error) -> Self {
+        Self::Io(value)
+    }
+}
+
+/// Metrics from json-lines, one [`Metric`] per line. Blank lines are skipped and a line that
+/// fails to parse is reported without ending the iteration
+#[cfg(feature = "json")]
+pub struct JsonLines<R> {
+    reader: BufReader<R>,
+    line: String,
+}
+
+#[cfg(feature = "json")]
+impl<R: Read> JsonLines<R> {
+    /// Stream metrics from the provided reader, it is buffered internally
+    pub fn new(reader: R) -> Self {
+        Self {
+            reader: BufReader::new(reader),
+            line: String::new(),
+        }
+    }
+}
+
+#[cfg(feature = "json")]
+impl<R: Read> Iterator for JsonLines<R> {
+    type Item = Result<Metric, StreamError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            self.line.clear();
+            match self.reader.read_line(&mut self.line) {
+                Ok(0) => return None,
+                Ok(_) if self.line.trim().is_empty() => continue,
+                Ok(_) => return Some(serde_json::from_str(&self.line).map_err(StreamError::Json)),
+                Err(e) => return Some(Err(e.into())),
+            }
+        }
+    }
+}
+
+/// Metrics from a json array of [`Metric`]s, each element is parsed as soon as its closing
+/// brace is read. The first error ends the iteration
+#[cfg(feature = "json")]
+pub struct JsonArray<R> {
+    reader: BufReader<R>,
+    /// The bytes of the current element
+    element: Vec<u8>,
+    started: bool,
+    done: bool,
+}
+
+#[cfg(feature = "json")]
+impl<R: Read> JsonArray<R> {
+    /// Stream metrics from the provided reader, it is buffered internally
+    pub fn new(reader: R) -> Self {
+        Self {
+            reader: BufReader::new(reader),
+            element: Vec::new(),
+            started: false,
+            done: false,
+        }
+    }
+
+    /// The next metric, or `None` after the closing bracket
+    fn next_metric(&mut self) -> Result<Option<Metric>, StreamError> {
+        let first = if self.started {
+            match self.next_token()? {
+                b',' => self.next_token()?,
+                b']' => return self.finish(),
+                found => {
+                    return Err(StreamError::UnexpectedByte {
+                        expected: "`,` or `]`",
+                        found,
+                    });
+                }
+            }
+        } else {
+            match self.next_token()? {
+                b'[' => self.started = true,
+                found => {
+                    return Err(StreamError::UnexpectedByte {
+                        expected: "`[`",
+                        found,
+                    });
+                }
+            }
+            match self.next_token()? {
+                b']' => return self.finish(),
+                first => first,
+            }
+        };
+        if first != b'{' {
+            return Err(StreamError::UnexpectedByte {
+                expected: "`{`",
+                found: first,
+            });
+        }
+        self.read_object()?;
+        serde_json::from_slice(&self.element)
+            .map(Some)
+            .map_err(StreamError::Json)
+    }
+
+    /// Read the rest of an object after its opening brace into `element`, tracking nesting and
+    /// strings so braces inside of them don't end it early
+    fn read_object(&mut self) -> Result<(), StreamError> {
+        self.element.clear();
+        self.element.push(b'{');
+        let mut depth = 1usize;
+        let mut in_string = false;
+        let mut escaped = false;
+        while depth > 0 {
+            let byte = self
+                .byte()?
+                .ok_or(io::Error::from(io::ErrorKind::UnexpectedEof))?;
+            self.element.push(byte);
+            match byte {
+                _ if escaped => escaped = false,
+                b'\\' if in_string => escaped = true,
+                b'"' => in_string = !in_string,
+                _ if in_string => {}
+                b'{' | b'[' => depth += 1,
+                b'}' | b']' => depth -= 1,
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+
+    /// After the closing bracket only whitespace may remain
+    fn finish(&mut self) -> Result<Option<Metric>, StreamError> {
+        match self.non_whitespace()? {
+            None => Ok(None),
+            Some(found) => Err(StreamError::UnexpectedByte {
+                expected: "the end of the input",
+                found,
+            }),
+        }
+    }
+
+    /// The next byte that isn't whitespace, running out of input is an error
+    fn next_token(&mut self) -> Result<u8, StreamError> {
+        self.non_whitespace()?
+            .ok_or_else(|| io::Error::from(io::ErrorKind::UnexpectedEof).into())
+    }
+
+    fn non_whitespace(&mut self) -> io::Result<Option<u8>> {
+        while let Some(byte) = self.byte()? {
+            if !byte.is_ascii_whitespace() {
+                return Ok(Some(byte));
+            }
+        }
+        Ok(None)
+    }
+
+    fn byte(&mut self) -> io::Result<Option<u8>> {
+        let Some(&byte) = self.reader.fill_buf()?.first() else {
+            return Ok(None);
+        };
+        self.reader.consume(1);
+        Ok(Some(byte))
+    }
+}
+
+#[cfg(feature = "json")]
+impl<R: Read> Iterator for JsonArray<R> {
+    type Item = Result<Metric, StreamError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+        match self.next_metric() {
+            Ok(Some(metric)) => Some(Ok(metric)),
+            Ok(None) => {
+                self.done = true;
+                None
+            }
+            Err(e) => {
+                self.done = true;
+                Some(Err(e))
+            }
+        }
+    }
+}
+
+/// The scratch space a [`Postcard`] starts with, it is doubled whenever a metric needs more
+#[cfg(feature = "postcard")]
+const SCRATCH_LEN: usize = 64 * 1024;
+
+/// The default limit on the scratch space of a [`Postcard`], see [`Postcard::with_scratch_limit`]
+#[cfg(feature = "postcard")]
+pub const SCRATCH_LIMIT: usize = 16 * 1024 * 1024;
+
+/// Metrics from a postcard encoded sequence of [`Metric`]s. The first error ends the iteration
+#[cfg(feature = "postcard")]
+pub struct Postcard<R> {
+    reader: Replay<R>,
+    /// The number of metrics left to read, `None` until the length of the sequence is read
+    remaining: Option<u64>,
+    /// Space for postcard to read each string into before it is copied into the [`Metric`]
+    scratch: Vec<u8>,
+    scratch_limit: usize,
+}
+
+#[cfg(feature = "postcard")]
+impl<R: Read> Postcard<R> {
+    /// Stream metrics from the provided reader, it is buffered internally
+    pub fn new(reader: R) -> Self {
+        Self::with_scratch_limit(reader, SCRATCH_LIMIT)
+    }
+
+    /// Stream metrics from the provided reader, growing the scratch space used for the strings of
+    /// each metric up to `limit` bytes. A metric needing more is reported as
+    /// [`StreamError::ScratchLimit`]
+    pub fn with_scratch_limit(reader: R, limit: usize) -> Self {
+        Self {
+            reader: Replay {
+                reader: BufReader::new(reader),
+                consumed: Vec::new(),
+                position: 0,
+                ended: false,
+            },
+            remaining: None,
+            scratch: vec![0; SCRATCH_LEN.min(limit)],
+            scratch_limit: limit,
+        }
+    }
+
+    /// Decode the next value, when the scratch space runs out before the input does it is doubled
+    /// and the bytes read so far are replayed
+    fn read<T: serde::de::DeserializeOwned>(&mut self) -> Result<T, StreamError> {
+        loop {
+            self.reader.rewind();
+            let ret =
+                postcard::from_io((&mut self.reader, &mut self.scratch)).map(|(value, _)| value);
+            match ret {
+                Ok(value) => {
+                    self.reader.consumed.clear();
+                    return Ok(value);
+                }
+                Err(postcard::Error::DeserializeUnexpectedEnd) if !self.reader.ended => {
+                    if self.scratch.len() >= self.scratch_limit {
+                        return Err(StreamError::ScratchLimit(self.scratch_limit));
+                    }
+                    let len = (self.scratch.len() * 2).clamp(1, self.scratch_limit);
+                    self.scratch.resize(len, 0);
+                }
+                Err(e) => return Err(StreamError::Postcard(e)),
+            }
+        }
+    }
+}
+
+/// Keeps the bytes read for the current value so they can be read again when decoding it is
+/// retried with more scratch space
+#[cfg(feature = "postcard")]
+struct Replay<R> {
+    reader: BufReader<R>,
+    consumed: Vec<u8>,
+    /// How much of `consumed` has been read again since the last rewind
+    position: usize,
+    /// Whether the reader ran out of input (or failed) since the last rewind
+    ended: bool,
+}
+
+#[cfg(feature = "postcard")]
+impl<R> Replay<R> {
+    fn rewind(&mut self) {
+        self.position = 0;
+        self.ended = false;
+    }
+}
+
+#[cfg(feature = "postcard")]
+impl<R: Read> Read for Replay<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.position < self.consumed.len() {
+            let len = (&self.consumed[self.position..]).read(buf)?;
+            self.position += len;
+            return Ok(len);
+        }
+        match self.reader.read(buf) {
+            Ok(0) if !buf.is_empty() => {
+                self.ended = true;
+                Ok(0)
+            }
+            Ok(len) => {
+                self.consumed.extend_from_slice(&buf[..len]);
+                self.position = self.consumed.len();
+                Ok(len)
+            }
+            Err(e) => {
+                self.ended = true;
+                Err(e)
+            }
+        }
+    }
+}
+
+#[cfg(feature = "postcard")]
+impl<R: Read> Iterator for Postcard<R> {
+    type Item = Result<Metric, StreamError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let remaining = match self.remaining {
+            Some(remaining) => remaining,
+            // a sequence starts with its length as a varint, the same as a u64
+            None => match self.read::<u64>() {
+                Ok(len) => len,
+                Err(e) => {
+                    self.remaining = Some(0);
+                    return Some(Err(e));
+                }
+            },
+        };
+        if remaining == 0 {
+            self.remaining = Some(0);
+            return None;
+        }
+        let ret = self.read();
+        self.remaining = Some(if ret.is_ok() { remaining - 1 } else { 0 });
+        Some(ret)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use metrics::{Key, Label};
+
+    use crate::{
+        event::{Entry, Op},
+        iter::MetricRef,
+        procession::Procession,
+        test_util,
+    };
+
+    use super::*;
+
+    /// The shared series with a label value and a description that need escaping in json
+    fn procession() -> Procession {
+        let mut procession = test_util::procession();
+        procession.describe(
+            "requests".into(),
+            None,
+            "requests with \"quotes\", {braces} and \\".into(),
+        );
+        let depth = procession
+            .ensure_label(&Key::from_parts("depth", vec![Label::new("queue", "a}]")]))
+            .unwrap();
+        procession.insert_entry(
+            Entry::Gauge {
+                value: 3.0,
+                op: Op::Set,
+            },
+            depth,
+        );
+        procession
+    }
+
+    fn assert_streams(
+        procession: &Procession,
+        metrics: impl Iterator<Item = Result<Metric, StreamError>>,
+    ) {
+        let expected: Vec<MetricRef> = procession.iter().collect();
+        let metrics: Vec<Metric> = metrics.map(Result::unwrap).collect();
+        assert_eq!(metrics.len(), expected.len());
+        for (metric, expected) in metrics.into_iter().zip(expected) {
+            assert_eq!(metric, expected);
+        }
+    }
+
+    #[cfg(feature = "json")]
+    #[test]
+    fn json() {
+        let procession = procession();
+        let mut lines = Vec::new();
+        for metric in procession.iter() {
+            serde_json::to_writer(&mut lines, &metric).unwrap();
+            lines.extend_from_slice(b"\n\n");
+        }
+        assert_streams(&procession, JsonLines::new(lines.as_slice()));
+        let metrics: Vec<MetricRef> = procession.iter().collect();
+        let array = serde_json::to_vec_pretty(&metrics).unwrap();
+        assert_streams(&procession, JsonArray::new(array.as_slice()));
+        assert_eq!(JsonArray::new(&b" [ ] "[..]).count(), 0);
+    }
+
+    #[cfg(feature = "json")]
+    #[test]
+    fn json_errors() {
+        let mut lines = JsonLines::new(&b"{}\n\n[]"[..]);
+        assert!(matches!(lines.next(), Some(Err(StreamError::Json(_)))));
+        assert!(matches!(lines.next(), Some(Err(StreamError::Json(_)))));
+        assert!(lines.next().is_none());
+
+        for (input, expected) in [(&b"{}"[..], b'{'), (b"[1]", b'1'), (b"[] x", b'x')] {
+            let mut array = JsonArray::new(input);
+            assert!(
+                matches!(array.next(), Some(Err(StreamError::UnexpectedByte { found, .. })) if found == expected),
+                "{}",
+                String::from_utf8_lossy(input)
+            );
+            assert!(array.next().is_none());
+        }
+        let procession = procession();
+        let metrics: Vec<MetricRef> = procession.iter().collect();
+        let array = serde_json::to_vec(&metrics).unwrap();
+        let mut array = JsonArray::new(&array[..array.len() - 10]);
+        assert!(matches!(
+            array.find_map(Result::err),
+            Some(StreamError::Io(e)) if e.kind() == io::ErrorKind::UnexpectedEof
+        ));
+        assert!(array.next().is_none());
+    }
+
+    #[cfg(feature = "postcard")]
+    #[test]
+    fn postcard() {
+        let procession = procession();
+        let metrics: Vec<Metric> = procession.iter_owned().collect();
+        let bytes = postcard::to_stdvec(&metrics).unwrap();
+        assert_streams(&procession, Postcard::new(bytes.as_slice()));
+        let mut truncated = Postcard::new(&bytes[..bytes.len() - 1]);
+        assert!(matches!(
+            truncated.find_map(Result::err),
+            Some(StreamError::Postcard(_))
+        ));
+        assert!(truncated.next().is_none());
+    }
+
+    #[cfg(feature = "postcard")]
+    #[test]
+    fn postcard_long_strings() {
+        let mut procession = Procession::default();
+        let name = "a".repeat(3 * SCRATCH_LEN);
+        let label = procession.ensure_label(&Key::from_name(name)).unwrap();
+        procession.insert_entry(
+            Entry::Counter {
+                value: 1,
+                op: Op::Add,
+            },
+            label,
+        );
+        let metrics: Vec<Metric> = procession.iter_owned().collect();
+        let bytes = postcard::to_stdvec(&metrics).unwrap();
+        assert_streams(&procession, Postcard::new(bytes.as_slice()));
+        let mut limited = Postcard::with_scratch_limit(bytes.as_slice(), 2 * SCRATCH_LEN);
+        assert!(matches!(
+            limited.next(),
+            Some(Err(StreamError::ScratchLimit(limit))) if limit == 2 * SCRATCH_LEN
+        ));
+        assert!(limited.next().is_none());
+    }
+}
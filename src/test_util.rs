@@ -0,0 +1,65 @@
+//! Fixtures shared by the tests of several modules
+
+use std::sync::Arc;
+
+use metrics::{Key, KeyName, Label, Unit};
+use time::{Date, Duration, Month, OffsetDateTime, Time};
+
+use crate::{
+    clock::ManualClock,
+    codec::SealOptions,
+    event::{Entry, Float, Op},
+    procession::Procession,
+};
+
+/// The time every test clock starts at
+pub fn start() -> OffsetDateTime {
+    OffsetDateTime::new_utc(
+        Date::from_calendar_date(2025, Month::January, 1).unwrap(),
+        Time::MIDNIGHT,
+    )
+}
+
+/// A sealed series of 2,000 events over four keys, one every 250ms from [`start`], with its first
+/// two minutes rolled up into 10 second buckets
+pub fn procession() -> Procession {
+    let clock = Arc::new(ManualClock::new(start()));
+    let mut procession = Procession::default()
+        .with_clock(clock.clone())
+        .with_sealing(Some(SealOptions::default()));
+    let keys = [
+        Key::from_parts("requests", vec![Label::new("service", "api")]),
+        Key::from_parts("requests", vec![Label::new("service", "worker")]),
+        Key::from_name("queue_depth"),
+        Key::from_name("latency"),
+    ];
+    let labels: Vec<_> = keys
+        .iter()
+        .map(|k| procession.ensure_label(k).unwrap())
+        .collect();
+    procession.describe(
+        KeyName::from("latency"),
+        Some(Unit::Seconds),
+        "how long each request took".into(),
+    );
+    procession.describe(KeyName::from("requests"), None, "requests handled".into());
+    for i in 0..2_000u16 {
+        let entry = match i % 4 {
+            0 | 1 => Entry::Counter {
+                value: u64::from(i),
+                op: Op::Add,
+            },
+            2 => Entry::Gauge {
+                value: Float::from(i % 50),
+                op: Op::Set,
+            },
+            _ => Entry::Histogram {
+                value: Float::from(i) / 8.0,
+            },
+        };
+        procession.insert_entry(entry, labels[usize::from(i % 4)]);
+        clock.advance(Duration::milliseconds(250));
+    }
+    procession.roll_up(start() + Duration::minutes(2), Duration::seconds(10));
+    procession
+}
@@ -0,0 +1,277 @@
+//! Structural checks for a [`Procession`], a capture that was corrupted or edited by hand can
+//! deserialize without complaint while breaking the assumptions the rest of this crate makes
+use std::{collections::BTreeSet, fmt::Display};
+
+use time::OffsetDateTime;
+
+use crate::{event::RawEntry, label_set::LabelId, procession::Procession};
+
+/// An invariant of a [`Procession`] that doesn't hold, chunks are identified by their position
+/// in [`Procession::chunks`] and events (or buckets) by their position in that chunk
+#[derive(Debug, Clone, PartialEq)]
+pub enum ValidationError {
+    /// More than one key in the [`crate::label_set::LabelSet`] has this label identifier
+    DuplicateLabel(LabelId),
+    /// An event or bucket refers to a label identifier that isn't in the
+    /// [`crate::label_set::LabelSet`], iterating would report it with an empty key
+    UnknownLabel {
+        chunk: usize,
+        event: usize,
+        label: LabelId,
+    },
+    /// An event (or bucket) has a smaller `ms` value than the one before it
+    UnorderedMs {
+        chunk: usize,
+        event: usize,
+        ms: u16,
+        previous: u16,
+    },
+    /// A chunk has an earlier `reference_time` than the chunk before it
+    UnorderedChunk {
+        chunk: usize,
+        reference_time: OffsetDateTime,
+        previous: OffsetDateTime,
+    },
+    /// A wide counter refers to a value past the end of its chunk's `wide_values`
+    MissingWideValue {
+        chunk: usize,
+        event: usize,
+        index: u32,
+    },
+}
+
+impl Display for ValidationError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::DuplicateLabel(label) => {
+                write!(f, "label id {label} is assigned to more than one key")
+            }
+            Self::UnknownLabel {
+                chunk,
+                event,
+                label,
+            } => write!(
+                f,
+                "event {event} of chunk {chunk} has unknown label id {label}"
+            ),
+            Self::UnorderedMs {
+                chunk,
+                event,
+                ms,
+                previous,
+            } => write!(
+                f,
+                "event {event} of chunk {chunk} at {ms}ms is before the previous event at {previous}ms"
+            ),
+            Self::UnorderedChunk {
+                chunk,
+                reference_time,
+                previous,
+            } => write!(
+                f,
+                "chunk {chunk} starts at {reference_time} which is before the previous chunk at {previous}"
+            ),
+            Self::MissingWideValue {
+                chunk,
+                event,
+                index,
+            } => write!(
+                f,
+                "event {event} of chunk {chunk} refers to missing wide value {index}"
+            ),
+        }
+    }
+}
+
+impl std::error::Error for ValidationError {}
+
+impl Procession {
+    /// Check the structural invariants of this series, returning the first one that doesn't
+    /// hold. Every label identifier must be assigned to exactly one key, the events of each
+    /// chunk must be in `ms` order and the chunks must be in `reference_time` order. Any sealed
+    /// chunks are decoded to check their events
+    pub fn validate(&self) -> Result<(), ValidationError> {
+        let mut ids = BTreeSet::new();
+        for (_, label) in self.labels.iter() {
+            if !ids.insert(label) {
+                return Err(ValidationError::DuplicateLabel(label));
+            }
+        }
+        let mut previous_time = None;
+        for (chunk_index, chunk) in self.chunks.iter().enumerate() {
+            if let Some(previous) = previous_time
+                && chunk.reference_time < previous
+            {
+                return Err(ValidationError::UnorderedChunk {
+                    chunk: chunk_index,
+                    reference_time: chunk.reference_time,
+                    previous,
+                });
+            }
+            previous_time = Some(chunk.reference_time);
+            let events = chunk.events();
+            let items: Vec<(u16, LabelId)> = match chunk.rollup() {
+                Some(rollup) => rollup.buckets.iter().map(|b| (b.ms, b.label)).collect(),
+                None => events.iter().map(|e| (e.ms, e.label)).collect(),
+            };
+            let mut previous_ms = 0;
+            for (event, (ms, label)) in items.into_iter().enumerate() {
+                if !ids.contains(&label) {
+                    return Err(ValidationError::UnknownLabel {
+                        chunk: chunk_index,
+                        event,
+                        label,
+                    });
+                }
+                if ms < previous_ms {
+                    return Err(ValidationError::UnorderedMs {
+                        chunk: chunk_index,
+                        event,
+                        ms,
+                        previous: previous_ms,
+                    });
+                }
+                previous_ms = ms;
+            }
+            for (event, e) in events.iter().enumerate() {
+                if let RawEntry::WideCounter { index, .. } = e.entry
+                    && index as usize >= events.wide_values.len()
+                {
+                    return Err(ValidationError::MissingWideValue {
+                        chunk: chunk_index,
+                        event,
+                        index,
+                    });
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use metrics::Key;
+    use time::Duration;
+
+    use crate::{
+        chunk::{Chunk, Events},
+        codec::SealOptions,
+        event::{Entry, Event, Op},
+        label_set::LabelSet,
+    };
+
+    use super::*;
+
+    fn procession() -> Procession {
+        let mut procession = Procession::default().with_sealing(Some(SealOptions::default()));
+        let label = procession
+            .ensure_label(&Key::from_name("requests"))
+            .unwrap();
+        for i in 0..200 {
+            let entry = Entry::Counter {
+                value: 1 << (i % 40),
+                op: Op::Add,
+            };
+            procession.insert_entry_at(
+                OffsetDateTime::UNIX_EPOCH + Duration::seconds(i),
+                entry,
+                label,
+            );
+        }
+        procession
+    }
+
+    fn event(ms: u16, label: LabelId) -> Event {
+        Event {
+            entry: RawEntry::Counter {
+                value: 1,
+                op: Op::Add,
+            },
+            ms,
+            label,
+        }
+    }
+
+    #[test]
+    fn valid_series() {
+        let mut procession = procession();
+        assert!(procession.chunks.iter().any(|c| c.is_sealed()));
+        assert_eq!(procession.validate(), Ok(()));
+        procession.roll_up(
+            OffsetDateTime::UNIX_EPOCH + Duration::seconds(100),
+            Duration::seconds(10),
+        );
+        assert_eq!(procession.validate(), Ok(()));
+        assert_eq!(Procession::default().validate(), Ok(()));
+    }
+
+    #[test]
+    fn invalid_series() {
+        let mut unknown = procession();
+        let reference_time = unknown.chunks[1].reference_time;
+        unknown.chunks[1] = Chunk::from_events(
+            reference_time,
+            Events {
+                events: vec![event(0, 0), event(1, 7)],
+                wide_values: Vec::new(),
+            },
+        );
+        assert_eq!(
+            unknown.validate(),
+            Err(ValidationError::UnknownLabel {
+                chunk: 1,
+                event: 1,
+                label: 7
+            })
+        );
+
+        let mut unordered = procession();
+        let last = unordered.chunks.len() - 1;
+        let previous = unordered.chunks[last].last_ms();
+        unordered.chunks[last].push(event(0, 0));
+        assert_eq!(
+            unordered.validate(),
+            Err(ValidationError::UnorderedMs {
+                chunk: last,
+                event: unordered.chunks[last].len() - 1,
+                ms: 0,
+                previous,
+            })
+        );
+
+        let mut chunks = procession();
+        chunks.chunks.swap(0, 1);
+        assert_eq!(
+            chunks.validate(),
+            Err(ValidationError::UnorderedChunk {
+                chunk: 1,
+                reference_time: chunks.chunks[1].reference_time,
+                previous: chunks.chunks[0].reference_time,
+            })
+        );
+
+        let mut wide = procession();
+        wide.chunks[0].unseal();
+        let reference_time = wide.chunks[0].reference_time;
+        let mut events = wide.chunks[0].events().into_owned();
+        events.wide_values.clear();
+        wide.chunks[0] = Chunk::from_events(reference_time, events);
+        assert!(matches!(
+            wide.validate(),
+            Err(ValidationError::MissingWideValue { chunk: 0, .. })
+        ));
+
+        let mut duplicate = procession();
+        duplicate.labels = [
+            (Key::from_name("requests"), 0),
+            (Key::from_name("other"), 0),
+        ]
+        .into_iter()
+        .collect::<LabelSet>();
+        assert_eq!(
+            duplicate.validate(),
+            Err(ValidationError::DuplicateLabel(0))
+        );
+    }
+}
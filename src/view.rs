@@ -0,0 +1,534 @@
+//! A read-only view over the bytes of a [`crate::capture`], for captures too large to decode
+//! into a [`Procession`](crate::procession::Procession)
+//!
+//! The header, labels and descriptions are decoded once when the view is created while the
+//! chunks are only decoded one at a time as they are iterated. Each [`ChunkView`] borrows its
+//! encoded events from the capture and carries the [`ChunkIndex`] written ahead of them, so a
+//! chunk that can't match a [`Filter`] is skipped without being decoded. With the `mmap`
+//! feature a [`MappedCapture`] provides those bytes from a memory mapped file so even the
+//! encoded chunks are never read into memory as a whole
+use time::{Duration, OffsetDateTime};
+
+use crate::{
+    capture::{self, Frame, Header},
+    chunk::{Chunk, ChunkIndex},
+    codec::{DecodeError, Reader},
+    description::{Description, Descriptions},
+    event::Entry,
+    iter::{EMPTY_KEY, Filter, Metric, MetricRef},
+    label_set::{LabelId, LabelSet},
+    rollup::Aggregate,
+};
+
+use metrics::Key;
+
+/// The labels and descriptions of a capture along with its still encoded chunks
+#[derive(Debug)]
+pub struct ProcessionView<'a> {
+    header: Header,
+    labels: LabelSet,
+    descriptions: Descriptions,
+    /// The number of chunks in the capture
+    len: usize,
+    /// The encoded chunks
+    chunks: &'a [u8],
+}
+
+impl<'a> ProcessionView<'a> {
+    /// Create a view over the provided capture, decoding everything ahead of its chunks
+    pub fn new(bytes: &'a [u8]) -> Result<Self, DecodeError> {
+        let mut reader = Reader::new(bytes);
+        let header = capture::read_header(&mut reader)?;
+        let labels = capture::decode_labels(&mut reader)?;
+        let descriptions = capture::decode_descriptions(&mut reader)?;
+        let len = reader.length()?;
+        Ok(Self {
+            header,
+            labels,
+            descriptions,
+            len,
+            chunks: reader.rest(),
+        })
+    }
+
+    /// The header of the capture
+    pub fn header(&self) -> &Header {
+        &self.header
+    }
+
+    /// Every key in the capture along with its label identifier
+    pub fn labels(&self) -> &LabelSet {
+        &self.labels
+    }
+
+    /// The unit and help text of each metric name in the capture
+    pub fn descriptions(&self) -> &Descriptions {
+        &self.descriptions
+    }
+
+    /// The number of chunks in the capture
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    /// If the capture has no chunks
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Iterate over the chunks of the capture without decoding their events. A capture written
+    /// before version 3 of the format has no index for its chunks, so each one is decoded to
+    /// build its index
+    pub fn chunks(&self) -> Chunks<'_> {
+        Chunks {
+            header: &self.header,
+            reader: Reader::new(self.chunks),
+            index: 0,
+            len: self.len,
+            done: false,
+        }
+    }
+
+    /// Iterate over every event in the capture, decoding each chunk as it is reached
+    pub fn iter(&self) -> ViewIterator<'_> {
+        ViewIterator {
+            view: self,
+            chunks: self.chunks(),
+            chunk: None,
+            event_index: 0,
+            filter: None,
+            done: false,
+        }
+    }
+
+    /// Iterate over the events in the capture that match the provided [`Filter`], chunks whose
+    /// [`ChunkIndex`] cannot match are skipped without being decoded
+    pub fn iter_filtered(&self, filter: Filter) -> ViewIterator<'_> {
+        ViewIterator {
+            filter: Some(filter),
+            ..self.iter()
+        }
+    }
+}
+
+/// A chunk of a capture that hasn't been decoded yet, along with a summary of its events
+#[derive(Debug, Clone)]
+pub struct ChunkView<'a> {
+    header: &'a Header,
+    source: Source<'a>,
+}
+
+#[derive(Debug, Clone)]
+enum Source<'a> {
+    /// A chunk of a version 3 capture with its encoded events borrowed from the capture
+    Frame(Frame<'a>),
+    /// A chunk of an earlier capture, which had to be decoded to build its index
+    Decoded(Chunk, ChunkIndex),
+}
+
+impl<'a> ChunkView<'a> {
+    /// The start time of this chunk
+    pub fn reference_time(&self) -> OffsetDateTime {
+        match &self.source {
+            Source::Frame(frame) => frame.reference_time,
+            Source::Decoded(chunk, _) => chunk.reference_time,
+        }
+    }
+
+    /// A summary of the events in this chunk
+    pub fn index(&self) -> &ChunkIndex {
+        match &self.source {
+            Source::Frame(frame) => &frame.index,
+            Source::Decoded(_, index) => index,
+        }
+    }
+
+    /// If this chunk holds a [`crate::rollup::Rollup`] instead of events
+    pub fn is_rolled_up(&self) -> bool {
+        match &self.source {
+            Source::Frame(frame) => frame.is_rolled_up(),
+            Source::Decoded(chunk, _) => chunk.is_rolled_up(),
+        }
+    }
+
+    /// The encoded events (see [`crate::codec`]) or rolled up buckets of this chunk, borrowed
+    /// from the capture. `None` for a capture written before version 3 of the format
+    pub fn bytes(&self) -> Option<&'a [u8]> {
+        match &self.source {
+            Source::Frame(frame) => Some(frame.body),
+            Source::Decoded(..) => None,
+        }
+    }
+
+    /// Decode the events or buckets of this chunk
+    pub fn decode(&self) -> Result<Chunk, DecodeError> {
+        match &self.source {
+            Source::Frame(frame) => frame.decode(self.header),
+            Source::Decoded(chunk, _) => Ok(chunk.clone()),
+        }
+    }
+
+    /// Take the decoded chunk, decoding it if needed
+    fn into_chunk(self) -> Result<Chunk, DecodeError> {
+        match self.source {
+            Source::Frame(frame) => frame.decode(self.header),
+            Source::Decoded(chunk, _) => Ok(chunk),
+        }
+    }
+}
+
+/// An iterator over the chunks of a [`ProcessionView`], the first chunk that fails to read is
+/// reported as an error and ends the iteration
+pub struct Chunks<'a> {
+    header: &'a Header,
+    reader: Reader<'a>,
+    /// The position of the next chunk
+    index: usize,
+    /// The number of chunks in the capture
+    len: usize,
+    done: bool,
+}
+
+impl<'a> Chunks<'a> {
+    /// Read the next chunk, once every chunk has been read any bytes left over are an error
+    fn read(&mut self) -> Result<Option<ChunkView<'a>>, DecodeError> {
+        if self.index == self.len {
+            self.done = true;
+            return match self.reader.remaining() {
+                0 => Ok(None),
+                count => Err(DecodeError::TrailingBytes(count)),
+            };
+        }
+        let source = match self.header.version {
+            1 | 2 => {
+                let chunk = capture::decode_chunk(self.header, self.index, &mut self.reader)?;
+                let index = chunk.index().into_owned();
+                Source::Decoded(chunk, index)
+            }
+            _ => Source::Frame(capture::read_frame(
+                self.header,
+                self.index,
+                &mut self.reader,
+            )?),
+        };
+        self.index += 1;
+        Ok(Some(ChunkView {
+            header: self.header,
+            source,
+        }))
+    }
+}
+
+impl<'a> Iterator for Chunks<'a> {
+    type Item = Result<ChunkView<'a>, DecodeError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+        let ret = self.read();
+        if ret.is_err() {
+            self.done = true;
+        }
+        ret.transpose()
+    }
+}
+
+/// A single event read from a [`ProcessionView`], like a [`MetricRef`] the key and
+/// description are borrowed from the view while the [`Aggregate`] of a rolled up chunk is
+/// owned since only the chunk being iterated is decoded
+#[derive(Debug, Clone)]
+pub struct ViewMetric<'a> {
+    /// The time this event occurred
+    pub when: OffsetDateTime,
+    /// The value emitted for the key
+    pub event: Entry,
+    /// The key and labels provided by the metrics crate
+    pub key: &'a Key,
+    /// The unit and help text provided for this metric's name
+    pub description: Option<&'a Description>,
+    /// The combined events this metric stands in for when it came from a rolled up chunk
+    pub rollup: Option<Aggregate>,
+}
+
+impl ViewMetric<'_> {
+    /// Borrow this event as a [`MetricRef`]
+    pub fn as_ref(&self) -> MetricRef<'_> {
+        MetricRef {
+            when: self.when,
+            event: self.event,
+            key: self.key,
+            description: self.description,
+            rollup: self.rollup.as_ref(),
+        }
+    }
+}
+
+impl From<ViewMetric<'_>> for Metric {
+    fn from(value: ViewMetric<'_>) -> Self {
+        let ViewMetric {
+            when,
+            event,
+            key,
+            description,
+            rollup,
+        } = value;
+        Metric {
+            when,
+            event,
+            key: key.name().to_string(),
+            labels: key
+                .labels()
+                .map(|l| (l.key().to_string(), l.value().to_string()))
+                .collect(),
+            description: description.cloned(),
+            rollup,
+        }
+    }
+}
+
+/// An iterator over the events of a [`ProcessionView`], the first chunk that fails to decode
+/// is reported as an error and ends the iteration
+pub struct ViewIterator<'a> {
+    view: &'a ProcessionView<'a>,
+    chunks: Chunks<'a>,
+    /// The chunk currently being iterated
+    chunk: Option<Chunk>,
+    event_index: usize,
+    /// Only events matching this filter will be produced
+    filter: Option<Filter>,
+    done: bool,
+}
+
+impl<'a> Iterator for ViewIterator<'a> {
+    type Item = Result<ViewMetric<'a>, DecodeError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        while !self.done {
+            if let Some((label, event, when, rollup)) = self.next_in_chunk() {
+                let view = self.view;
+                let key = view
+                    .labels
+                    .key(label)
+                    .unwrap_or_else(|| EMPTY_KEY.get_or_init(|| Key::from_name("")));
+                return Some(Ok(ViewMetric {
+                    when,
+                    event,
+                    key,
+                    description: view.descriptions.get(key.name()),
+                    rollup,
+                }));
+            }
+            if let Err(e) = self.next_chunk() {
+                self.done = true;
+                return Some(Err(e));
+            }
+        }
+        None
+    }
+}
+
+impl ViewIterator<'_> {
+    /// The next event of the current chunk that matches the filter, if there is one
+    fn next_in_chunk(&mut self) -> Option<(LabelId, Entry, OffsetDateTime, Option<Aggregate>)> {
+        let chunk = self.chunk.as_ref()?;
+        let matches = |label, when| self.filter.as_ref().is_none_or(|f| f.matches(label, when));
+        if let Some(rollup) = chunk.rollup() {
+            while let Some(bucket) = rollup.buckets.get(self.event_index) {
+                self.event_index += 1;
+                let when = chunk.reference_time + Duration::milliseconds(bucket.ms as i64);
+                if matches(bucket.label, when) {
+                    let aggregate = bucket.aggregate.clone();
+                    return Some((bucket.label, aggregate.entry(), when, Some(aggregate)));
+                }
+            }
+            return None;
+        }
+        let events = chunk.events();
+        while let Some(event) = events.get(self.event_index) {
+            self.event_index += 1;
+            let when = chunk.reference_time + Duration::milliseconds(event.ms as i64);
+            if matches(event.label, when)
+                && let Some(entry) = events.entry(event)
+            {
+                return Some((event.label, entry, when, None));
+            }
+        }
+        None
+    }
+
+    /// Decode the next chunk that may match the filter, replacing the current one. Once every
+    /// chunk has been read the iteration is done
+    fn next_chunk(&mut self) -> Result<(), DecodeError> {
+        self.chunk = None;
+        self.event_index = 0;
+        for chunk in self.chunks.by_ref() {
+            let chunk = chunk?;
+            if let Some(filter) = &self.filter
+                && !filter.may_match_index(chunk.reference_time(), chunk.index())
+            {
+                continue;
+            }
+            self.chunk = Some(chunk.into_chunk()?);
+            return Ok(());
+        }
+        self.done = true;
+        Ok(())
+    }
+}
+
+/// A capture file mapped into memory, the bytes are read by the operating system as they are
+/// used instead of all up front
+#[cfg(feature = "mmap")]
+#[derive(Debug)]
+pub struct MappedCapture(memmap2::Mmap);
+
+#[cfg(feature = "mmap")]
+impl MappedCapture {
+    /// Map the file at the provided path into memory
+    ///
+    /// The file must not be truncated or modified while it is mapped, doing so is undefined
+    /// behavior. Captures are written once and never modified by this crate
+    pub fn open(path: impl AsRef<std::path::Path>) -> std::io::Result<Self> {
+        let file = std::fs::File::open(path)?;
+        // Safety: see the documentation above, the mapping is read-only
+        let map = unsafe { memmap2::Mmap::map(&file)? };
+        Ok(Self(map))
+    }
+
+    /// Create a [`ProcessionView`] over the mapped capture
+    pub fn view(&self) -> Result<ProcessionView<'_>, DecodeError> {
+        ProcessionView::new(self)
+    }
+}
+
+#[cfg(feature = "mmap")]
+impl std::ops::Deref for MappedCapture {
+    type Target = [u8];
+
+    fn deref(&self) -> &Self::Target {
+        &self.0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::test_util::{procession, start};
+
+    use super::*;
+
+    #[test]
+    fn matches_procession() {
+        let procession = procession();
+        assert!(procession.chunks.iter().any(|c| c.is_rolled_up()));
+        let bytes = procession.to_capture();
+        let view = ProcessionView::new(&bytes).unwrap();
+        assert_eq!(view.len(), procession.chunks.len());
+        assert_eq!(view.labels(), &procession.labels);
+        let metrics: Vec<Metric> = view.iter().map(|m| m.unwrap().into()).collect();
+        assert_eq!(metrics.len(), procession.iter().count());
+        for (metric, expected) in metrics.into_iter().zip(procession.iter()) {
+            assert_eq!(metric, expected);
+        }
+
+        let key = Key::from_name("queue_depth");
+        let filter = || {
+            Filter::new()
+                .keys(&procession.labels, |k| k == &key)
+                .range(..start() + Duration::minutes(4))
+        };
+        let filtered: Vec<Metric> = view
+            .iter_filtered(filter())
+            .map(|m| m.unwrap().into())
+            .collect();
+        assert!(!filtered.is_empty());
+        assert_eq!(filtered.len(), procession.iter_filtered(filter()).count());
+        for (metric, expected) in filtered.into_iter().zip(procession.iter_filtered(filter())) {
+            assert_eq!(metric, expected);
+        }
+    }
+
+    #[test]
+    fn skips_chunks_by_index() {
+        let procession = procession();
+        let mut bytes = Vec::new();
+        let options = capture::CaptureOptions {
+            checksums: false,
+            ..Default::default()
+        };
+        capture::encode(&procession, options, &mut bytes);
+        let view = ProcessionView::new(&bytes).unwrap();
+        let range = bytes.as_ptr_range();
+        for (chunk, expected) in view.chunks().zip(&procession.chunks) {
+            let chunk = chunk.unwrap();
+            assert_eq!(chunk.reference_time(), expected.reference_time);
+            assert_eq!(chunk.index(), &*expected.index());
+            assert_eq!(chunk.is_rolled_up(), expected.is_rolled_up());
+            // the encoded events are borrowed from the capture
+            let body = chunk.bytes().unwrap().as_ptr_range();
+            assert!(range.start <= body.start && body.end <= range.end);
+            assert_eq!(&chunk.decode().unwrap(), expected);
+        }
+        // the last chunk can't be decoded, a filter that excludes it never decodes it
+        let last = procession.chunks.last().unwrap().reference_time;
+        let body = view
+            .chunks()
+            .last()
+            .unwrap()
+            .unwrap()
+            .bytes()
+            .unwrap()
+            .as_ptr();
+        let offset = body as usize - bytes.as_ptr() as usize;
+        bytes[offset] = 0xFF;
+        let view = ProcessionView::new(&bytes).unwrap();
+        assert!(view.iter().any(|m| m.is_err()));
+        let filter = || Filter::new().range(..last);
+        let filtered: Vec<_> = view.iter_filtered(filter()).collect();
+        assert_eq!(filtered.len(), procession.iter_filtered(filter()).count());
+        assert!(filtered.iter().all(|m| m.is_ok()));
+    }
+
+    #[test]
+    fn errors() {
+        let bytes = procession().to_capture();
+        let mut corrupt = bytes.clone();
+        *corrupt.last_mut().unwrap() ^= 0x01;
+        let view = ProcessionView::new(&corrupt).unwrap();
+        let last = view.len() - 1;
+        let mut iter = view.iter();
+        assert!(matches!(
+            iter.by_ref().find_map(Result::err),
+            Some(DecodeError::ChunkChecksum(index)) if index == last
+        ));
+        assert!(iter.next().is_none());
+
+        let mut trailing = bytes.clone();
+        trailing.push(0);
+        let view = ProcessionView::new(&trailing).unwrap();
+        assert_eq!(
+            view.iter().last().unwrap().unwrap_err(),
+            DecodeError::TrailingBytes(1)
+        );
+        assert_eq!(
+            ProcessionView::new(&bytes[..capture::MAGIC.len()]).unwrap_err(),
+            DecodeError::UnexpectedEnd
+        );
+    }
+
+    #[cfg(feature = "mmap")]
+    #[test]
+    fn mapped() {
+        let procession = procession();
+        let path = std::env::temp_dir().join(format!("view-{}.mproc", std::process::id()));
+        std::fs::write(&path, procession.to_capture()).unwrap();
+        let mapped = MappedCapture::open(&path).unwrap();
+        let view = mapped.view().unwrap();
+        assert!(view.iter().all(|m| m.is_ok()));
+        let count = view.iter().count();
+        assert_eq!(count, procession.iter().count());
+        drop(view);
+        drop(mapped);
+        std::fs::remove_file(path).unwrap();
+    }
+}
@@ -0,0 +1,475 @@
+//! An append-only log of everything recorded into a [`Procession`], written as it happens so
+//! the series can be rebuilt with [`recover`] after a crash
+//!
+//! The log starts with [`MAGIC`], the format version as a little endian `u16` and the width of
+//! the label identifiers and of the gauge and histogram values in bits. Every record after that
+//! is a frame of
+//!
+//! - the length of the payload as a little endian `u32`
+//! - the CRC-32 of the payload as a little endian `u32`
+//! - the payload: a tag followed by a label registration, a description or an event
+//!
+//! A crash in the middle of a write leaves a torn final record, [`recover`] stops cleanly in
+//! front of it and reports how many bytes of the log were intact
+use std::{
+    fmt::Debug,
+    fs::File,
+    io::{self, Write},
+    path::Path,
+};
+
+use metrics::{Key, KeyName, Label, Unit};
+use time::OffsetDateTime;
+
+use crate::{
+    codec::{self, DecodeError, FLOAT_BYTES, Reader},
+    description::Description,
+    event::Entry,
+    label_set::{LabelId, LabelSet},
+    procession::Procession,
+};
+
+/// The bytes every log starts with
+pub const MAGIC: [u8; 8] = *b"MPROCWAL";
+
+/// The version of the log written by this build
+pub const VERSION: u16 = 1;
+
+/// The length of [`MAGIC`], the version and both widths
+const HEADER_LEN: usize = MAGIC.len() + 4;
+/// The length of the length and checksum in front of each payload
+const FRAME_LEN: usize = 8;
+/// The longest payload a record can have, a longer length can only be a corrupted one
+const MAX_RECORD_LEN: usize = 1 << 20;
+
+/// The tag of a label registration
+const LABEL: u8 = 0;
+/// The tag of a description
+const DESCRIPTION: u8 = 1;
+/// The tag of an event
+const EVENT: u8 = 2;
+
+/// Writes label registrations, descriptions and events into an append-only log as they are
+/// recorded, see [`crate::recorder::ProcessionRecorderBuilder::write_ahead_log`]
+pub struct WriteAheadLog {
+    writer: Box<dyn Write + Send>,
+    /// The payload of the record being written, kept to reuse its allocation
+    buf: Vec<u8>,
+}
+
+impl Debug for WriteAheadLog {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("WriteAheadLog").finish_non_exhaustive()
+    }
+}
+
+impl WriteAheadLog {
+    /// Start a new log, writing the header to the provided writer
+    pub fn new(writer: impl Write + Send + 'static) -> io::Result<Self> {
+        let mut ret = Self::append(writer);
+        let mut header = Vec::with_capacity(HEADER_LEN);
+        header.extend_from_slice(&MAGIC);
+        header.extend_from_slice(&VERSION.to_le_bytes());
+        header.push(LabelId::BITS as u8);
+        header.push(FLOAT_BYTES * 8);
+        ret.writer.write_all(&header)?;
+        Ok(ret)
+    }
+
+    /// Continue an existing log, the writer should be positioned at the end of the intact
+    /// records reported by [`recover`]
+    pub fn append(writer: impl Write + Send + 'static) -> Self {
+        Self {
+            writer: Box::new(writer),
+            buf: Vec::new(),
+        }
+    }
+
+    /// Create (or truncate) the file at the provided path and start a new log in it
+    pub fn create(path: impl AsRef<Path>) -> io::Result<Self> {
+        Self::new(File::create(path)?)
+    }
+
+    /// Record that the provided key was assigned the provided label identifier
+    pub fn log_label(&mut self, key: &Key, label: LabelId) -> io::Result<()> {
+        self.buf.clear();
+        self.buf.push(LABEL);
+        codec::write_varint(&mut self.buf, label as u128);
+        codec::write_str(&mut self.buf, key.name());
+        codec::write_varint(&mut self.buf, key.labels().len() as u128);
+        for label in key.labels() {
+            codec::write_str(&mut self.buf, label.key());
+            codec::write_str(&mut self.buf, label.value());
+        }
+        self.write_record()
+    }
+
+    /// Record the description provided for a metric name
+    pub fn log_description(&mut self, name: &KeyName, description: &Description) -> io::Result<()> {
+        self.buf.clear();
+        self.buf.push(DESCRIPTION);
+        codec::write_str(&mut self.buf, name.as_str());
+        let unit = description.unit.map(|u| u.as_str()).unwrap_or_default();
+        codec::write_str(&mut self.buf, unit);
+        codec::write_str(&mut self.buf, &description.text);
+        self.write_record()
+    }
+
+    /// Record an entry for the provided label identifier that happened at the provided time
+    pub fn log_event(
+        &mut self,
+        when: OffsetDateTime,
+        entry: Entry,
+        label: LabelId,
+    ) -> io::Result<()> {
+        self.buf.clear();
+        self.buf.push(EVENT);
+        codec::write_time(&mut self.buf, when);
+        codec::write_varint(&mut self.buf, label as u128);
+        match entry {
+            Entry::Gauge { value, op } => {
+                self.buf.push(codec::op_bits(op));
+                self.buf.extend_from_slice(&value.to_le_bytes());
+            }
+            Entry::Counter { value, op } => {
+                self.buf.push(3 + codec::op_bits(op));
+                codec::write_varint(&mut self.buf, u128::from(value));
+            }
+            Entry::Histogram { value } => {
+                self.buf.push(6);
+                self.buf.extend_from_slice(&value.to_le_bytes());
+            }
+        }
+        self.write_record()
+    }
+
+    /// Record every key and description of the provided series, used when a log is attached to
+    /// a series that already has keys so the events recorded after it can be recovered
+    pub(crate) fn log_keys(&mut self, procession: &Procession) -> io::Result<()> {
+        for (key, label) in procession.labels.iter() {
+            self.log_label(key, label)?;
+        }
+        for (name, description) in &procession.descriptions.0 {
+            self.log_description(name, description)?;
+        }
+        Ok(())
+    }
+
+    /// Flush any records buffered by the writer
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.writer.flush()
+    }
+
+    /// Write the payload in `buf` as a single frame
+    fn write_record(&mut self) -> io::Result<()> {
+        if self.buf.len() > MAX_RECORD_LEN {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("record of {} bytes is too long to log", self.buf.len()),
+            ));
+        }
+        let mut frame = Vec::with_capacity(FRAME_LEN + self.buf.len());
+        frame.extend_from_slice(&(self.buf.len() as u32).to_le_bytes());
+        frame.extend_from_slice(&codec::crc32(&self.buf).to_le_bytes());
+        frame.extend_from_slice(&self.buf);
+        self.writer.write_all(&frame)
+    }
+}
+
+/// The series rebuilt from a log by [`recover`]
+#[derive(Debug)]
+pub struct Recovery {
+    /// Every label, description and event from the intact records
+    pub procession: Procession,
+    /// The number of bytes at the start of the log holding intact records, a log should be
+    /// truncated to this length before it is appended to
+    pub valid_len: usize,
+    /// The number of bytes of a torn final record that were discarded
+    pub torn_len: usize,
+}
+
+/// Rebuild a series from the bytes of a log. A final record that is incomplete or fails its
+/// checksum is treated as torn and discarded while a damaged record followed by more records
+/// is an error
+pub fn recover(bytes: &[u8]) -> Result<Recovery, DecodeError> {
+    let mut reader = Reader::new(bytes);
+    if reader
+        .bytes(MAGIC.len())
+        .map_err(|_| DecodeError::NotALog)?
+        != MAGIC
+    {
+        return Err(DecodeError::NotALog);
+    }
+    let version = u16::from_le_bytes(
+        reader
+            .bytes(2)?
+            .try_into()
+            .expect("exactly 2 bytes were read"),
+    );
+    if version == 0 || version > VERSION {
+        return Err(DecodeError::UnsupportedVersion(version));
+    }
+    let label_id_bits = reader.byte()?;
+    if !matches!(label_id_bits, 16 | 32) {
+        return Err(DecodeError::LabelWidth(label_id_bits));
+    }
+    let value_bits = reader.byte()?;
+    if value_bits != 32 && value_bits != FLOAT_BYTES * 8 {
+        return Err(DecodeError::ValueWidth(value_bits / 8));
+    }
+    let mut procession = Procession::default();
+    let mut keys = Vec::new();
+    let mut valid_len = HEADER_LEN;
+    while reader.remaining() > 0 {
+        let Some(payload) = next_frame(&mut reader, valid_len)? else {
+            break;
+        };
+        let mut payload = Reader::new(payload);
+        match payload.byte()? {
+            LABEL => {
+                let label = payload.varint_u64()?;
+                let label =
+                    LabelId::try_from(label).map_err(|_| DecodeError::LabelOutOfRange(label))?;
+                let name = payload.str()?.to_string();
+                let len = payload.length()?;
+                let mut labels = Vec::with_capacity(len);
+                for _ in 0..len {
+                    let key = payload.str()?.to_string();
+                    labels.push(Label::new(key, payload.str()?.to_string()));
+                }
+                keys.push((Key::from_parts(name, labels), label));
+            }
+            DESCRIPTION => {
+                let name = KeyName::from(payload.str()?.to_string());
+                let unit = match payload.str()? {
+                    "" => None,
+                    unit => Some(
+                        Unit::from_string(unit)
+                            .ok_or_else(|| DecodeError::UnknownUnit(unit.to_string()))?,
+                    ),
+                };
+                let text = payload.str()?.to_string();
+                procession
+                    .descriptions
+                    .insert(name, Description { unit, text });
+            }
+            EVENT => {
+                let when = payload.time()?;
+                let label = payload.varint_u64()?;
+                let label =
+                    LabelId::try_from(label).map_err(|_| DecodeError::LabelOutOfRange(label))?;
+                let entry = match payload.byte()? {
+                    kind @ 0..=2 => Entry::Gauge {
+                        value: payload.float(value_bits / 8)?,
+                        op: codec::op(kind),
+                    },
+                    kind @ 3..=5 => Entry::Counter {
+                        value: payload.varint_u64()?,
+                        op: codec::op(kind - 3),
+                    },
+                    6 => Entry::Histogram {
+                        value: payload.float(value_bits / 8)?,
+                    },
+                    kind => return Err(DecodeError::UnknownKind(kind)),
+                };
+                procession.insert_entry_at(when, entry, label);
+            }
+            tag => return Err(DecodeError::UnknownKind(tag)),
+        }
+        if payload.remaining() > 0 {
+            return Err(DecodeError::TrailingBytes(payload.remaining()));
+        }
+        valid_len = bytes.len() - reader.remaining();
+    }
+    procession.labels = keys.into_iter().collect::<LabelSet>();
+    Ok(Recovery {
+        procession,
+        valid_len,
+        torn_len: bytes.len() - valid_len,
+    })
+}
+
+/// The payload of the frame at `offset`, `None` if it is a torn final frame: one that runs
+/// past the end of the log or fails its checksum with nothing after it. A torn frame leaves the
+/// reader empty. A length no record can have or a damaged frame followed by more bytes is a
+/// [`DecodeError::CorruptRecord`] since the records after it can't be found
+fn next_frame<'a>(reader: &mut Reader<'a>, offset: usize) -> Result<Option<&'a [u8]>, DecodeError> {
+    let torn = |reader: &mut Reader<'a>| {
+        let rest = reader.remaining();
+        let _ = reader.bytes(rest);
+        Ok(None)
+    };
+    if reader.remaining() < FRAME_LEN {
+        return torn(reader);
+    }
+    let len = u32::from_le_bytes(reader.bytes(4)?.try_into().expect("4 bytes were read"));
+    let crc = u32::from_le_bytes(reader.bytes(4)?.try_into().expect("4 bytes were read"));
+    let len = len as usize;
+    if len > MAX_RECORD_LEN {
+        return Err(DecodeError::CorruptRecord(offset));
+    }
+    if len > reader.remaining() {
+        return torn(reader);
+    }
+    let payload = reader.bytes(len)?;
+    if codec::crc32(payload) == crc {
+        Ok(Some(payload))
+    } else if reader.remaining() == 0 {
+        Ok(None)
+    } else {
+        Err(DecodeError::CorruptRecord(offset))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use time::Duration;
+
+    use crate::{clock::ManualClock, recorder::ProcessionRecorder, test_util::start};
+
+    use super::*;
+
+    /// A writer that can be read back while the log still holds it
+    #[derive(Clone, Default)]
+    struct Shared(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for Shared {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl Shared {
+        fn bytes(&self) -> Vec<u8> {
+            self.0.lock().unwrap().clone()
+        }
+    }
+
+    /// Record a few minutes of events through a recorder writing to a log
+    fn record(shards: usize) -> (ProcessionRecorder, Shared) {
+        let buf = Shared::default();
+        let clock = Arc::new(ManualClock::new(start()));
+        let recorder = ProcessionRecorder::builder()
+            .clock(clock.clone())
+            .shards(shards)
+            .write_ahead_log(WriteAheadLog::new(buf.clone()).unwrap())
+            .build();
+        metrics::with_local_recorder(&recorder, || {
+            metrics::describe_histogram!("latency", metrics::Unit::Seconds, "request latency");
+            let ct = metrics::counter!("requests", "service" => "api");
+            let g = metrics::gauge!("queue_depth");
+            let h = metrics::histogram!("latency");
+            for i in 0..1_000u32 {
+                ct.increment(u64::from(i));
+                g.set(f64::from(i % 17));
+                h.record(f64::from(i) / 4.0);
+                clock.advance(Duration::milliseconds(250));
+            }
+            ct.absolute(u64::MAX);
+        });
+        (recorder, buf)
+    }
+
+    #[test]
+    fn recovers_everything_recorded() {
+        for shards in [0, 2] {
+            let (recorder, buf) = record(shards);
+            recorder.flush_log().unwrap();
+            let bytes = buf.bytes();
+            let recovery = recover(&bytes).unwrap();
+            assert_eq!(recovery.valid_len, bytes.len());
+            assert_eq!(recovery.torn_len, 0);
+            assert_eq!(recovery.procession, *recorder.lock());
+            assert_eq!(recorder.log_errors(), 0);
+        }
+    }
+
+    #[test]
+    fn stops_at_a_torn_record() {
+        let (recorder, buf) = record(0);
+        let bytes = buf.bytes();
+        let complete = recover(&bytes).unwrap().procession;
+        let events = |p: &Procession| p.iter().count();
+        for torn in 1..20 {
+            let len = bytes.len() - torn;
+            let recovery = recover(&bytes[..len]).unwrap();
+            assert!(recovery.valid_len <= len);
+            assert_eq!(recovery.valid_len + recovery.torn_len, len);
+            // only the last event is lost
+            assert_eq!(events(&recovery.procession), events(&complete) - 1);
+        }
+        // a damaged final record is discarded
+        let mut damaged = bytes.clone();
+        *damaged.last_mut().unwrap() ^= 0xFF;
+        let recovery = recover(&damaged).unwrap();
+        assert!(recovery.torn_len > 0);
+        assert_eq!(events(&recovery.procession), events(&recorder.lock()) - 1);
+        // while damage followed by more records is an error
+        let mut corrupt = bytes.clone();
+        corrupt[HEADER_LEN + FRAME_LEN] ^= 0xFF;
+        assert_eq!(
+            recover(&corrupt).unwrap_err(),
+            DecodeError::CorruptRecord(HEADER_LEN)
+        );
+    }
+
+    #[test]
+    fn corrupt_length() {
+        let (_, buf) = record(0);
+        let bytes = buf.bytes();
+        // the second record, with plenty of records after it
+        let first_len = u32::from_le_bytes(bytes[HEADER_LEN..][..4].try_into().unwrap());
+        let offset = HEADER_LEN + FRAME_LEN + first_len as usize;
+        let len = u32::from_le_bytes(bytes[offset..][..4].try_into().unwrap());
+        for corrupted in [u32::MAX, MAX_RECORD_LEN as u32 + 1, len + 1, len - 1, 0] {
+            let mut corrupt = bytes.clone();
+            corrupt[offset..][..4].copy_from_slice(&corrupted.to_le_bytes());
+            assert_eq!(
+                recover(&corrupt).unwrap_err(),
+                DecodeError::CorruptRecord(offset),
+                "{corrupted}"
+            );
+        }
+    }
+
+    #[test]
+    fn appends_after_recovery() {
+        let (_, buf) = record(0);
+        let mut bytes = buf.bytes();
+        bytes.truncate(bytes.len() - 3);
+        let recovery = recover(&bytes).unwrap();
+        bytes.truncate(recovery.valid_len);
+        let tail = Shared::default();
+        let recorder = ProcessionRecorder::builder()
+            .procession(recovery.procession)
+            .write_ahead_log(WriteAheadLog::append(tail.clone()))
+            .build();
+        metrics::with_local_recorder(&recorder, || {
+            metrics::gauge!("queue_depth").set(2.5);
+            metrics::gauge!("new_gauge").set(1.0);
+        });
+        bytes.extend(tail.bytes());
+        let recovered = recover(&bytes).unwrap();
+        assert_eq!(recovered.torn_len, 0);
+        assert_eq!(recovered.procession, *recorder.lock());
+    }
+
+    #[test]
+    fn errors() {
+        assert_eq!(recover(b"").unwrap_err(), DecodeError::NotALog);
+        assert_eq!(recover(b"not a log").unwrap_err(), DecodeError::NotALog);
+        let mut version = MAGIC.to_vec();
+        version.extend_from_slice(&(VERSION + 1).to_le_bytes());
+        assert_eq!(
+            recover(&version).unwrap_err(),
+            DecodeError::UnsupportedVersion(VERSION + 1)
+        );
+    }
+}